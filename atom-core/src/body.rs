@@ -5,13 +5,21 @@ use futures_util::stream::Stream;
 use futures_util::{StreamExt, TryStream};
 use http_body::{Body as HttpBody, Frame};
 use http_body_util::BodyExt;
+use hyper::HeaderMap;
 use pin_project_lite::pin_project;
+use std::future::Future;
+use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use sync_wrapper::SyncWrapper;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::error::{BoxError, Error};
 use crate::helpers::utils::try_downcast;
+use crate::state::{FromState, State};
 
 type BoxBody = http_body_util::combinators::UnsyncBoxBody<Bytes, Error>;
 
@@ -20,7 +28,25 @@ where
     B: http_body::Body<Data = Bytes> + Send + 'static,
     B::Error: Into<BoxError>,
 {
-    try_downcast(body).unwrap_or_else(|body| body.map_err(Error::new_box).boxed_unsync())
+    try_downcast(body).unwrap_or_else(|body| body.map_err(into_error).boxed_unsync())
+}
+
+/// Converts `error` into an `Error`, keeping it as it is when it already is one, even if an
+/// adapter has wrapped it in an `io::Error`, so that `Error::is_length_limit` still holds.
+fn into_error(error: impl Into<BoxError>) -> Error {
+    let error = error.into();
+    let error = match error.downcast::<io::Error>() {
+        Ok(error) if error.get_ref().is_some_and(|inner| inner.is::<Error>()) => {
+            error.into_inner().unwrap()
+        }
+        Ok(error) => error,
+        Err(error) => error,
+    };
+
+    match error.downcast::<Error>() {
+        Ok(error) => *error,
+        Err(error) => Error::new_box(error),
+    }
 }
 
 /// The body type used in requests and responses.
@@ -57,12 +83,61 @@ impl Body {
         })
     }
 
+    /// Creates a `Body` fed by a `BodySender`, for responses which are produced incrementally.
+    ///
+    /// At most `buffer` frames are queued at once; beyond that, sending waits until the client
+    /// has read some of the body. The body ends once the `BodySender` is dropped or has sent
+    /// trailers.
+    ///
+    /// ```rust,ignore
+    /// async fn export(state: &mut State) -> Result<Response<Body>, HandlerError> {
+    ///     let (mut sender, body) = Body::channel(16);
+    ///
+    ///     tokio::spawn(async move {
+    ///         let mut hasher = Crc32::new();
+    ///         for row in rows() {
+    ///             hasher.update(row.as_bytes());
+    ///             if sender.send_data(row).await.is_err() {
+    ///                 return; // the client has gone away
+    ///             }
+    ///         }
+    ///
+    ///         let mut trailers = HeaderMap::new();
+    ///         trailers.insert("x-checksum", hasher.finish().into());
+    ///         let _ = sender.send_trailers(trailers).await;
+    ///     });
+    ///
+    ///     Ok(create_response(state, StatusCode::OK, mime::TEXT_CSV, body))
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is zero.
+    pub fn channel(buffer: usize) -> (BodySender, Body) {
+        let (data_tx, data_rx) = mpsc::channel(buffer);
+        let (abort_tx, abort_rx) = oneshot::channel();
+
+        let sender = BodySender {
+            data: data_tx,
+            abort: abort_tx,
+        };
+        let body = ChannelBody {
+            data: data_rx,
+            abort: Some(abort_rx),
+        };
+
+        (sender, Body(body.boxed_unsync()))
+    }
+
+    /// Collects the whole body into a single `Bytes`.
+    ///
+    /// Fails if reading the body fails, or if the body exceeds the limit configured for the
+    /// request, see `BodyLimit`. Returning such an error from a handler with `?` produces a
+    /// `413 Payload Too Large` response.
     pub async fn to_bytes(self) -> Result<Bytes, Error> {
-        // futures_util::pin_mut!(self);
-        if let Ok(collect) = BodyExt::collect(self).await {
-          return  Ok(collect.to_bytes());
-        }
-       return  Ok(Bytes::new());
+        let collected = BodyExt::collect(self).await?;
+        Ok(collected.to_bytes())
     }
 }
 
@@ -155,14 +230,455 @@ where
         let stream = self.project().stream.get_pin_mut();
         match futures_util::ready!(stream.try_poll_next(cx)) {
             Some(Ok(chunk)) => Poll::Ready(Some(Ok(Frame::data(chunk.into())))),
-            Some(Err(err)) => Poll::Ready(Some(Err(Error::new_box(err)))),
+            Some(Err(err)) => Poll::Ready(Some(Err(into_error(err)))),
             None => Poll::Ready(None),
         }
     }
 }
 
-#[test]
-fn test_try_downcast() {
-    assert_eq!(try_downcast::<i32, _>(5_u32), Err(5_u32));
-    assert_eq!(try_downcast::<i32, _>(5_i32), Ok(5_i32));
+/// The sending half of a `Body` created by `Body::channel`.
+///
+/// Data frames are sent with `send_data`, waiting whenever the channel is full, optionally
+/// followed by a single trailers frame. Dropping the `BodySender` ends the body; use `abort` to
+/// end it with an error instead, so the client can tell that the response is incomplete.
+#[derive(Debug)]
+pub struct BodySender {
+    data: mpsc::Sender<Frame<Bytes>>,
+    abort: oneshot::Sender<Error>,
+}
+
+impl BodySender {
+    /// Sends a chunk of data, waiting for space in the channel if the client is reading slower
+    /// than data is produced.
+    ///
+    /// Fails if the `Body` has been dropped, usually because the client disconnected, in which
+    /// case producing the rest of the body can be skipped.
+    pub async fn send_data(&mut self, data: impl Into<Bytes>) -> Result<(), Error> {
+        self.send(Frame::data(data.into())).await
+    }
+
+    /// Sends the trailers, which end the body.
+    ///
+    /// Trailers are only delivered over HTTP/2; HTTP/1.1 connections discard them.
+    pub async fn send_trailers(mut self, trailers: HeaderMap) -> Result<(), Error> {
+        self.send(Frame::trailers(trailers)).await
+    }
+
+    /// Ends the body with `error` rather than cleanly, discarding any frames which have not been
+    /// read yet. The connection is closed without completing the response.
+    pub fn abort(self, error: impl Into<BoxError>) {
+        let _ = self.abort.send(Error::new_box(error));
+    }
+
+    /// Returns `true` if the `Body` has been dropped, so nothing sent will be read.
+    pub fn is_closed(&self) -> bool {
+        self.data.is_closed()
+    }
+
+    /// Converts the sender into an `AsyncWrite`, for producing the body with code which writes
+    /// to an I/O stream.
+    pub fn into_writer(self) -> BodyWriter {
+        BodyWriter {
+            sender: Some(self),
+            reserve: None,
+        }
+    }
+
+    async fn send(&mut self, frame: Frame<Bytes>) -> Result<(), Error> {
+        self.data
+            .send(frame)
+            .await
+            .map_err(|_| Error::new("the response body was dropped"))
+    }
+}
+
+type Reserve = Pin<
+    Box<
+        dyn Future<Output = Result<mpsc::OwnedPermit<Frame<Bytes>>, mpsc::error::SendError<()>>>
+            + Send,
+    >,
+>;
+
+/// An `AsyncWrite` which sends everything written to it as data frames of a `Body`, created by
+/// `BodySender::into_writer`.
+///
+/// Each write is sent as a frame of its own, so many small writes are best wrapped in a
+/// `tokio::io::BufWriter`. Shutting the writer down ends the body, as does dropping it.
+///
+/// ```rust,ignore
+/// let (sender, body) = Body::channel(16);
+/// let mut writer = BufWriter::new(sender.into_writer());
+///
+/// tokio::spawn(async move {
+///     for record in records {
+///         writer.write_all(record.to_csv_line().as_bytes()).await?;
+///     }
+///     writer.shutdown().await
+/// });
+/// ```
+pub struct BodyWriter {
+    sender: Option<BodySender>,
+    reserve: Option<Reserve>,
+}
+
+impl BodyWriter {
+    /// Converts the writer back into a `BodySender`, e.g. to send trailers once everything has
+    /// been written. Returns `None` if the writer has been shut down.
+    pub fn into_sender(self) -> Option<BodySender> {
+        self.sender
+    }
+}
+
+impl std::fmt::Debug for BodyWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyWriter")
+            .field("sender", &self.sender)
+            .finish_non_exhaustive()
+    }
+}
+
+fn body_dropped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the response body was dropped")
+}
+
+impl AsyncWrite for BodyWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let data = match &self.sender {
+            Some(sender) => sender.data.clone(),
+            None => return Poll::Ready(Err(body_dropped())),
+        };
+
+        let reserve = self
+            .reserve
+            .get_or_insert_with(|| Box::pin(data.reserve_owned()));
+
+        let result = futures_util::ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+
+        match result {
+            Ok(permit) => {
+                permit.send(Frame::data(Bytes::copy_from_slice(buf)));
+                Poll::Ready(Ok(buf.len()))
+            }
+            Err(_) => Poll::Ready(Err(body_dropped())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.reserve = None;
+        self.sender = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The receiving half of `Body::channel`.
+struct ChannelBody {
+    data: mpsc::Receiver<Frame<Bytes>>,
+    abort: Option<oneshot::Receiver<Error>>,
+}
+
+impl http_body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(abort) = self.abort.as_mut() {
+            match Pin::new(abort).poll(cx) {
+                Poll::Ready(Ok(err)) => {
+                    self.abort = None;
+                    self.data.close();
+                    return Poll::Ready(Some(Err(err)));
+                }
+                // The sender was dropped without aborting; keep reading what it sent.
+                Poll::Ready(Err(_)) => self.abort = None,
+                Poll::Pending => {}
+            }
+        }
+
+        self.data.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+/// The size limit applied to the body of the current request.
+///
+/// Gotham stores this in `State` when a limit has been configured, either for the whole server via
+/// `ServerBuilder::max_body_size` or for a single route via `DefineSingleRoute::with_body_limit`.
+/// A route's limit replaces the server's, so it may be larger as well as smaller.
+///
+/// Requests declaring a larger `Content-Length` are rejected with `413 Payload Too Large` before
+/// the handler runs. Otherwise reading stops as soon as the limit is passed, and the `Body` yields
+/// an error for which `Error::is_length_limit` returns `true`.
+#[derive(Clone, Debug)]
+pub struct BodyLimit {
+    limit: Arc<AtomicUsize>,
+}
+
+impl BodyLimit {
+    /// The maximum number of bytes the request body may contain.
+    pub fn get(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits the request body in `state` to `limit` bytes, replacing any limit applied before.
+pub(crate) fn limit_request_body(state: &mut State, limit: usize) {
+    if let Some(existing) = BodyLimit::try_borrow_from(state) {
+        existing.limit.store(limit, Ordering::Relaxed);
+        return;
+    }
+
+    let shared = Arc::new(AtomicUsize::new(limit));
+
+    if let Some(body) = Body::try_take_from(state) {
+        let limited = Limited {
+            inner: body,
+            read: 0,
+            limit: shared.clone(),
+        };
+        state.put(Body(limited.boxed_unsync()));
+    }
+
+    state.put(BodyLimit { limit: shared });
+}
+
+/// Returns `true` if the request body in `state` is already known to exceed its limit, because
+/// the client declared a larger length up front.
+pub(crate) fn exceeds_body_limit(state: &State) -> bool {
+    match (
+        BodyLimit::try_borrow_from(state),
+        Body::try_borrow_from(state),
+    ) {
+        (Some(limit), Some(body)) => HttpBody::size_hint(body).lower() > limit.get() as u64,
+        _ => false,
+    }
+}
+
+/// A body which fails once more than the limit shared with its `BodyLimit` has been read.
+struct Limited {
+    inner: Body,
+    read: usize,
+    limit: Arc<AtomicUsize>,
+}
+
+impl http_body::Body for Limited {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let limit = self.limit.load(Ordering::Relaxed);
+
+        // Don't read anything when the rest of the body is known to be too large.
+        let declared = HttpBody::size_hint(&self.inner).lower();
+        if (self.read as u64).saturating_add(declared) > limit as u64 {
+            return Poll::Ready(Some(Err(Error::length_limit(limit))));
+        }
+
+        let frame = match futures_util::ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            other => return Poll::Ready(other),
+        };
+
+        if let Some(data) = frame.data_ref() {
+            self.read = self.read.saturating_add(data.len());
+            if self.read > limit {
+                return Poll::Ready(Some(Err(Error::length_limit(limit))));
+            }
+        }
+
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        HttpBody::size_hint(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use hyper::Request;
+
+    fn limited_state(body: Body, limit: usize) -> State {
+        let req = Request::post("http://localhost/").body(body).unwrap();
+        let mut state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+        limit_request_body(&mut state, limit);
+        state
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::from_stream(stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, Error>(Bytes::from(*chunk))),
+        ))
+    }
+
+    #[test]
+    fn test_try_downcast() {
+        assert_eq!(try_downcast::<i32, _>(5_u32), Err(5_u32));
+        assert_eq!(try_downcast::<i32, _>(5_i32), Ok(5_i32));
+    }
+
+    #[test]
+    fn to_bytes_within_limit() {
+        let mut state = limited_state(chunked(&["hello", " world"]), 11);
+        assert!(!exceeds_body_limit(&state));
+
+        let body = Body::take_from(&mut state);
+        let bytes = block_on(body.to_bytes()).unwrap();
+        assert_eq!(bytes, "hello world");
+    }
+
+    #[test]
+    fn to_bytes_stops_at_limit() {
+        let mut state = limited_state(chunked(&["hello", " world"]), 8);
+        assert!(!exceeds_body_limit(&state));
+
+        let body = Body::take_from(&mut state);
+        let err = block_on(body.to_bytes()).unwrap_err();
+        assert!(err.is_length_limit());
+    }
+
+    #[test]
+    fn declared_length_exceeds_limit() {
+        let mut state = limited_state(Body::from("hello world"), 4);
+        assert!(exceeds_body_limit(&state));
+
+        // A later limit replaces the earlier one, rather than stacking on top of it.
+        limit_request_body(&mut state, 64);
+        assert_eq!(BodyLimit::borrow_from(&state).get(), 64);
+        assert!(!exceeds_body_limit(&state));
+
+        let body = Body::take_from(&mut state);
+        let bytes = block_on(body.to_bytes()).unwrap();
+        assert_eq!(bytes, "hello world");
+    }
+
+    #[test]
+    fn to_bytes_surfaces_errors() {
+        let body = Body::from_stream(stream::iter(vec![
+            Ok(Bytes::from("partial")),
+            Err(Error::new("connection reset")),
+        ]));
+
+        let err = block_on(body.to_bytes()).unwrap_err();
+        assert!(!err.is_length_limit());
+    }
+
+    #[test]
+    fn stream_errors_keep_their_kind() {
+        let body = Body::from_stream(stream::iter(vec![Err::<Bytes, _>(io::Error::other(
+            Error::length_limit(4),
+        ))]));
+
+        let err = block_on(body.to_bytes()).unwrap_err();
+        assert!(err.is_length_limit());
+        assert_eq!(err.message(), "length limit of 4 bytes exceeded");
+    }
+
+    #[test]
+    fn channel_sends_data_then_trailers() {
+        let (mut sender, body) = Body::channel(4);
+
+        let collected = block_on(async move {
+            sender.send_data("hello").await.unwrap();
+            sender.send_data(Bytes::from(" world")).await.unwrap();
+
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", "abc".parse().unwrap());
+            sender.send_trailers(trailers).await.unwrap();
+
+            BodyExt::collect(body).await.unwrap()
+        });
+
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc");
+        assert_eq!(collected.to_bytes(), "hello world");
+    }
+
+    #[test]
+    fn channel_applies_backpressure() {
+        let (mut sender, mut body) = Body::channel(1);
+
+        block_on(async move {
+            sender.send_data("one").await.unwrap();
+
+            // The channel is full until the body is read.
+            let mut send = Box::pin(sender.send_data("two"));
+            assert!(futures_util::poll!(send.as_mut()).is_pending());
+
+            let frame = body.frame().await.unwrap().unwrap();
+            assert_eq!(frame.into_data().unwrap(), "one");
+            send.await.unwrap();
+            drop(sender);
+
+            assert_eq!(body.to_bytes().await.unwrap(), "two");
+        });
+    }
+
+    #[test]
+    fn channel_reports_dropped_bodies_and_aborts() {
+        let (mut sender, body) = Body::channel(1);
+        drop(body);
+        assert!(sender.is_closed());
+        assert!(block_on(sender.send_data("lost")).is_err());
+
+        let (mut sender, body) = Body::channel(4);
+        let err = block_on(async move {
+            sender.send_data("partial").await.unwrap();
+            sender.abort("database went away");
+            body.to_bytes().await.unwrap_err()
+        });
+        assert_eq!(err.message(), "database went away");
+    }
+
+    #[test]
+    fn writer_streams_into_the_body() {
+        use tokio::io::AsyncWriteExt;
+
+        let (sender, body) = Body::channel(1);
+        let mut writer = tokio::io::BufWriter::with_capacity(8, sender.into_writer());
+
+        let bytes = block_on(async move {
+            let write = async move {
+                for line in ["id,name\n", "1,alice\n", "2,bob\n"] {
+                    writer.write_all(line.as_bytes()).await.unwrap();
+                }
+                writer.shutdown().await.unwrap();
+            };
+
+            let (_, bytes) = futures_util::join!(write, body.to_bytes());
+            bytes.unwrap()
+        });
+
+        assert_eq!(bytes, "id,name\n1,alice\n2,bob\n");
+    }
 }
@@ -13,6 +13,14 @@ use http_body::Body;
 pub struct Error {
     message: SharedString,
     source: Option<Box<Error>>,
+    kind: Kind,
+}
+
+/// What went wrong, where callers need to tell errors apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Other,
+    LengthLimit,
 }
 
 impl Error {
@@ -22,6 +30,7 @@ impl Error {
         Self {
             message: error.into(),
             source: None,
+            kind: Kind::Other,
         }
     }
     #[inline]
@@ -29,6 +38,7 @@ impl Error {
         Self {
             message: SharedString::from(error.into().to_string()),
             source: None,
+            kind: Kind::Other,
         }
     }
 
@@ -36,6 +46,7 @@ impl Error {
         Self {
             message: error.into(),
             source: Some(Box::new(source.into())),
+            kind: Kind::Other,
         }
     }
 
@@ -44,9 +55,35 @@ impl Error {
         Self {
             message: message.into(),
             source: Some(Box::new(self)),
+            kind: Kind::Other,
+        }
+    }
+
+    /// Create an `Error` signalling that a body was larger than `limit` bytes.
+    pub(crate) fn length_limit(limit: usize) -> Self {
+        Self {
+            message: format!("length limit of {} bytes exceeded", limit).into(),
+            source: None,
+            kind: Kind::LengthLimit,
         }
     }
 
+    /// Create an `Error` signalling that a compressed body expanded more than `ratio` times when
+    /// decoded. It counts as a length limit error.
+    pub(crate) fn compression_ratio_limit(ratio: usize) -> Self {
+        Self {
+            message: format!("compression ratio of {} exceeded", ratio).into(),
+            source: None,
+            kind: Kind::LengthLimit,
+        }
+    }
+
+    /// Returns `true` if `self`, or any of its sources, was caused by a body exceeding its size
+    /// limit.
+    pub fn is_length_limit(&self) -> bool {
+        self.sources().any(|error| error.kind == Kind::LengthLimit)
+    }
+
     /// Returns the error message.
     #[inline]
     pub fn message(&self) -> &str {
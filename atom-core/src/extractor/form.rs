@@ -0,0 +1,175 @@
+//! Defines the `FormExtractor` trait, which deserializes `application/x-www-form-urlencoded`
+//! request bodies into `State`.
+
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+
+use futures_util::future::FutureExt;
+use hyper::{body::Body as HttpBody, Response, StatusCode};
+use log::debug;
+use serde::Deserialize;
+
+use crate::body::Body;
+use crate::extractor::internal::from_query_string_mapping;
+use crate::helpers::http::request::query_string;
+use crate::helpers::http::response::create_empty_response;
+use crate::router::response::StaticResponseExtender;
+use crate::router::route::matcher::{ContentTypeHeaderRouteMatcher, RouteMatcher};
+use crate::state::{request_id, FromState, State};
+
+/// A type which can be deserialized from an `application/x-www-form-urlencoded` request body,
+/// applied to a route with `DefineSingleRoute::with_form_extractor`.
+///
+/// The body is decoded with the same rules as a `QueryStringExtractor`, so repeated keys can be
+/// collected into a `Vec<T>`.
+pub trait FormExtractor<B>:
+    for<'de> Deserialize<'de> + StaticResponseExtender<ResBody = B> + Any + Send
+where
+    B: HttpBody,
+{
+}
+
+impl<T, B> FormExtractor<B> for T
+where
+    B: HttpBody,
+    for<'de> T: Deserialize<'de> + StaticResponseExtender<ResBody = B> + Any + Send,
+{
+}
+
+/// The future returned by a `BodyExtraction`, which yields the `State` with the extracted value
+/// stored, or the response to send instead of invoking the handler.
+pub(crate) type BodyExtractionFuture =
+    Pin<Box<dyn Future<Output = Result<State, (State, Response<Body>)>> + Send>>;
+
+/// Reads the request body and stores it in `State` as a typed value, before the handler runs.
+pub(crate) type BodyExtraction = fn(State) -> BodyExtractionFuture;
+
+/// Reads an `application/x-www-form-urlencoded` body and stores it in `State` as `FE`.
+///
+/// Requests with another `Content-Type` receive `415 Unsupported Media Type`, and bodies over
+/// their `BodyLimit` receive `413 Payload Too Large`. Bodies which fail to deserialize are passed
+/// to `FE`'s `StaticResponseExtender`, as with the other extractors.
+pub(crate) fn extract_form<FE>(mut state: State) -> BodyExtractionFuture
+where
+    FE: FormExtractor<Body>,
+{
+    async move {
+        let matcher =
+            ContentTypeHeaderRouteMatcher::new(vec![mime::APPLICATION_WWW_FORM_URLENCODED]);
+        if matcher.is_match(&state).is_err() {
+            let res = create_empty_response(&state, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            return Err((state, res));
+        }
+
+        let body = Body::try_take_from(&mut state).unwrap_or_default();
+        let bytes = match body.to_bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                debug!("[{}] failed to read form body: {}", request_id(&state), err);
+                let status = if err.is_length_limit() {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                let res = create_empty_response(&state, status);
+                return Err((state, res));
+            }
+        };
+
+        let result: Result<FE, String> = match std::str::from_utf8(&bytes) {
+            Ok(form) => {
+                let mapping = query_string::split(Some(form));
+                from_query_string_mapping(&mapping).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+
+        match result {
+            Ok(val) => {
+                state.put(val);
+                Ok(state)
+            }
+            Err(e) => {
+                debug!("[{}] form extractor failed: {}", request_id(&state), e);
+                let mut res = Response::new(Body::empty());
+                FE::extend(&mut state, &mut res);
+                Err((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::CONTENT_TYPE;
+    use hyper::Request;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Signup {
+        name: String,
+        age: u8,
+        tag: Vec<String>,
+    }
+
+    impl StaticResponseExtender for Signup {
+        type ResBody = Body;
+
+        fn extend(_state: &mut State, res: &mut Response<Body>) {
+            *res.status_mut() = StatusCode::BAD_REQUEST;
+        }
+    }
+
+    fn extract(content_type: &str, body: &'static str) -> Result<State, StatusCode> {
+        let req = Request::post("http://localhost/")
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(extract_form::<Signup>(state))
+            .map_err(|(_, res)| res.status())
+    }
+
+    fn status(content_type: &str, body: &'static str) -> StatusCode {
+        extract(content_type, body).map(|_| ()).unwrap_err()
+    }
+
+    #[test]
+    fn extracts_form_body() {
+        let state = extract(
+            "application/x-www-form-urlencoded",
+            "name=Jane+Doe&age=42&tag=a&tag=b%26c",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Signup::borrow_from(&state),
+            &Signup {
+                name: "Jane Doe".into(),
+                age: 42,
+                tag: vec!["a".into(), "b&c".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_forms() {
+        let form = "application/x-www-form-urlencoded";
+
+        assert_eq!(
+            status("application/json", "name=x&age=1"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(status(form, "name=x&age=old"), StatusCode::BAD_REQUEST);
+        assert_eq!(status(form, "age=1"), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,263 @@
+//! Defines `Json`, which reads typed JSON request bodies and writes typed JSON responses.
+
+use std::ops::{Deref, DerefMut};
+
+use hyper::{Response, StatusCode};
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+use thiserror::Error;
+
+use crate::body::Body;
+use crate::handler::IntoResponse;
+use crate::helpers::http::response::{create_empty_response, create_response};
+use crate::router::route::matcher::{ContentTypeHeaderRouteMatcher, RouteMatcher};
+use crate::state::{request_id, FromState, State};
+
+/// A JSON request or response body, deserialized into or serialized from `T`.
+///
+/// As an extractor, `Json::extract` takes the request body out of `State` and deserializes it,
+/// failing with a `JsonRejection` which carries the appropriate status code. As a responder, a
+/// `Json` value serializes `T` and sets `Content-Type: application/json`.
+///
+/// ```rust,ignore
+/// #[derive(Deserialize)]
+/// struct NewUser {
+///     name: String,
+/// }
+///
+/// #[derive(Serialize)]
+/// struct User {
+///     id: u64,
+///     name: String,
+/// }
+///
+/// async fn create_user(state: &mut State) -> Result<Json<User>, HandlerError> {
+///     let Json(new_user) = Json::<NewUser>::extract(state).await?;
+///
+///     Ok(Json(User {
+///         id: 1,
+///         name: new_user.name,
+///     }))
+/// }
+///
+/// build_simple_router(|route| {
+///     route.post("/users").to_async_borrowing(create_user);
+/// })
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    /// Consumes the `Json`, returning the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Json<T>
+where
+    T: DeserializeOwned,
+{
+    /// Takes the request body out of `state` and deserializes it as JSON.
+    ///
+    /// The request must have a `Content-Type` of `application/json`, as checked by a
+    /// `ContentTypeHeaderRouteMatcher`. The body is read in full, subject to any `BodyLimit`
+    /// configured for the request.
+    pub async fn extract(state: &mut State) -> Result<Json<T>, JsonRejection> {
+        ContentTypeHeaderRouteMatcher::new(vec![mime::APPLICATION_JSON])
+            .is_match(state)
+            .map_err(|_| JsonRejection::UnsupportedMediaType)?;
+
+        let body = Body::try_take_from(state).unwrap_or_default();
+        let bytes = body.to_bytes().await.map_err(JsonRejection::Body)?;
+
+        let value = serde_json::from_slice(&bytes).map_err(|err| match err.classify() {
+            Category::Data => JsonRejection::Data(err),
+            Category::Io | Category::Syntax | Category::Eof => JsonRejection::Syntax(err),
+        })?;
+
+        Ok(Json(value))
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Json<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for Json<T> {
+    fn from(value: T) -> Json<T> {
+        Json(value)
+    }
+}
+
+impl<T> IntoResponse for Json<T>
+where
+    T: Serialize,
+{
+    fn into_response(self, state: &State) -> Response<Body> {
+        match serde_json::to_vec(&self.0) {
+            Ok(bytes) => create_response(state, StatusCode::OK, mime::APPLICATION_JSON, bytes),
+            Err(err) => {
+                error!(
+                    "[{}] failed to serialize JSON response: {}",
+                    request_id(state),
+                    err
+                );
+                create_empty_response(state, StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// The reasons `Json::extract` can fail.
+///
+/// Returning a `JsonRejection` from a handler with `?` produces a `HandlerError` with the status
+/// given by `JsonRejection::status`. Used as a response directly, it also describes the problem
+/// in a JSON body of the form `{"error": "..."}`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum JsonRejection {
+    /// The request did not have a `Content-Type` of `application/json`.
+    #[error("expected a request with Content-Type: application/json")]
+    UnsupportedMediaType,
+    /// The request body could not be read, or exceeded its size limit.
+    #[error("failed to read the request body: {0}")]
+    Body(#[source] crate::error::Error),
+    /// The request body was not syntactically valid JSON.
+    #[error("failed to parse the request body as JSON: {0}")]
+    Syntax(#[source] serde_json::Error),
+    /// The request body was valid JSON, but did not match the expected type.
+    #[error("failed to deserialize the request body: {0}")]
+    Data(#[source] serde_json::Error),
+}
+
+impl JsonRejection {
+    /// The status code which should be sent to the client.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JsonRejection::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            JsonRejection::Body(err) if err.is_length_limit() => StatusCode::PAYLOAD_TOO_LARGE,
+            JsonRejection::Body(_) | JsonRejection::Syntax(_) => StatusCode::BAD_REQUEST,
+            JsonRejection::Data(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl IntoResponse for JsonRejection {
+    fn into_response(self, state: &State) -> Response<Body> {
+        debug!("[{}] rejecting JSON request: {}", request_id(state), self);
+
+        let body = serde_json::json!({ "error": self.to_string() });
+        create_response(
+            state,
+            self.status(),
+            mime::APPLICATION_JSON,
+            body.to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::CONTENT_TYPE;
+    use hyper::Request;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    fn extract(content_type: Option<&str>, body: &'static str) -> Result<Point, JsonRejection> {
+        let mut req = Request::post("http://localhost/");
+        if let Some(content_type) = content_type {
+            req = req.header(CONTENT_TYPE, content_type);
+        }
+
+        let req = req.body(Body::from(body)).unwrap();
+        let mut state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(Json::extract(&mut state))
+            .map(Json::into_inner)
+    }
+
+    #[test]
+    fn extracts_json() {
+        let point = extract(Some("application/json"), r#"{"x":1,"y":2}"#).unwrap();
+        assert_eq!(point, Point { x: 1, y: 2 });
+
+        let point = extract(Some("application/json; charset=utf-8"), r#"{"x":3,"y":4}"#).unwrap();
+        assert_eq!(point, Point { x: 3, y: 4 });
+    }
+
+    #[test]
+    fn rejects_other_content_types() {
+        let status = |content_type| {
+            extract(content_type, r#"{"x":1,"y":2}"#)
+                .unwrap_err()
+                .status()
+        };
+
+        assert_eq!(status(None), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            status(Some("text/plain")),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn classifies_invalid_bodies() {
+        let status = |body| {
+            extract(Some("application/json"), body)
+                .unwrap_err()
+                .status()
+        };
+
+        assert_eq!(status(r#"{"x":1,"#), StatusCode::BAD_REQUEST);
+        assert_eq!(status(""), StatusCode::BAD_REQUEST);
+        assert_eq!(status(r#"{"x":1}"#), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            status(r#"{"x":"1","y":2}"#),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn responds_with_json() {
+        let req = Request::get("http://localhost/")
+            .body(Body::empty())
+            .unwrap();
+        let state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+
+        let res = Json(serde_json::json!({"ok": true})).into_response(&state);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+
+        let res = JsonRejection::UnsupportedMediaType.into_response(&state);
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+    }
+}
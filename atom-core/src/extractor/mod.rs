@@ -1,3 +1,10 @@
+pub mod form;
 pub mod internal;
+pub mod json;
+pub mod multipart;
 pub mod path;
-pub mod query_string;
\ No newline at end of file
+pub mod query_string;
+
+pub use form::FormExtractor;
+pub use json::{Json, JsonRejection};
+pub use multipart::{Field, Multipart, MultipartError, MultipartLimits, SpooledFile};
@@ -0,0 +1,814 @@
+//! Defines `Multipart`, a streaming parser for `multipart/form-data` request bodies.
+//!
+//! Fields are parsed out of the request `Body` as it arrives, so uploads never have to be held in
+//! memory in full. Each `Field` is an async stream of its content, and file parts can be spooled
+//! to disk with `Field::spool`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::future::poll_fn;
+use futures_util::stream::{Stream, StreamExt};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::StatusCode;
+use mime::Mime;
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::body::Body;
+use crate::state::{FromState, State};
+
+/// The most headers a single part may carry.
+const MAX_PART_HEADERS: usize = 32;
+
+/// The largest header block a single part may carry, in bytes.
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+
+/// Limits applied while parsing a `Multipart` body. By default nothing is limited beyond the
+/// `BodyLimit` of the request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MultipartLimits {
+    field_size: Option<usize>,
+    total_size: Option<usize>,
+}
+
+impl MultipartLimits {
+    /// Limits the content of each field to `size` bytes.
+    pub fn field_size(mut self, size: usize) -> MultipartLimits {
+        self.field_size = Some(size);
+        self
+    }
+
+    /// Limits the whole body, including boundaries and part headers, to `size` bytes.
+    pub fn total_size(mut self, size: usize) -> MultipartLimits {
+        self.total_size = Some(size);
+        self
+    }
+}
+
+/// Where the parser is within the body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// Skipping anything before the first boundary.
+    Preamble,
+    /// Just after a boundary, which either ends the body or is followed by a part.
+    Delimiter,
+    /// Reading the headers of a part.
+    Headers,
+    /// Reading the content of a part.
+    Data,
+    /// The closing boundary has been seen, or parsing failed.
+    Done,
+}
+
+/// A streaming `multipart/form-data` parser.
+///
+/// ```rust,ignore
+/// async fn upload(state: &mut State) -> Result<String, HandlerError> {
+///     let limits = MultipartLimits::default().field_size(64 * 1024 * 1024);
+///     let mut multipart = Multipart::extract(state)?.with_limits(limits);
+///     let mut uploaded = Vec::new();
+///
+///     while let Some(field) = multipart.next_field().await? {
+///         if field.file_name().is_some() {
+///             let file = field.spool().await?;
+///             uploaded.push(file.persist(format!("uploads/{}", Uuid::new_v4())).await?);
+///         }
+///     }
+///
+///     Ok(format!("{:?}", uploaded))
+/// }
+/// ```
+pub struct Multipart {
+    body: Body,
+    buffer: BytesMut,
+    // `\r\n--` followed by the boundary, which precedes every boundary in the body.
+    delimiter: Bytes,
+    stage: Stage,
+    limits: MultipartLimits,
+    total_read: usize,
+    field_read: usize,
+    eof: bool,
+}
+
+impl Multipart {
+    /// Creates a parser for `body`, with parts separated by `boundary`.
+    pub fn new<S: AsRef<str>>(body: Body, boundary: S) -> Multipart {
+        let mut delimiter = BytesMut::from(&b"\r\n--"[..]);
+        delimiter.extend_from_slice(boundary.as_ref().as_bytes());
+
+        // The first boundary may start the body without a preceding line break, which is
+        // simpler to find if we pretend one was there.
+        let buffer = BytesMut::from(&b"\r\n"[..]);
+
+        Multipart {
+            body,
+            buffer,
+            delimiter: delimiter.freeze(),
+            stage: Stage::Preamble,
+            limits: MultipartLimits::default(),
+            total_read: 0,
+            field_read: 0,
+            eof: false,
+        }
+    }
+
+    /// Takes the request body out of `state`, reading the boundary from its `Content-Type`.
+    ///
+    /// Fails if the request is not `multipart/form-data`, or does not specify a boundary.
+    pub fn extract(state: &mut State) -> Result<Multipart, MultipartError> {
+        let mime: Mime = state
+            .borrow::<HeaderMap>()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .ok_or(MultipartError::UnsupportedMediaType)?;
+
+        if mime.type_() != mime::MULTIPART || mime.subtype() != mime::FORM_DATA {
+            return Err(MultipartError::UnsupportedMediaType);
+        }
+
+        let boundary = mime
+            .get_param(mime::BOUNDARY)
+            .ok_or(MultipartError::MissingBoundary)?;
+
+        let body = Body::try_take_from(state).unwrap_or_default();
+        Ok(Multipart::new(body, boundary.as_str()))
+    }
+
+    /// Applies `limits` to the rest of the body.
+    pub fn with_limits(self, limits: MultipartLimits) -> Multipart {
+        Multipart { limits, ..self }
+    }
+
+    /// Advances to the next field, skipping whatever remains of the current one.
+    ///
+    /// Returns `None` once the closing boundary has been read.
+    pub async fn next_field(&mut self) -> Result<Option<Field<'_>>, MultipartError> {
+        let headers = match poll_fn(|cx| self.poll_next_part(cx)).await? {
+            Some(headers) => headers,
+            None => return Ok(None),
+        };
+
+        let (name, file_name) = headers
+            .get(CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .map(parse_content_disposition)
+            .unwrap_or_default();
+
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        Ok(Some(Field {
+            multipart: self,
+            name,
+            file_name,
+            content_type,
+            headers,
+        }))
+    }
+
+    /// Reads up to the headers of the next part.
+    fn poll_next_part(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, MultipartError>> {
+        loop {
+            match self.stage {
+                Stage::Preamble => match memchr::memmem::find(&self.buffer, &self.delimiter) {
+                    Some(idx) => {
+                        self.buffer.advance(idx + self.delimiter.len());
+                        self.stage = Stage::Delimiter;
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        if self.buffer.len() > keep {
+                            self.buffer.advance(self.buffer.len() - keep);
+                        }
+                        futures_util::ready!(self.fill(cx, "missing opening boundary"))?;
+                    }
+                },
+                Stage::Delimiter => {
+                    // Transport padding may follow the boundary before the line break.
+                    let padding = self
+                        .buffer
+                        .iter()
+                        .take_while(|b| **b == b' ' || **b == b'\t')
+                        .count();
+
+                    if self.buffer.starts_with(b"--") {
+                        self.stage = Stage::Done;
+                    } else if self.buffer.len() < padding + 2 {
+                        futures_util::ready!(self.fill(cx, "incomplete boundary"))?;
+                    } else if &self.buffer[padding..padding + 2] == b"\r\n" {
+                        self.buffer.advance(padding + 2);
+                        self.stage = Stage::Headers;
+                    } else {
+                        return self.fail(MultipartError::Malformed("invalid boundary"));
+                    }
+                }
+                Stage::Headers => {
+                    let mut parsed = [httparse::EMPTY_HEADER; MAX_PART_HEADERS];
+                    match httparse::parse_headers(&self.buffer, &mut parsed) {
+                        Ok(httparse::Status::Complete((len, parsed))) => {
+                            let headers = match to_header_map(parsed) {
+                                Ok(headers) => headers,
+                                Err(err) => return self.fail(err),
+                            };
+                            self.buffer.advance(len);
+                            self.stage = Stage::Data;
+                            self.field_read = 0;
+                            return Poll::Ready(Ok(Some(headers)));
+                        }
+                        Ok(httparse::Status::Partial) => {
+                            if self.buffer.len() > MAX_PART_HEADER_SIZE {
+                                return self
+                                    .fail(MultipartError::Malformed("part headers too large"));
+                            }
+                            futures_util::ready!(self.fill(cx, "incomplete part headers"))?;
+                        }
+                        Err(_) => {
+                            return self.fail(MultipartError::Malformed("invalid part headers"))
+                        }
+                    }
+                }
+                Stage::Data => match futures_util::ready!(self.poll_chunk(cx)) {
+                    Some(Ok(_)) | None => {}
+                    Some(Err(err)) => return Poll::Ready(Err(err)),
+                },
+                Stage::Done => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    /// Reads the next chunk of the current part's content, or `None` at the end of the part.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, MultipartError>>> {
+        loop {
+            if self.stage != Stage::Data {
+                return Poll::Ready(None);
+            }
+
+            let chunk = match memchr::memmem::find(&self.buffer, &self.delimiter) {
+                Some(0) => {
+                    self.buffer.advance(self.delimiter.len());
+                    self.stage = Stage::Delimiter;
+                    return Poll::Ready(None);
+                }
+                Some(idx) => self.buffer.split_to(idx),
+                None => {
+                    // The tail of the buffer may be the start of a delimiter, so hold it back.
+                    let keep = self.delimiter.len() - 1;
+                    if self.buffer.len() > keep {
+                        self.buffer.split_to(self.buffer.len() - keep)
+                    } else {
+                        match self.fill(cx, "incomplete part") {
+                            Poll::Ready(Ok(())) => continue,
+                            Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+                }
+            };
+
+            self.field_read += chunk.len();
+            if let Some(limit) = self.limits.field_size {
+                if self.field_read > limit {
+                    self.stage = Stage::Done;
+                    return Poll::Ready(Some(Err(MultipartError::FieldTooLarge { limit })));
+                }
+            }
+
+            return Poll::Ready(Some(Ok(chunk.freeze())));
+        }
+    }
+
+    /// Reads more of the body into the buffer, failing with `Malformed(context)` if the body ends
+    /// first.
+    fn fill(
+        &mut self,
+        cx: &mut Context<'_>,
+        context: &'static str,
+    ) -> Poll<Result<(), MultipartError>> {
+        if self.eof {
+            return self.fail(MultipartError::Malformed(context));
+        }
+
+        match futures_util::ready!(self.body.poll_next_unpin(cx)) {
+            Some(Ok(data)) => {
+                self.total_read += data.len();
+                if let Some(limit) = self.limits.total_size {
+                    if self.total_read > limit {
+                        return self.fail(MultipartError::TotalTooLarge { limit });
+                    }
+                }
+
+                self.buffer.extend_from_slice(&data);
+                Poll::Ready(Ok(()))
+            }
+            Some(Err(err)) => self.fail(MultipartError::Body(err)),
+            None => {
+                self.eof = true;
+                self.fail(MultipartError::Malformed(context))
+            }
+        }
+    }
+
+    fn fail<T>(&mut self, err: MultipartError) -> Poll<Result<T, MultipartError>> {
+        self.stage = Stage::Done;
+        Poll::Ready(Err(err))
+    }
+}
+
+/// A single field of a `Multipart` body.
+///
+/// The field's content is available as a `Stream` of `Bytes`, or collected with `bytes`, `text`
+/// or `spool`. Any content which hasn't been read is skipped by `Multipart::next_field`.
+pub struct Field<'a> {
+    multipart: &'a mut Multipart,
+    name: Option<String>,
+    file_name: Option<String>,
+    content_type: Option<Mime>,
+    headers: HeaderMap,
+}
+
+impl<'a> Field<'a> {
+    /// The name of the field, from its `Content-Disposition` header.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file name sent for a file upload, from its `Content-Disposition` header.
+    ///
+    /// This is supplied by the client, so must not be trusted as a path.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The media type of the field's content, from its `Content-Type` header.
+    pub fn content_type(&self) -> Option<&Mime> {
+        self.content_type.as_ref()
+    }
+
+    /// All of the headers sent for the field.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Collects the rest of the field's content into a single `Bytes`.
+    pub async fn bytes(mut self) -> Result<Bytes, MultipartError> {
+        let mut content = BytesMut::new();
+        while let Some(chunk) = self.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        Ok(content.freeze())
+    }
+
+    /// Collects the rest of the field's content into a `String`.
+    pub async fn text(self) -> Result<String, MultipartError> {
+        let bytes = self.bytes().await?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| MultipartError::Malformed("field is not valid UTF-8"))
+    }
+
+    /// Writes the rest of the field's content to a new file in the system's temporary directory.
+    pub async fn spool(self) -> Result<SpooledFile, MultipartError> {
+        self.spool_to(std::env::temp_dir()).await
+    }
+
+    /// Writes the rest of the field's content to a new file in `dir`.
+    ///
+    /// The file is removed when the returned `SpooledFile` is dropped, unless it is persisted.
+    pub async fn spool_to<P: AsRef<Path>>(mut self, dir: P) -> Result<SpooledFile, MultipartError> {
+        let path = dir
+            .as_ref()
+            .join(format!("gotham-multipart-{}", Uuid::new_v4()));
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+
+        // Created before writing, so that the file is removed again if anything fails.
+        let mut spooled = SpooledFile {
+            path,
+            len: 0,
+            file_name: self.file_name.take(),
+            content_type: self.content_type.take(),
+            persisted: false,
+        };
+
+        while let Some(chunk) = self.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            spooled.len += chunk.len() as u64;
+        }
+
+        file.flush().await?;
+        Ok(spooled)
+    }
+}
+
+impl<'a> Stream for Field<'a> {
+    type Item = Result<Bytes, MultipartError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().multipart.poll_chunk(cx)
+    }
+}
+
+/// A field which has been written to disk by `Field::spool`.
+///
+/// The file is removed when the `SpooledFile` is dropped, unless it has been moved elsewhere with
+/// `persist`.
+#[derive(Debug)]
+pub struct SpooledFile {
+    path: PathBuf,
+    len: u64,
+    file_name: Option<String>,
+    content_type: Option<Mime>,
+    persisted: bool,
+}
+
+impl SpooledFile {
+    /// The path of the file on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the file, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the field was empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The file name sent by the client, see `Field::file_name`.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The media type sent by the client, see `Field::content_type`.
+    pub fn content_type(&self) -> Option<&Mime> {
+        self.content_type.as_ref()
+    }
+
+    /// Moves the file to `path`, keeping it once the `SpooledFile` is dropped.
+    ///
+    /// `path` must be on the same filesystem as the directory the file was spooled to.
+    pub async fn persist<P: AsRef<Path>>(mut self, path: P) -> io::Result<PathBuf> {
+        let path = path.as_ref().to_path_buf();
+        tokio::fs::rename(&self.path, &path).await?;
+        self.persisted = true;
+        Ok(path)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The reasons parsing a `Multipart` body can fail.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MultipartError {
+    /// The request did not have a `Content-Type` of `multipart/form-data`.
+    #[error("expected a request with Content-Type: multipart/form-data")]
+    UnsupportedMediaType,
+    /// The request's `Content-Type` did not specify a boundary.
+    #[error("multipart/form-data request is missing a boundary")]
+    MissingBoundary,
+    /// The body did not follow the `multipart/form-data` format.
+    #[error("malformed multipart body: {0}")]
+    Malformed(&'static str),
+    /// The request body could not be read, or exceeded its size limit.
+    #[error("failed to read the request body: {0}")]
+    Body(#[source] crate::error::Error),
+    /// A field exceeded `MultipartLimits::field_size`.
+    #[error("multipart field exceeded {limit} bytes")]
+    FieldTooLarge {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// The body exceeded `MultipartLimits::total_size`.
+    #[error("multipart body exceeded {limit} bytes")]
+    TotalTooLarge {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// A field could not be spooled to disk.
+    #[error("failed to spool multipart field: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl MultipartError {
+    /// The status code which should be sent to the client.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MultipartError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MultipartError::Body(err) if err.is_length_limit() => StatusCode::PAYLOAD_TOO_LARGE,
+            MultipartError::FieldTooLarge { .. } | MultipartError::TotalTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            MultipartError::MissingBoundary
+            | MultipartError::Malformed(_)
+            | MultipartError::Body(_) => StatusCode::BAD_REQUEST,
+            MultipartError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn to_header_map(parsed: &[httparse::Header<'_>]) -> Result<HeaderMap, MultipartError> {
+    let mut headers = HeaderMap::with_capacity(parsed.len());
+
+    for header in parsed {
+        let name = HeaderName::from_bytes(header.name.as_bytes());
+        let value = HeaderValue::from_bytes(header.value);
+        match (name, value) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            }
+            _ => return Err(MultipartError::Malformed("invalid part headers")),
+        }
+    }
+
+    Ok(headers)
+}
+
+/// Extracts the `name` and `filename` parameters of a `Content-Disposition` header (RFC 7578),
+/// preferring an RFC 5987 encoded `filename*` where present.
+fn parse_content_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut file_name = None;
+    let mut extended_file_name = None;
+
+    // Skip the disposition type, which is always `form-data`.
+    let mut rest = value.split_once(';').map_or("", |(_, rest)| rest);
+
+    while let Some((key, value, remainder)) = next_param(rest) {
+        rest = remainder;
+
+        if key.eq_ignore_ascii_case("name") {
+            name = Some(value);
+        } else if key.eq_ignore_ascii_case("filename") {
+            file_name = Some(value);
+        } else if key.eq_ignore_ascii_case("filename*") {
+            extended_file_name = decode_extended_value(&value);
+        }
+    }
+
+    (name, extended_file_name.or(file_name))
+}
+
+/// Parses a `key=value` parameter from the start of `input`, where the value may be a token or a
+/// quoted string, returning the remaining input.
+fn next_param(input: &str) -> Option<(&str, String, &str)> {
+    let input = input.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+    let (key, rest) = input.split_once('=')?;
+    let rest = rest.trim_start();
+
+    let mut value = String::new();
+    let remainder = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = idx + 1;
+                        break;
+                    }
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    c => value.push(c),
+                }
+            }
+            &quoted[end..]
+        }
+        None => {
+            let end = rest.find(';').unwrap_or(rest.len());
+            value.push_str(rest[..end].trim_end());
+            &rest[end..]
+        }
+    };
+
+    Some((key.trim(), value, remainder))
+}
+
+/// Decodes an RFC 5987 `charset'language'value` string. Only UTF-8 is supported, as required by
+/// RFC 6266.
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+
+    percent_decode_str(encoded)
+        .decode_utf8()
+        .ok()
+        .map(|decoded| decoded.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Splits `body` into chunks of `size` bytes, to exercise boundaries spanning chunks.
+    fn chunked(body: &str, size: usize) -> Body {
+        let chunks: Vec<_> = body
+            .as_bytes()
+            .chunks(size)
+            .map(|chunk| Ok::<_, io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Body::from_stream(stream::iter(chunks))
+    }
+
+    fn sample() -> String {
+        format!(
+            "preamble\r\n\
+             --{0}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\
+             \r\n\
+             hello world\r\n\
+             --{0}\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             line one\r\n\
+             line two\r\n\
+             --{0}--\r\n\
+             epilogue",
+            BOUNDARY
+        )
+    }
+
+    async fn collect(
+        mut multipart: Multipart,
+    ) -> Result<Vec<(Option<String>, Option<String>, Bytes)>, MultipartError> {
+        let mut fields = Vec::new();
+        while let Some(field) = multipart.next_field().await? {
+            let name = field.name().map(String::from);
+            let file_name = field.file_name().map(String::from);
+            fields.push((name, file_name, field.bytes().await?));
+        }
+        Ok(fields)
+    }
+
+    #[test]
+    fn parses_fields_across_chunk_sizes() {
+        for size in [1, 3, 7, 16, 1024] {
+            let multipart = Multipart::new(chunked(&sample(), size), BOUNDARY);
+            let fields = block_on(collect(multipart)).unwrap();
+
+            assert_eq!(
+                fields,
+                vec![
+                    (Some("title".into()), None, Bytes::from("hello world")),
+                    (
+                        Some("upload".into()),
+                        Some("a \"b\".txt".into()),
+                        Bytes::from("line one\r\nline two")
+                    ),
+                ],
+                "chunk size {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn skips_unread_fields() {
+        block_on(async {
+            let mut multipart = Multipart::new(chunked(&sample(), 5), BOUNDARY);
+
+            let first = multipart.next_field().await.unwrap().unwrap();
+            assert_eq!(first.name(), Some("title"));
+            drop(first);
+
+            let second = multipart.next_field().await.unwrap().unwrap();
+            assert_eq!(second.content_type(), Some(&mime::TEXT_PLAIN));
+            assert!(multipart.next_field().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = MultipartLimits::default().field_size(12);
+        let multipart = Multipart::new(chunked(&sample(), 4), BOUNDARY).with_limits(limits);
+        let err = block_on(collect(multipart)).unwrap_err();
+        assert!(matches!(err, MultipartError::FieldTooLarge { limit: 12 }));
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let limits = MultipartLimits::default().total_size(64);
+        let multipart = Multipart::new(chunked(&sample(), 4), BOUNDARY).with_limits(limits);
+        let err = block_on(collect(multipart)).unwrap_err();
+        assert!(matches!(err, MultipartError::TotalTooLarge { limit: 64 }));
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        let body = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nunterminated",
+            BOUNDARY
+        );
+        let multipart = Multipart::new(chunked(&body, 8), BOUNDARY);
+        let err = block_on(collect(multipart)).unwrap_err();
+        assert!(matches!(err, MultipartError::Malformed(_)));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn spools_fields_to_disk() {
+        block_on(async {
+            let mut multipart = Multipart::new(chunked(&sample(), 6), BOUNDARY);
+            multipart.next_field().await.unwrap();
+
+            let field = multipart.next_field().await.unwrap().unwrap();
+            let spooled = field.spool().await.unwrap();
+            let path = spooled.path().to_path_buf();
+
+            assert_eq!(spooled.len(), 18);
+            assert_eq!(spooled.file_name(), Some("a \"b\".txt"));
+            assert_eq!(std::fs::read(&path).unwrap(), b"line one\r\nline two");
+
+            drop(spooled);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn extracts_boundary_from_request() {
+        State::with_new(|state| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, "text/plain".parse().unwrap());
+            state.put(headers);
+            assert!(matches!(
+                Multipart::extract(state),
+                Err(MultipartError::UnsupportedMediaType)
+            ));
+
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, "multipart/form-data".parse().unwrap());
+            state.put(headers);
+            assert!(matches!(
+                Multipart::extract(state),
+                Err(MultipartError::MissingBoundary)
+            ));
+
+            let mut headers = HeaderMap::new();
+            let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+            headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+            state.put(headers);
+            state.put(chunked(&sample(), 32));
+
+            let multipart = Multipart::extract(state).unwrap();
+            assert_eq!(block_on(collect(multipart)).unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn content_disposition() {
+        assert_eq!(
+            parse_content_disposition("form-data; name=field"),
+            (Some("field".into()), None)
+        );
+        assert_eq!(
+            parse_content_disposition(
+                "form-data; name=\"file\"; filename=\"plain.txt\"; filename*=UTF-8''%E2%82%AC.txt"
+            ),
+            (Some("file".into()), Some("€.txt".into()))
+        );
+        assert_eq!(parse_content_disposition("form-data"), (None, None));
+    }
+}
@@ -1,4 +1,4 @@
-mod accepted_encoding;
+pub(crate) mod accepted_encoding;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures_util::stream::{self, TryStream, TryStreamExt};
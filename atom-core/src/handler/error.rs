@@ -5,12 +5,14 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use crate::body::Body;
+use crate::extractor::{JsonRejection, MultipartError};
 use hyper::{Response, StatusCode};
 use log::{debug, trace};
 
 use crate::handler::IntoResponse;
 use crate::helpers::http::response::create_empty_response;
 use crate::state::{request_id, State};
+use crate::websocket::WebSocketUpgradeError;
 
 /// Describes an error which occurred during handler execution, and allows the creation of a HTTP
 /// `Response`.
@@ -22,6 +24,11 @@ pub struct HandlerError {
 
 /// Convert a generic `anyhow::Error` into a `HandlerError`, similar as you would a concrete error
 /// type with `into_handler_error()`.
+///
+/// Errors from reading a request body which exceeded its size limit become
+/// `413 Payload Too Large`, and a `JsonRejection`, `MultipartError` or `WebSocketUpgradeError`
+/// keeps its own status. Everything else becomes `500 Internal Server Error`; use
+/// `map_err_with_status` or `HandlerError::with_status` to choose the status of other errors.
 impl<E> From<E> for HandlerError
 where
     E: Into<anyhow::Error> + Display,
@@ -29,10 +36,31 @@ where
     fn from(error: E) -> HandlerError {
         trace!(" converting Error to HandlerError: {}", error);
 
-        HandlerError {
-            status_code: StatusCode::INTERNAL_SERVER_ERROR,
-            cause: error.into(),
-        }
+        let cause = error.into();
+        let status_code = status_for(&cause);
+
+        HandlerError { status_code, cause }
+    }
+}
+
+/// Picks the status for errors raised by Gotham while reading the request, which are the client's
+/// fault rather than `500 Internal Server Error`.
+fn status_for(cause: &anyhow::Error) -> StatusCode {
+    if let Some(rejection) = cause.downcast_ref::<JsonRejection>() {
+        return rejection.status();
+    }
+
+    if let Some(err) = cause.downcast_ref::<MultipartError>() {
+        return err.status();
+    }
+
+    if let Some(err) = cause.downcast_ref::<WebSocketUpgradeError>() {
+        return err.status();
+    }
+
+    match cause.downcast_ref::<crate::error::Error>() {
+        Some(err) if err.is_length_limit() => StatusCode::PAYLOAD_TOO_LARGE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
@@ -208,4 +236,28 @@ mod test {
         assert!(err.downcast_cause_ref::<io::Error>().is_none());
         assert!(err.downcast_cause_mut::<io::Error>().is_none());
     }
+
+    #[test]
+    fn test_request_error_status() {
+        let err = HandlerError::from(crate::error::Error::length_limit(16));
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let err = HandlerError::from(JsonRejection::UnsupportedMediaType);
+        assert_eq!(err.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let err = HandlerError::from(MultipartError::FieldTooLarge { limit: 16 });
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let err = HandlerError::from(WebSocketUpgradeError::MethodNotGet);
+        assert_eq!(err.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let err = HandlerError::from(crate::error::Error::new("unrelated"));
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let err = HandlerError::from(anyhow::Error::from(crate::error::Error::length_limit(16)));
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let err = HandlerError::from(DummyError);
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 }
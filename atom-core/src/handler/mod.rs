@@ -13,7 +13,7 @@ use mime::{self, Mime};
 use crate::helpers::http::response;
 use crate::state::State;
 
-mod assets;
+pub(crate) mod assets;
 pub use assets::*;
 
 mod error;
@@ -1,8 +1,48 @@
 //! Headers recognised by Gotham which do not exist in the standard headers
 //! provided by the Hyper library.
 
+use hyper::header::{HeaderMap, HeaderValue, VARY};
+
 /// Marks the identifier of a request to a Gotham server.
 pub const X_REQUEST_ID: &str = "x-request-id";
 
 /// Marks the execution time of a Gotham request.
 pub const X_RUNTIME_DURATION: &str = "x-runtime-duration";
+
+/// Carries the ID of the last Server-Sent Event received by a reconnecting client.
+pub const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Controls which browser features a document may use.
+pub const PERMISSIONS_POLICY: &str = "permissions-policy";
+
+/// Isolates a document's browsing context group from cross-origin documents.
+pub const CROSS_ORIGIN_OPENER_POLICY: &str = "cross-origin-opener-policy";
+
+/// Requires the cross-origin resources loaded by a document to opt in.
+pub const CROSS_ORIGIN_EMBEDDER_POLICY: &str = "cross-origin-embedder-policy";
+
+/// Restricts which origins may load a resource.
+pub const CROSS_ORIGIN_RESOURCE_POLICY: &str = "cross-origin-resource-policy";
+
+/// The number of requests a client may make in each window of a rate limit.
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+
+/// The number of requests a client may still make in the current window of a rate limit.
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+
+/// The number of seconds until a rate limit is fully replenished.
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+/// Adds `name` to the `Vary` header, unless it's listed already or `Vary` is `*`.
+pub(crate) fn add_vary(headers: &mut HeaderMap, name: &'static str) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim() == "*" || value.trim().eq_ignore_ascii_case(name));
+
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static(name));
+    }
+}
@@ -1,13 +1,14 @@
 //! Helpers for HTTP response generation
 
 use crate::body::Body;
-use hyper::header::{CONTENT_TYPE, LOCATION};
-use hyper::{Method, Response, StatusCode};
+use hyper::header::{HeaderMap, CONTENT_TYPE, HOST, LOCATION};
+use hyper::http::uri::{Authority, PathAndQuery};
+use hyper::{Method, Response, StatusCode, Uri};
 use mime::Mime;
 use std::borrow::Cow;
 
 use crate::helpers::http::header::X_REQUEST_ID;
-use crate::state::{request_id, FromState, State};
+use crate::state::{real_client, request_id, FromState, State};
 
 pub fn create_response<B>(state: &State, status: StatusCode, mime: Mime, body: B) -> Response<Body>
 where
@@ -41,22 +42,107 @@ pub fn create_empty_response(state: &State, status: StatusCode) -> Response<Body
     built.expect("Response built from a compatible type")
 }
 
+/// Creates a `308 Permanent Redirect` response to `location`.
+///
+/// A path such as `/login` is expanded into an absolute URL when the original scheme and host of
+/// the request are known, see `RealClient`.
 pub fn create_permanent_redirect<L: Into<Cow<'static, str>>>(
     state: &State,
     location: L,
 ) -> Response<Body> {
     let mut res = create_empty_response(state, StatusCode::PERMANENT_REDIRECT);
-    res.headers_mut()
-        .insert(LOCATION, location.into().to_string().parse().unwrap());
+    res.headers_mut().insert(
+        LOCATION,
+        absolute_location(state, location.into()).parse().unwrap(),
+    );
     res
 }
 
+/// Creates a `307 Temporary Redirect` response to `location`.
+///
+/// A path such as `/login` is expanded into an absolute URL when the original scheme and host of
+/// the request are known, see `RealClient`.
 pub fn create_temporary_redirect<L: Into<Cow<'static, str>>>(
     state: &State,
     location: L,
 ) -> Response<Body> {
     let mut res = create_empty_response(state, StatusCode::TEMPORARY_REDIRECT);
-    res.headers_mut()
-        .insert(LOCATION, location.into().to_string().parse().unwrap());
+    res.headers_mut().insert(
+        LOCATION,
+        absolute_location(state, location.into()).parse().unwrap(),
+    );
     res
 }
+
+/// Creates a `308 Permanent Redirect` response to the same path and query on the HTTPS origin of
+/// the request's host, listening on `https_port`.
+///
+/// Requests without a valid `Host` receive `400 Bad Request`, since there is nowhere to redirect
+/// them to.
+pub fn create_https_redirect(state: &State, https_port: u16) -> Response<Body> {
+    let host = state
+        .borrow::<HeaderMap>()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .or_else(|| Uri::borrow_from(state).authority().cloned());
+
+    let host = match host {
+        Some(host) => host,
+        None => return create_empty_response(state, StatusCode::BAD_REQUEST),
+    };
+
+    let path = Uri::borrow_from(state)
+        .path_and_query()
+        .map(PathAndQuery::as_str)
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or("/");
+
+    let location = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+
+    create_permanent_redirect(state, location)
+}
+
+fn absolute_location(state: &State, location: Cow<'static, str>) -> Cow<'static, str> {
+    if location.starts_with('/') && !location.starts_with("//") {
+        if let Some(url) = real_client(state).and_then(|client| client.url_for(&location)) {
+            return Cow::Owned(url);
+        }
+    }
+    location
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    fn https_redirect(uri: &str, host: Option<&str>, https_port: u16) -> Response<Body> {
+        let mut req = Request::get(uri);
+        if let Some(host) = host {
+            req = req.header(HOST, host);
+        }
+
+        let state = State::from_request(
+            req.body(Body::empty()).unwrap(),
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+        create_https_redirect(&state, https_port)
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let res = https_redirect("/search?q=a%20b", Some("example.org:8080"), 443);
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://example.org/search?q=a%20b");
+
+        let res = https_redirect("http://[::1]:8080/", None, 8443);
+        assert_eq!(res.headers()[LOCATION], "https://[::1]:8443/");
+
+        let res = https_redirect("/", None, 443);
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -46,6 +46,13 @@ impl Timer {
 #[derive(Clone, Copy)]
 pub(crate) struct Timing(Duration);
 
+impl Timing {
+    /// Returns the elapsed time as a `Duration`.
+    pub(crate) fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
 impl Display for Timing {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let duration = self.0;
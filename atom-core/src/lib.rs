@@ -10,10 +10,13 @@ pub mod middleware;
 pub mod pipeline;
 pub mod plain;
 pub mod router;
+pub mod server;
 pub mod service;
+pub mod sse;
 pub mod state;
 pub mod tls;
 pub mod test;
+pub mod websocket;
 pub mod error;
 
 pub use anyhow;
@@ -28,17 +31,26 @@ pub use atom_derive;
 pub use plain::*;
 
 use crate::handler::NewHandler;
+use crate::server::listener::Listener;
+use crate::server::protocol::Http;
+use crate::server::proxy_protocol::DEFAULT_HEADER_TIMEOUT;
+use crate::server::shutdown::Connections;
+use crate::server::upgrade;
+use crate::server::{ConnectionError, ConnectionErrors, Protocols, Settings};
 use crate::service::GothamService;
-use hyper::server::conn::http1;
+use crate::tls::TlsInfo;
+use futures_util::future::{self, TryFutureExt};
 use std::future::Future;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime;
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
 
 /// The error that can occur when starting the gotham server.
 #[derive(Debug, Error)]
@@ -49,31 +61,45 @@ pub enum StartError {
     IoError(#[from] io::Error),
 }
 
-fn new_runtime(threads: usize) -> Runtime {
+fn new_runtime(threads: usize, thread_name: &str) -> Runtime {
     runtime::Builder::new_multi_thread()
         .worker_threads(threads)
-        .thread_name("gotham-worker")
+        .thread_name(thread_name)
         .enable_all()
         .build()
         .unwrap()
 }
 
+fn resolve_addr<A>(addr: A) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "unable to resolve listener address")
+    })
+}
+
 async fn tcp_listener<A>(addr: A) -> io::Result<TcpListener>
 where
     A: ToSocketAddrs + 'static,
 {
-    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::Other, "unable to resolve listener address")
-    })?;
-    TcpListener::bind(addr).await
+    TcpListener::bind(resolve_addr(addr)?).await
 }
 
 /// Returns a `Future` used to spawn a Gotham application.
 ///
 /// This is used internally, but it's exposed for clients that want to set up their own TLS
 /// support. The wrap argument is a function that will receive a tokio-io TcpStream and should wrap
-/// the socket as necessary. Errors returned by this function will be ignored and the connection
-/// will be dropped if the future returned by the wrapper resolves to an error.
+/// the socket as necessary. If the future returned by the wrapper resolves to an error the
+/// connection will be dropped, and the error reported as a `ConnectionErrorKind::TlsHandshake`.
+///
+/// The future returned by the wrapper resolves to an `io::Result`, so the reason a handshake
+/// failed can be reported. Wrappers written when it resolved to `Result<Wrapped, ()>` need to
+/// return an `io::Error` instead, e.g. by replacing `.map_err(|_| ())` with
+/// `.map_err(|err| io::Error::new(io::ErrorKind::Other, err))`.
+///
+/// Connections are served using `Protocols::Auto`, see `bind_server_with_protocols`. Connection
+/// errors are logged and counted, see `bind_server_with_connection_errors`.
 pub async fn bind_server<'a, NH, F, Wrapped, Wrap>(
     listener: TcpListener,
     new_handler: NH,
@@ -81,40 +107,355 @@ pub async fn bind_server<'a, NH, F, Wrapped, Wrap>(
 ) -> !
 where
     NH: NewHandler + 'static,
-    F: Future<Output = Result<Wrapped, ()>> + Unpin + Send + 'static,
+    F: Future<Output = io::Result<Wrapped>> + Unpin + Send + 'static,
+    Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+    Wrap: Fn(TcpStream) -> F,
+{
+    bind_server_forever(listener, new_handler, wrap, Settings::default()).await
+}
+
+/// Returns a `Future` used to spawn a Gotham application, speaking only the HTTP versions allowed
+/// by `protocols`.
+///
+/// The protocol chosen for each connection is stored in `State` as a `server::Protocol`.
+pub async fn bind_server_with_protocols<NH, F, Wrapped, Wrap>(
+    listener: TcpListener,
+    new_handler: NH,
+    wrap: Wrap,
+    protocols: Protocols,
+) -> !
+where
+    NH: NewHandler + 'static,
+    F: Future<Output = io::Result<Wrapped>> + Unpin + Send + 'static,
+    Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+    Wrap: Fn(TcpStream) -> F,
+{
+    let settings = Settings {
+        protocols,
+        ..Settings::default()
+    };
+    bind_server_forever(listener, new_handler, wrap, settings).await
+}
+
+/// Returns a `Future` used to spawn a Gotham application, reporting connection errors via
+/// `connection_errors`.
+///
+/// Connection errors are those which occur outside of any handler, such as a client resetting the
+/// connection or sending a malformed request. They never affect other connections.
+pub async fn bind_server_with_connection_errors<NH, F, Wrapped, Wrap>(
+    listener: TcpListener,
+    new_handler: NH,
+    wrap: Wrap,
+    connection_errors: ConnectionErrors,
+) -> !
+where
+    NH: NewHandler + 'static,
+    F: Future<Output = io::Result<Wrapped>> + Unpin + Send + 'static,
+    Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+    Wrap: Fn(TcpStream) -> F,
+{
+    let settings = Settings {
+        connection_errors,
+        ..Settings::default()
+    };
+    bind_server_forever(listener, new_handler, wrap, settings).await
+}
+
+/// Returns a `Future` used to spawn a Gotham application which stops gracefully once `shutdown`
+/// resolves.
+///
+/// When `shutdown` completes the listener is closed and no further connections are accepted.
+/// Connections which are already open are asked to finish the requests they are processing and
+/// then close; those still open after `drain_timeout` are aborted. The returned future resolves
+/// once every connection has been closed.
+pub async fn bind_server_with_shutdown<NH, F, Wrapped, Wrap, S>(
+    listener: TcpListener,
+    new_handler: NH,
+    wrap: Wrap,
+    shutdown: S,
+    drain_timeout: Duration,
+) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    F: Future<Output = io::Result<Wrapped>> + Unpin + Send + 'static,
+    Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+    Wrap: Fn(TcpStream) -> F,
+    S: Future<Output = ()>,
+{
+    let settings = Settings {
+        drain_timeout,
+        ..Settings::default()
+    };
+    serve(listener, new_handler, without_tls_info(wrap), settings, None, shutdown).await
+}
+
+pub(crate) async fn bind_server_forever<NH, F, Wrapped, Wrap>(
+    listener: TcpListener,
+    new_handler: NH,
+    wrap: Wrap,
+    settings: Settings,
+) -> !
+where
+    NH: NewHandler + 'static,
+    F: Future<Output = io::Result<Wrapped>> + Unpin + Send + 'static,
     Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
     Wrap: Fn(TcpStream) -> F,
 {
-    let protocol = Arc::new(http1::Builder::new());
-    let gotham_service = GothamService::new(new_handler);
+    let never = future::pending::<()>();
+    let _ = serve(listener, new_handler, without_tls_info(wrap), settings, None, never).await;
+    unreachable!("server stopped without a shutdown signal")
+}
+
+type WithoutTlsInfo<F, Wrapped> = future::MapOk<F, fn(Wrapped) -> (Wrapped, Option<TlsInfo>)>;
+
+/// Adapts a `wrap` function supplied by the application, whose connections carry no `TlsInfo`.
+fn without_tls_info<I, F, Wrapped, Wrap>(wrap: Wrap) -> impl Fn(I) -> WithoutTlsInfo<F, Wrapped>
+where
+    F: Future<Output = io::Result<Wrapped>>,
+    Wrap: Fn(I) -> F,
+{
+    move |socket| wrap(socket).map_ok((|io| (io, None)) as fn(_) -> _)
+}
+
+/// Serves connections from `listener` until `shutdown` resolves. Each connection holds a permit
+/// from `limit`, if any, which may be shared with other listeners.
+pub(crate) async fn serve<L, NH, F, Wrapped, Wrap, S>(
+    mut listener: L,
+    new_handler: NH,
+    wrap: Wrap,
+    settings: Settings,
+    limit: Option<Arc<Semaphore>>,
+    shutdown: S,
+) -> Result<(), StartError>
+where
+    L: Listener,
+    NH: NewHandler + 'static,
+    F: Future<Output = io::Result<(Wrapped, Option<TlsInfo>)>> + Unpin + Send + 'static,
+    Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+    Wrap: Fn(L::Io) -> F,
+    S: Future<Output = ()>,
+{
+    let http = Arc::new(Http::new(&settings));
+    let gotham_service = Arc::new(
+        GothamService::new(new_handler)
+            .with_max_headers(settings.max_headers)
+            .with_max_body_size(settings.max_body_size),
+    );
+    let idle_timeout = settings.idle_timeout;
+    let handshake_timeout = settings
+        .header_read_timeout
+        .unwrap_or(DEFAULT_HEADER_TIMEOUT);
+    let mut connections = Connections::new();
+
+    tokio::pin!(shutdown);
 
     loop {
-        let (socket, addr) = match listener.accept().await {
-            Ok(ok) => ok,
-            Err(err) => {
-                log::error!("Socket Error: {}", err);
-                continue;
-            }
+        // When the connection limit is reached, stop accepting until a connection closes; the
+        // pending clients wait in the listen backlog.
+        let accept = async {
+            let permit = match limit {
+                Some(ref limit) => Some(limit.clone().acquire_owned().await.unwrap()),
+                None => None,
+            };
+            let accepted = future::poll_fn(|cx| listener.poll_accept(cx)).await;
+            (accepted, permit)
+        };
+
+        let (socket, addr, permit) = tokio::select! {
+            _ = &mut shutdown => break,
+            _ = connections.reap(), if !connections.is_empty() => continue,
+            (accepted, permit) = accept => match accepted {
+                Ok((socket, addr)) => (socket, addr, permit),
+                Err(err) => {
+                    log::error!("Socket Error: {}", err);
+                    continue;
+                }
+            },
         };
 
-        let service = gotham_service.connect(addr);
-        let accepted_protocol = protocol.clone();
+        let gotham_service = gotham_service.clone();
+        let accepted_protocol = http.clone();
+        let connection_errors = settings.connection_errors.clone();
         let wrapper = wrap(socket);
 
-        // NOTE: HTTP protocol errors and handshake errors are ignored here (i.e. so the socket
-        // will be dropped).
-        let task = async move {
-            let socket = wrapper.await?;
+        // Errors on a single connection only ever close that connection; they are reported via
+        // `ConnectionErrors` rather than propagated.
+        connections.spawn(move |watcher| async move {
+            let task = async move {
+                let handshake = async {
+                    let (socket, tls_info) = wrapper
+                        .await
+                        .map_err(|err| ConnectionError::handshake(err, addr.clone()))?;
+
+                    let (socket, protocol) = accepted_protocol
+                        .detect(socket, tls_info.as_ref())
+                        .await
+                        .map_err(|err| ConnectionError::from_io(err, addr.clone()))?;
+
+                    Ok((socket, protocol, tls_info))
+                };
+
+                // Until hyper takes over, nothing else stops a silent client from holding on to
+                // its connection permit.
+                let (socket, protocol, tls_info) =
+                    match tokio::time::timeout(handshake_timeout, handshake).await {
+                        Ok(connected) => connected?,
+                        Err(_) => {
+                            let err = io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "timed out waiting for the client to start the connection",
+                            );
+                            return Err(ConnectionError::from_io(err, addr));
+                        }
+                    };
+
+                let (upgrades, upgrade_tasks) = upgrade::channel();
+                let service = gotham_service.connect(addr.clone(), protocol, tls_info, upgrades);
+
+                accepted_protocol
+                    .serve_connection(
+                        socket,
+                        protocol,
+                        service,
+                        upgrade_tasks,
+                        watcher,
+                        idle_timeout,
+                    )
+                    .await
+                    .map_err(|err| ConnectionError::from_hyper(err, addr))
+            };
+
+            if let Err(err) = task.await {
+                connection_errors.report(err);
+            }
+
+            drop(permit);
+        });
+    }
 
-            accepted_protocol
-                .serve_connection(socket, service)
-                .with_upgrades()
+    drop(listener);
+    connections.drain(settings.drain_timeout).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::http::response::create_empty_response;
+    use crate::server::ConnectionErrorKind;
+    use crate::state::State;
+    use hyper::StatusCode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn silent_clients_release_their_permit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let errors = ConnectionErrors::new();
+        let settings = Settings {
+            connection_errors: errors.clone(),
+            header_read_timeout: Some(Duration::from_millis(100)),
+            ..Settings::default()
+        };
+        let limit = Some(Arc::new(Semaphore::new(1)));
+
+        let new_handler = || {
+            Ok(|state: State| {
+                let res = create_empty_response(&state, StatusCode::OK);
+                (state, res)
+            })
+        };
+        let wrap = without_tls_info(|socket: TcpStream| future::ok::<_, io::Error>(socket));
+        let server = serve(listener, new_handler, wrap, settings, limit, future::pending());
+
+        let client = async {
+            // The only permit goes to a client which never sends anything.
+            let _silent = TcpStream::connect(addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+
+        let response = tokio::select! {
+            _ = server => unreachable!("server stopped"),
+            response = tokio::time::timeout(Duration::from_secs(5), client) => response.unwrap(),
+        };
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(errors.counts().get(ConnectionErrorKind::Timeout), 1);
+    }
+
+    #[tokio::test]
+    async fn websockets_are_served_by_their_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = Settings {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Settings::default()
+        };
+        let limit = Some(Arc::new(Semaphore::new(1)));
+
+        let new_handler = || {
+            Ok(|mut state: State| {
+                let res = match websocket::WebSocketUpgrade::from_state(&mut state) {
+                    Ok(upgrade) => upgrade.on_upgrade(&state, |socket| async move {
+                        let _socket = socket;
+                        future::pending::<()>().await
+                    }),
+                    Err(_) => create_empty_response(&state, StatusCode::OK),
+                };
+                (state, res)
+            })
+        };
+        let wrap = without_tls_info(|socket: TcpStream| future::ok::<_, io::Error>(socket));
+        let server = serve(listener, new_handler, wrap, settings, limit, future::pending());
+
+        let client = async {
+            let mut websocket = TcpStream::connect(addr).await.unwrap();
+            websocket
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\n\
+                      Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut switched = [0u8; 12];
+            websocket.read_exact(&mut switched).await.unwrap();
+            assert_eq!(&switched, b"HTTP/1.1 101");
+
+            // The WebSocket still holds the only permit.
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
                 .await
-                .expect("accepted protocol errors");
+                .unwrap();
+            let mut byte = [0u8; 1];
+            let waiting = tokio::time::timeout(Duration::from_millis(100), client.read(&mut byte));
+            assert!(waiting.await.is_err());
+
+            // Until it's closed for being idle.
+            websocket.read_to_end(&mut Vec::new()).await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
 
-            Result::<_, ()>::Ok(())
+        let response = tokio::select! {
+            _ = server => unreachable!("server stopped"),
+            response = tokio::time::timeout(Duration::from_secs(5), client) => response.unwrap(),
         };
 
-        tokio::spawn(task);
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
     }
 }
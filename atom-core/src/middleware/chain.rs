@@ -1,5 +1,6 @@
 //! Defines the types for connecting multiple middleware into a "chain" when forming a pipeline.
 
+use hyper::Method;
 use log::trace;
 
 use std::panic::RefUnwindSafe;
@@ -19,6 +20,10 @@ pub trait MiddlewareChainBuild: RefUnwindSafe + Sized {
 
     /// Create and return a new `MiddlewareChain` value.
     fn construct(&self) -> anyhow::Result<Self::Instance>;
+
+    /// Returns the method claimed by the first `MiddlewareBuild` in the chain which claims an
+    /// `OPTIONS` request, see `MiddlewareBuild::options_method`.
+    fn options_method(&self, state: &State) -> Option<Method>;
 }
 
 impl<T, U> MiddlewareChainBuild for (T, U)
@@ -38,6 +43,12 @@ where
         let (ref nm, ref tail) = *self;
         Ok((nm.new_middleware()?, tail.construct()?))
     }
+
+    fn options_method(&self, state: &State) -> Option<Method> {
+        let (ref nm, ref tail) = *self;
+        nm.options_method(state)
+            .or_else(|| tail.options_method(state))
+    }
 }
 
 impl MiddlewareChainBuild for () {
@@ -48,6 +59,10 @@ impl MiddlewareChainBuild for () {
         trace!(" completed middleware pipeline construction");
         Ok(())
     }
+
+    fn options_method(&self, _state: &State) -> Option<Method> {
+        None
+    }
 }
 
 /// A recursive type representing an instance of a pipeline, which is used to process a single
@@ -0,0 +1,245 @@
+//! Defines `ClientCertMiddleware`, which authorizes requests by the client certificate presented
+//! during a mutual TLS handshake.
+use std::fmt::{self, Debug, Formatter};
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_util::future::{self, FutureExt};
+use hyper::StatusCode;
+use log::debug;
+
+use crate::handler::HandlerFuture;
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{request_id, FromState, State};
+use crate::tls::{PeerIdentity, TlsInfo};
+
+type Predicate = dyn Fn(&PeerIdentity) -> bool + Send + Sync + RefUnwindSafe;
+
+#[derive(Clone)]
+enum Rule {
+    Subject(String),
+    CommonName(String),
+    DnsName(String),
+    Uri(String),
+    Email(String),
+    Predicate(Arc<Predicate>),
+}
+
+impl Rule {
+    fn matches(&self, identity: &PeerIdentity) -> bool {
+        match self {
+            Rule::Subject(subject) => identity.subject() == subject,
+            Rule::CommonName(cn) => identity.common_name() == Some(cn.as_str()),
+            Rule::DnsName(pattern) => identity
+                .dns_names()
+                .iter()
+                .any(|name| dns_name_matches(pattern, name)),
+            Rule::Uri(uri) => identity.uris().iter().any(|u| u == uri),
+            Rule::Email(email) => identity
+                .emails()
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(email)),
+            Rule::Predicate(predicate) => predicate(identity),
+        }
+    }
+}
+
+impl Debug for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Subject(subject) => f.debug_tuple("Subject").field(subject).finish(),
+            Rule::CommonName(cn) => f.debug_tuple("CommonName").field(cn).finish(),
+            Rule::DnsName(pattern) => f.debug_tuple("DnsName").field(pattern).finish(),
+            Rule::Uri(uri) => f.debug_tuple("Uri").field(uri).finish(),
+            Rule::Email(email) => f.debug_tuple("Email").field(email).finish(),
+            Rule::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+/// Matches a certificate DNS name against `pattern`, where a leading `*.` matches exactly one
+/// label. Comparison is case-insensitive.
+fn dns_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match name.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+            None => false,
+        },
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Restricts access to clients which authenticated with a certificate during the TLS handshake,
+/// and whose certificate matches at least one of the configured rules.
+///
+/// The certificate itself is verified by rustls, which must be configured to request client
+/// certificates, e.g. with `AllowAnyAuthenticatedClient`. This middleware then authorizes the
+/// verified identity found in `TlsInfo`. Requests without a client certificate, including those
+/// received without TLS, or whose certificate matches none of the rules, receive
+/// `403 Forbidden`. Without any rules, every client with a valid certificate is allowed.
+///
+/// ```rust,ignore
+/// let verifier = AllowAnyAuthenticatedClient::new(client_ca_roots);
+/// let tls_config = rustls::ServerConfig::builder()
+///     .with_safe_defaults()
+///     .with_client_cert_verifier(verifier)
+///     .with_single_cert(server_certs, server_key)?;
+///
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(
+///             ClientCertMiddleware::new()
+///                 .allow_uri("spiffe://example.org/billing")
+///                 .allow_dns_name("*.internal.example.org"),
+///         )
+///         .build(),
+/// );
+///
+/// tls::start("0.0.0.0:8443", build_router(chain, pipelines, routes), tls_config)
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClientCertMiddleware {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl ClientCertMiddleware {
+    /// Creates a `ClientCertMiddleware` which allows any client with a valid certificate, until
+    /// rules are added.
+    pub fn new() -> ClientCertMiddleware {
+        ClientCertMiddleware::default()
+    }
+
+    fn allow(mut self, rule: Rule) -> ClientCertMiddleware {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    /// Allows certificates whose subject distinguished name is exactly `subject`, as formatted
+    /// by `PeerIdentity::subject`.
+    pub fn allow_subject<S: Into<String>>(self, subject: S) -> ClientCertMiddleware {
+        self.allow(Rule::Subject(subject.into()))
+    }
+
+    /// Allows certificates whose subject common name is exactly `common_name`.
+    pub fn allow_common_name<S: Into<String>>(self, common_name: S) -> ClientCertMiddleware {
+        self.allow(Rule::CommonName(common_name.into()))
+    }
+
+    /// Allows certificates with a DNS name matching `pattern` in their Subject Alternative Name.
+    /// A leading `*.` matches a single label, so `*.example.org` matches `api.example.org` but
+    /// not `example.org` or `a.b.example.org`.
+    pub fn allow_dns_name<S: Into<String>>(self, pattern: S) -> ClientCertMiddleware {
+        self.allow(Rule::DnsName(pattern.into()))
+    }
+
+    /// Allows certificates with `uri` in their Subject Alternative Name, such as a SPIFFE ID.
+    pub fn allow_uri<S: Into<String>>(self, uri: S) -> ClientCertMiddleware {
+        self.allow(Rule::Uri(uri.into()))
+    }
+
+    /// Allows certificates with the email address `email` in their Subject Alternative Name.
+    pub fn allow_email<S: Into<String>>(self, email: S) -> ClientCertMiddleware {
+        self.allow(Rule::Email(email.into()))
+    }
+
+    /// Allows certificates for which `predicate` returns `true`.
+    pub fn allow_if<F>(self, predicate: F) -> ClientCertMiddleware
+    where
+        F: Fn(&PeerIdentity) -> bool + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.allow(Rule::Predicate(Arc::new(predicate)))
+    }
+
+    /// Returns `true` if `identity` satisfies the configured rules.
+    pub fn is_allowed(&self, identity: &PeerIdentity) -> bool {
+        self.rules.is_empty() || self.rules.iter().any(|rule| rule.matches(identity))
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for ClientCertMiddleware {
+    /// Rejects the request unless the client certificate is allowed.
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        let allowed = match TlsInfo::try_borrow_from(&state).and_then(TlsInfo::peer_identity) {
+            Some(identity) if self.is_allowed(identity) => true,
+            Some(identity) => {
+                debug!(
+                    "[{}] client certificate not allowed: {}",
+                    request_id(&state),
+                    identity.subject()
+                );
+                false
+            }
+            None => {
+                debug!("[{}] no client certificate presented", request_id(&state));
+                false
+            }
+        };
+
+        if allowed {
+            chain(state)
+        } else {
+            let res = create_empty_response(&state, StatusCode::FORBIDDEN);
+            future::ok((state, res)).boxed()
+        }
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for ClientCertMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> PeerIdentity {
+        PeerIdentity::from_der(include_bytes!("../tls/tls_cert.der")).unwrap()
+    }
+
+    #[test]
+    fn matches_dns_patterns() {
+        assert!(dns_name_matches("api.example.org", "API.example.org"));
+        assert!(dns_name_matches("*.example.org", "api.example.org"));
+        assert!(!dns_name_matches("*.example.org", "example.org"));
+        assert!(!dns_name_matches("*.example.org", "a.b.example.org"));
+        assert!(!dns_name_matches("*.example.org", ".example.org"));
+    }
+
+    #[test]
+    fn applies_policy_rules() {
+        let identity = identity();
+
+        assert!(ClientCertMiddleware::new().is_allowed(&identity));
+        assert!(ClientCertMiddleware::new()
+            .allow_common_name("example.org")
+            .is_allowed(&identity));
+        assert!(ClientCertMiddleware::new()
+            .allow_subject("CN=example.org")
+            .is_allowed(&identity));
+        assert!(ClientCertMiddleware::new()
+            .allow_uri("spiffe://example.org/billing")
+            .allow_dns_name("localhost")
+            .is_allowed(&identity));
+        assert!(ClientCertMiddleware::new()
+            .allow_if(|id| id.ip_addresses().iter().any(|ip| ip.is_loopback()))
+            .is_allowed(&identity));
+
+        assert!(!ClientCertMiddleware::new()
+            .allow_common_name("billing")
+            .allow_dns_name("*.example.net")
+            .allow_email("ops@example.org")
+            .is_allowed(&identity));
+    }
+}
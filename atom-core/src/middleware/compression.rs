@@ -0,0 +1,512 @@
+//! Defines `CompressionMiddleware`, which compresses response bodies using the best encoding the
+//! client accepts.
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Poll};
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder, ZstdEncoder};
+use bytes::Bytes;
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use http_body::{Body as HttpBody, Frame};
+use http_body_util::StreamBody;
+use hyper::header::{
+    HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG,
+};
+use hyper::{Method, Response, StatusCode};
+use mime::Mime;
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::body::Body;
+use crate::handler::assets::accepted_encoding::accepted_encodings;
+use crate::handler::HandlerFuture;
+use crate::helpers::http::header::add_vary;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{FromState, State};
+
+pub use async_compression::Level;
+
+/// Bodies smaller than this many bytes are sent uncompressed by default.
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// A content coding used to compress HTTP bodies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// `gzip`.
+    Gzip,
+    /// `deflate`, which is zlib wrapped deflate data.
+    Deflate,
+    /// `br`, Brotli.
+    Brotli,
+    /// `zstd`, Zstandard.
+    Zstd,
+}
+
+impl Encoding {
+    /// The name of the encoding, as used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Parses the name of an encoding, ignoring case. `x-gzip` is accepted as an alias of `gzip`.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        [
+            Encoding::Gzip,
+            Encoding::Deflate,
+            Encoding::Brotli,
+            Encoding::Zstd,
+        ]
+        .into_iter()
+        .find(|encoding| name.eq_ignore_ascii_case(encoding.name()))
+        .or_else(|| {
+            name.eq_ignore_ascii_case("x-gzip")
+                .then_some(Encoding::Gzip)
+        })
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Compresses response bodies on the fly, choosing the encoding from the request's
+/// `Accept-Encoding` header. Bodies are compressed as they are streamed, without buffering the
+/// whole response.
+///
+/// Responses are left as they are when:
+///
+/// - the client accepts none of the enabled encodings;
+/// - the body is known to be smaller than the minimum size, `DEFAULT_MIN_SIZE` by default;
+/// - the response already has a `Content-Encoding`, is a partial response, or its
+///   `Cache-Control` includes `no-transform`;
+/// - the `Content-Type` is missing or not compressible, such as images and archives. Event streams
+///   are also skipped, since compression would delay each event.
+///
+/// Compressible responses always gain `Vary: Accept-Encoding`, so caches store a variant per
+/// encoding. When a response is compressed, its `Content-Length` is removed and a strong `ETag` is
+/// made weak, since the bytes sent no longer match it.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(
+///             CompressionMiddleware::new()
+///                 .encodings([Encoding::Brotli, Encoding::Gzip])
+///                 .min_size(512),
+///         )
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct CompressionMiddleware {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    level: Option<Level>,
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> CompressionMiddleware {
+        CompressionMiddleware {
+            encodings: vec![
+                Encoding::Zstd,
+                Encoding::Brotli,
+                Encoding::Gzip,
+                Encoding::Deflate,
+            ],
+            min_size: DEFAULT_MIN_SIZE,
+            level: None,
+        }
+    }
+}
+
+impl CompressionMiddleware {
+    /// Creates a `CompressionMiddleware` supporting every `Encoding`, preferring `zstd`, then
+    /// `br`, `gzip` and `deflate` when the client accepts several equally.
+    pub fn new() -> CompressionMiddleware {
+        CompressionMiddleware::default()
+    }
+
+    /// Sets the encodings which may be used, in order of preference.
+    pub fn encodings<I>(mut self, encodings: I) -> CompressionMiddleware
+    where
+        I: IntoIterator<Item = Encoding>,
+    {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// Sends bodies known to be smaller than `bytes` uncompressed.
+    pub fn min_size(mut self, bytes: u64) -> CompressionMiddleware {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Sets the compression level used for every encoding.
+    ///
+    /// By default each encoding uses its own default level, except Brotli, which uses quality 4
+    /// because its default is too slow for compressing responses as they are served.
+    pub fn level(mut self, level: Level) -> CompressionMiddleware {
+        self.level = Some(level);
+        self
+    }
+
+    /// Chooses the encoding for a request, from the client's `Accept-Encoding` header.
+    ///
+    /// The encoding with the highest quality value is used, and ties go to the encoding preferred
+    /// by the server. Encodings with a quality of zero are never used, even when `*` is accepted.
+    pub fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let accepted = accepted_encodings(headers);
+        let quality = |encoding: Encoding| {
+            accepted
+                .iter()
+                .find(|accepted| Encoding::from_name(&accepted.encoding) == Some(encoding))
+                .or_else(|| accepted.iter().find(|accepted| accepted.encoding == "*"))
+                .map_or(0.0, |accepted| accepted.quality)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let quality = quality(encoding);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn level_for(&self, encoding: Encoding) -> Level {
+        match (self.level, encoding) {
+            (Some(level), _) => level,
+            (None, Encoding::Brotli) => Level::Precise(4),
+            (None, _) => Level::Default,
+        }
+    }
+
+    fn compress(&self, response: &mut Response<Body>, encoding: Option<Encoding>) {
+        if !is_compressible(response) {
+            return;
+        }
+
+        // Whether or not this response is compressed, others for the same URL may be.
+        add_vary(response.headers_mut(), "accept-encoding");
+
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        if response
+            .body()
+            .size_hint()
+            .upper()
+            .is_some_and(|size| size < self.min_size)
+        {
+            return;
+        }
+
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        headers.remove(CONTENT_LENGTH);
+
+        if let Some(etag) = headers.get(ETAG) {
+            if etag.as_bytes().starts_with(b"\"") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                    headers.insert(ETAG, weak);
+                }
+            }
+        }
+
+        let body = std::mem::take(response.body_mut());
+        *response.body_mut() = encode(body, encoding, self.level_for(encoding));
+    }
+}
+
+/// Streams `body` through the encoder for `encoding`, followed by its trailers, if any.
+fn encode(body: Body, encoding: Encoding, level: Level) -> Body {
+    let trailers = Arc::new(Mutex::new(None));
+    let reader = StreamReader::new(data_frames(body, trailers.clone()));
+
+    fn stream<R>(encoder: R, trailers: Arc<Mutex<Option<HeaderMap>>>) -> Body
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        // The trailers are set aside once the encoder has read the whole body, which happens
+        // before it ends.
+        let trailers = stream::once(async move { trailers.lock().unwrap().take() })
+            .filter_map(|trailers| future::ready(trailers.map(|t| Ok(Frame::trailers(t)))));
+
+        let frames = ReaderStream::new(encoder)
+            .map_ok(Frame::data)
+            .chain(trailers);
+        Body::new(StreamBody::new(frames))
+    }
+
+    match encoding {
+        Encoding::Gzip => stream(GzipEncoder::with_quality(reader, level), trailers),
+        Encoding::Deflate => stream(ZlibEncoder::with_quality(reader, level), trailers),
+        Encoding::Brotli => stream(BrotliEncoder::with_quality(reader, level), trailers),
+        Encoding::Zstd => stream(ZstdEncoder::with_quality(reader, level), trailers),
+    }
+}
+
+/// Returns the data of `body`, setting its trailers aside in `trailers`.
+fn data_frames(
+    mut body: Body,
+    trailers: Arc<Mutex<Option<HeaderMap>>>,
+) -> impl stream::Stream<Item = io::Result<Bytes>> + Send {
+    stream::poll_fn(move |cx| loop {
+        match ready!(Pin::new(&mut body).poll_frame(cx)) {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(data) => return Poll::Ready(Some(Ok(data))),
+                Err(frame) => {
+                    if let Ok(frame) = frame.into_trailers() {
+                        *trailers.lock().unwrap() = Some(frame);
+                    }
+                }
+            },
+            Some(Err(err)) => return Poll::Ready(Some(Err(io::Error::other(err)))),
+            None => return Poll::Ready(None),
+        }
+    })
+}
+
+/// Returns `true` if `response` may be compressed, ignoring its size.
+fn is_compressible(response: &Response<Body>) -> bool {
+    let status = response.status();
+    let headers = response.headers();
+
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+    {
+        return false;
+    }
+
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+
+    !no_transform
+        && headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Mime>().ok())
+            .is_some_and(|mime| is_compressible_type(&mime))
+}
+
+/// Returns `true` for textual media types, which compress well. Images, audio, video, fonts and
+/// archives are usually compressed already.
+fn is_compressible_type(mime: &Mime) -> bool {
+    let subtype = mime.subtype().as_str();
+    let suffix = mime.suffix().map(|suffix| suffix.as_str());
+
+    match mime.type_().as_str() {
+        "text" => subtype != "event-stream",
+        "application" => {
+            matches!(
+                subtype,
+                "json" | "javascript" | "ecmascript" | "xml" | "wasm" | "graphql" | "x-javascript"
+            ) || matches!(suffix, Some("json" | "xml"))
+        }
+        "image" => matches!(subtype, "svg" | "bmp" | "x-icon" | "vnd.microsoft.icon"),
+        "font" => matches!(subtype, "ttf" | "otf"),
+        _ => false,
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for CompressionMiddleware {
+    /// Compresses the response body, if the client accepts a supported encoding.
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        // Responses to HEAD requests have no body, so there's nothing to compress.
+        let encoding = match *Method::borrow_from(&state) {
+            Method::HEAD => None,
+            _ => self.negotiate(state.borrow::<HeaderMap>()),
+        };
+
+        let f = chain(state).and_then(move |(state, mut response)| {
+            self.compress(&mut response, encoding);
+            future::ok((state, response))
+        });
+
+        f.boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for CompressionMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+    use hyper::header::{ACCEPT_ENCODING, VARY};
+    use tokio::io::AsyncReadExt;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn response(content_type: &'static str, body: Body) -> Response<Body> {
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+    }
+
+    async fn read_all<R: AsyncRead + Unpin>(mut reader: R) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        let compression = CompressionMiddleware::new();
+
+        assert_eq!(
+            compression.negotiate(&accept("gzip, deflate, br, zstd")),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(
+            compression.negotiate(&accept("gzip;q=1.0, br;q=0.8")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            compression.negotiate(&accept("*;q=0.5, zstd;q=0")),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            compression.negotiate(&accept("X-GZIP")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(compression.negotiate(&accept("identity")), None);
+        assert_eq!(compression.negotiate(&HeaderMap::new()), None);
+
+        let gzip_only = CompressionMiddleware::new().encodings([Encoding::Gzip]);
+        assert_eq!(gzip_only.negotiate(&accept("br, zstd")), None);
+    }
+
+    #[test]
+    fn skips_unsuitable_responses() {
+        let compression = CompressionMiddleware::new().min_size(16);
+        let large = || Body::from("x".repeat(64));
+
+        let mut small = response("text/plain", Body::from("tiny"));
+        compression.compress(&mut small, Some(Encoding::Gzip));
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(small.headers()[VARY], "accept-encoding");
+
+        let mut image = response("image/png", large());
+        compression.compress(&mut image, Some(Encoding::Gzip));
+        assert!(!image.headers().contains_key(CONTENT_ENCODING));
+        assert!(!image.headers().contains_key(VARY));
+
+        let mut encoded = response("text/plain", large());
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        compression.compress(&mut encoded, Some(Encoding::Gzip));
+        assert_eq!(encoded.headers()[CONTENT_ENCODING], "br");
+
+        let mut events = response("text/event-stream", large());
+        compression.compress(&mut events, Some(Encoding::Gzip));
+        assert!(!events.headers().contains_key(CONTENT_ENCODING));
+
+        let mut no_transform = response("application/json", large());
+        no_transform.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, no-transform"),
+        );
+        compression.compress(&mut no_transform, Some(Encoding::Gzip));
+        assert!(!no_transform.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn compresses_streamed_bodies() {
+        let chunks = vec![Ok::<_, io::Error>("hello ".repeat(200)), Ok("world".into())];
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+
+        let mut res = response("application/problem+json", body);
+        res.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from_static("1205"));
+        res.headers_mut()
+            .insert(ETAG, HeaderValue::from_static("\"v1\""));
+        res.headers_mut()
+            .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+        CompressionMiddleware::new().compress(&mut res, Some(Encoding::Gzip));
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[ETAG], "W/\"v1\"");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(res.headers().get_all(VARY).iter().count(), 1);
+
+        let compressed = StreamReader::new(res.into_body().map_err(io::Error::other));
+        let data = read_all(GzipDecoder::new(compressed)).await;
+        assert_eq!(data, format!("{}world", "hello ".repeat(200)).into_bytes());
+
+        let mut res = response("text/html; charset=utf-8", Body::from("<p>".repeat(500)));
+        CompressionMiddleware::new().compress(&mut res, Some(Encoding::Zstd));
+        let compressed = StreamReader::new(res.into_body().map_err(io::Error::other));
+        assert_eq!(
+            read_all(ZstdDecoder::new(compressed)).await,
+            "<p>".repeat(500).into_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_trailers() {
+        let (mut sender, body) = Body::channel(4);
+        tokio::spawn(async move {
+            sender.send_data("checked ".repeat(200)).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+
+        let mut res = response("text/plain", body);
+        CompressionMiddleware::new().compress(&mut res, Some(Encoding::Gzip));
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+
+        let collected = http_body_util::BodyExt::collect(res.into_body())
+            .await
+            .unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc");
+
+        let data = read_all(GzipDecoder::new(&collected.to_bytes()[..])).await;
+        assert_eq!(data, "checked ".repeat(200).into_bytes());
+    }
+}
@@ -0,0 +1,461 @@
+//! Defines `ConcurrencyLimitMiddleware`, which caps the number of requests handled at once.
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use futures_util::future::FutureExt;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::StatusCode;
+use log::{debug, trace};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::handler::HandlerFuture;
+use crate::helpers::http::response::create_empty_response;
+use crate::helpers::timing::Timer;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{request_id, State};
+
+/// Shrinks the limit by this factor when a request is slower than the target latency.
+const BACKOFF_RATIO: f64 = 0.9;
+
+/// Adjusts the limit from the latency of requests, increasing it by one for each request faster
+/// than `target` which ran while at least half the limit was in use, and decreasing it by
+/// `BACKOFF_RATIO` for a slower one. The limit shrinks at most once per `target`, since the
+/// requests which were slow along with the first one don't reflect the reduced limit yet.
+#[derive(Clone, Copy, Debug)]
+struct Adaptive {
+    min: usize,
+    max: usize,
+    target: Duration,
+}
+
+#[derive(Debug)]
+struct Counts {
+    in_flight: usize,
+    queued: usize,
+    limit: f64,
+    backed_off_at: Option<Instant>,
+}
+
+impl Counts {
+    fn has_capacity(&self) -> bool {
+        (self.in_flight as f64) < self.limit.floor()
+    }
+}
+
+#[derive(Debug)]
+struct Limiter {
+    counts: Mutex<Counts>,
+    notify: Notify,
+    adaptive: Option<Adaptive>,
+}
+
+impl Limiter {
+    fn counts(&self) -> MutexGuard<'_, Counts> {
+        self.counts
+            .lock()
+            .expect("concurrency limit lock poisoned, arithmetic panicked?")
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let counts = self.counts();
+        self.take_permit(counts)
+    }
+
+    /// Takes a permit without waiting, unless requests are queued, which go first.
+    fn try_acquire_unqueued(self: &Arc<Self>) -> Option<Permit> {
+        let counts = self.counts();
+        if counts.queued > 0 {
+            return None;
+        }
+        self.take_permit(counts)
+    }
+
+    fn take_permit(self: &Arc<Self>, mut counts: MutexGuard<'_, Counts>) -> Option<Permit> {
+        if !counts.has_capacity() {
+            return None;
+        }
+
+        counts.in_flight += 1;
+        Some(Permit {
+            limiter: self.clone(),
+            timer: Timer::new(),
+        })
+    }
+
+    /// Waits in the queue for a permit, unless the queue holds `max_queued` requests already.
+    async fn acquire(self: &Arc<Self>, max_queued: usize, timeout: Duration) -> Option<Permit> {
+        {
+            let mut counts = self.counts();
+            if counts.queued >= max_queued {
+                return None;
+            }
+            counts.queued += 1;
+        }
+
+        // Leaves the queue even if the request is cancelled while waiting.
+        let _queued = Queued(self);
+
+        let wait = async {
+            loop {
+                let notified = self.notify.notified();
+                futures_util::pin_mut!(notified);
+
+                // Register before checking, so a permit released in between isn't missed.
+                notified.as_mut().enable();
+                if let Some(permit) = self.try_acquire() {
+                    return permit;
+                }
+                notified.await;
+            }
+        };
+
+        let permit = tokio::time::timeout(timeout, wait).await.ok();
+        if permit.is_none() && self.counts().has_capacity() {
+            // Pass on a wakeup which may have been meant for this request.
+            self.notify.notify_one();
+        }
+        permit
+    }
+
+    fn release(&self, latency: Duration) {
+        let mut counts = self.counts();
+        let in_flight = counts.in_flight;
+        counts.in_flight -= 1;
+
+        if let Some(adaptive) = self.adaptive {
+            let limit = counts.limit;
+            counts.limit = if latency > adaptive.target {
+                let now = Instant::now();
+                let backed_off = counts
+                    .backed_off_at
+                    .is_some_and(|at| now.duration_since(at) < adaptive.target);
+                if backed_off {
+                    limit
+                } else {
+                    counts.backed_off_at = Some(now);
+                    (limit * BACKOFF_RATIO).max(adaptive.min as f64)
+                }
+            } else if in_flight as f64 * 2.0 >= limit {
+                (limit + 1.0).min(adaptive.max as f64)
+            } else {
+                limit
+            };
+
+            if counts.limit.floor() != limit.floor() {
+                trace!(" concurrency limit changed to {}", counts.limit.floor());
+            }
+            if counts.limit.floor() > limit.floor() {
+                self.notify.notify_one();
+            }
+        }
+
+        drop(counts);
+        self.notify.notify_one();
+    }
+}
+
+/// Allows a request to run, until dropped along with its response future.
+struct Permit {
+    limiter: Arc<Limiter>,
+    timer: Timer,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(self.timer.elapsed().duration());
+    }
+}
+
+struct Queued<'a>(&'a Limiter);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.counts().queued -= 1;
+    }
+}
+
+/// Caps the number of requests handled at once, shedding excess load with
+/// `503 Service Unavailable` and a `Retry-After` header.
+///
+/// Requests over the limit are shed immediately by default. With `queue`, a bounded number of them
+/// may instead wait for a slot, for up to a timeout, and new requests queue behind them. A request holds its slot until its response
+/// is produced, or until it's cancelled.
+///
+/// `ConcurrencyLimitMiddleware::adaptive` adjusts the limit between a minimum and maximum from the
+/// latency of requests: the limit shrinks while requests are slower than a target latency, and
+/// grows again while they're faster and the limit is in use.
+///
+/// Each `ConcurrencyLimitMiddleware` counts requests on its own, and clones share the count.
+/// Adding separate instances to the pipelines of different route groups limits each group on its
+/// own, while adding one instance to several pipelines gives them a common limit.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(ConcurrencyLimitMiddleware::new(64).queue(128, Duration::from_secs(2)))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitMiddleware {
+    limiter: Arc<Limiter>,
+    max_queued: usize,
+    queue_timeout: Duration,
+    retry_after: Duration,
+}
+
+impl ConcurrencyLimitMiddleware {
+    fn with_limit(limit: usize, adaptive: Option<Adaptive>) -> ConcurrencyLimitMiddleware {
+        assert!(
+            limit > 0,
+            "concurrency limits must allow at least one request"
+        );
+
+        ConcurrencyLimitMiddleware {
+            limiter: Arc::new(Limiter {
+                counts: Mutex::new(Counts {
+                    in_flight: 0,
+                    queued: 0,
+                    limit: limit as f64,
+                    backed_off_at: None,
+                }),
+                notify: Notify::new(),
+                adaptive,
+            }),
+            max_queued: 0,
+            queue_timeout: Duration::ZERO,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+
+    /// Creates a `ConcurrencyLimitMiddleware` handling up to `limit` requests at once.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` is zero.
+    pub fn new(limit: usize) -> ConcurrencyLimitMiddleware {
+        ConcurrencyLimitMiddleware::with_limit(limit, None)
+    }
+
+    /// Creates a `ConcurrencyLimitMiddleware` handling up to `max` requests at once, and down to
+    /// `min` while requests take longer than `target_latency`.
+    ///
+    /// # Panics
+    ///
+    /// If `min` is zero or greater than `max`.
+    pub fn adaptive(
+        min: usize,
+        max: usize,
+        target_latency: Duration,
+    ) -> ConcurrencyLimitMiddleware {
+        assert!(
+            min > 0,
+            "concurrency limits must allow at least one request"
+        );
+        assert!(min <= max, "minimum concurrency limit exceeds the maximum");
+        let adaptive = Adaptive {
+            min,
+            max,
+            target: target_latency,
+        };
+        ConcurrencyLimitMiddleware::with_limit(max, Some(adaptive))
+    }
+
+    /// Lets up to `max_queued` requests over the limit wait for up to `timeout` for a slot,
+    /// rather than shedding them immediately.
+    pub fn queue(mut self, max_queued: usize, timeout: Duration) -> ConcurrencyLimitMiddleware {
+        self.max_queued = max_queued;
+        self.queue_timeout = timeout;
+        self
+    }
+
+    /// Sets the `Retry-After` delay sent with shed requests, which is one second by default.
+    pub fn retry_after(mut self, delay: Duration) -> ConcurrencyLimitMiddleware {
+        self.retry_after = delay;
+        self
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for ConcurrencyLimitMiddleware {
+    /// Runs the chain once a slot is free, or sheds the request.
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        if let Some(permit) = self.limiter.try_acquire_unqueued() {
+            return chain(state)
+                .map(move |result| {
+                    drop(permit);
+                    result
+                })
+                .boxed();
+        }
+
+        async move {
+            let permit = self
+                .limiter
+                .acquire(self.max_queued, self.queue_timeout)
+                .await;
+
+            match permit {
+                Some(permit) => {
+                    let result = chain(state).await;
+                    drop(permit);
+                    result
+                }
+                None => {
+                    debug!(
+                        "[{}] concurrency limit reached, shedding",
+                        request_id(&state)
+                    );
+                    let mut res = create_empty_response(&state, StatusCode::SERVICE_UNAVAILABLE);
+                    let secs =
+                        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(secs));
+                    Ok((state, res))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for ConcurrencyLimitMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use hyper::{Request, Response};
+    use std::net::SocketAddr;
+    use tokio::sync::oneshot;
+
+    fn new_state() -> State {
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    /// Starts a request which holds its slot until the returned sender is used or dropped.
+    fn hold(limit: &ConcurrencyLimitMiddleware) -> (oneshot::Sender<()>, Pin<Box<HandlerFuture>>) {
+        let (tx, rx) = oneshot::channel::<()>();
+        let f = limit.clone().call(new_state(), move |state| {
+            async move {
+                let _ = rx.await;
+                Ok((state, Response::new(Body::empty())))
+            }
+            .boxed()
+        });
+        (tx, f)
+    }
+
+    async fn status(limit: &ConcurrencyLimitMiddleware) -> (StatusCode, Option<HeaderValue>) {
+        let (tx, f) = hold(limit);
+        drop(tx);
+        let (_, res) = f.await.ok().unwrap();
+        (res.status(), res.headers().get(RETRY_AFTER).cloned())
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_over_the_limit() {
+        let limit = ConcurrencyLimitMiddleware::new(1).retry_after(Duration::from_millis(2500));
+
+        let (tx, held) = hold(&limit);
+        assert_eq!(
+            status(&limit).await,
+            (StatusCode::SERVICE_UNAVAILABLE, Some(HeaderValue::from(3)))
+        );
+
+        tx.send(()).unwrap();
+        held.await.ok().unwrap();
+        assert_eq!(status(&limit).await, (StatusCode::OK, None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queues_requests_over_the_limit() {
+        let limit = ConcurrencyLimitMiddleware::new(1).queue(1, Duration::from_secs(5));
+
+        let (tx, held) = hold(&limit);
+        let queued = {
+            let limit = limit.clone();
+            tokio::spawn(async move { status(&limit).await.0 })
+        };
+        tokio::task::yield_now().await;
+
+        // The queue is full, so this is shed without waiting.
+        assert_eq!(status(&limit).await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        tx.send(()).unwrap();
+        held.await.ok().unwrap();
+        assert_eq!(queued.await.unwrap(), StatusCode::OK);
+
+        // Waiting requests give up after the timeout.
+        let (_tx, _held) = hold(&limit);
+        assert_eq!(status(&limit).await.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(limit.limiter.counts().queued, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_queued_requests_first() {
+        let limit = ConcurrencyLimitMiddleware::new(1).queue(2, Duration::from_secs(5));
+
+        let (tx, held) = hold(&limit);
+        let queued = {
+            let limit = limit.clone();
+            tokio::spawn(async move { status(&limit).await.0 })
+        };
+        tokio::task::yield_now().await;
+
+        // The freed slot goes to the queued request rather than a newer one.
+        tx.send(()).unwrap();
+        held.await.ok().unwrap();
+        let (tx, newer) = hold(&limit);
+        assert_eq!(queued.await.unwrap(), StatusCode::OK);
+
+        tx.send(()).unwrap();
+        let (_, res) = newer.await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adapts_to_latency() {
+        let target = Duration::from_millis(100);
+        let limit = ConcurrencyLimitMiddleware::adaptive(2, 10, target);
+        let limiter = &limit.limiter;
+        let current = || limiter.counts().limit.floor() as usize;
+
+        // Requests which are slow together only shrink the limit once.
+        for _ in 0..5 {
+            limiter.counts().in_flight += 1;
+            limiter.release(Duration::from_secs(1));
+        }
+        assert_eq!(current(), 9);
+
+        for _ in 0..20 {
+            tokio::time::advance(target).await;
+            limiter.counts().in_flight += 1;
+            limiter.release(Duration::from_secs(1));
+        }
+        assert_eq!(current(), 2);
+
+        // Fast requests only raise the limit while it's in use.
+        limiter.counts().in_flight += 1;
+        limiter.release(Duration::from_millis(10));
+        assert_eq!(current(), 3);
+
+        limiter.counts().in_flight += 1;
+        limiter.release(Duration::from_millis(10));
+        assert_eq!(current(), 3);
+    }
+}
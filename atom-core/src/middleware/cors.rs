@@ -0,0 +1,521 @@
+//! Defines `CorsMiddleware`, which implements Cross-Origin Resource Sharing, and answers CORS
+//! preflight requests for the routes it's used with.
+use std::fmt::{self, Debug, Formatter};
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+};
+use hyper::{Method, Response, StatusCode};
+use regex::Regex;
+
+use crate::body::Body;
+use crate::handler::HandlerFuture;
+use crate::helpers::http::header::add_vary;
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::router::OptionsFallback;
+use crate::state::State;
+
+#[derive(Clone)]
+enum OriginRule {
+    Any,
+    Exact(String),
+    Regex(Regex),
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync + RefUnwindSafe>),
+}
+
+impl OriginRule {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Any => true,
+            OriginRule::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            OriginRule::Regex(regex) => regex.is_match(origin),
+            OriginRule::Predicate(predicate) => predicate(origin),
+        }
+    }
+}
+
+impl Debug for OriginRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginRule::Any => f.write_str("Any"),
+            OriginRule::Exact(origin) => f.debug_tuple("Exact").field(origin).finish(),
+            OriginRule::Regex(regex) => f.debug_tuple("Regex").field(&regex.as_str()).finish(),
+            OriginRule::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+/// Implements Cross-Origin Resource Sharing for the routes whose pipelines include it.
+///
+/// Responses to requests from allowed origins gain `Access-Control-Allow-Origin`, along with
+/// `Access-Control-Allow-Credentials` and `Access-Control-Expose-Headers` when configured. Unless
+/// any origin is allowed without credentials, every response also gains `Vary: Origin`, since
+/// it depends on the requesting origin.
+///
+/// CORS preflight requests are answered automatically: this middleware claims `OPTIONS` requests
+/// carrying `Origin` and `Access-Control-Request-Method` which no route accepts, so the `Router`
+/// dispatches them to the route which would handle the announced request, and answers them with
+/// `204 No Content` before the rest of the pipeline runs, see `MiddlewareBuild::options_method`.
+/// Routes without this middleware answer such requests with `405 Method Not Allowed`. The allowed
+/// methods default to those registered for the path, and may be narrowed with `allow_methods`.
+/// Preflights which aren't allowed are answered without any CORS headers, so the browser won't send
+/// the request. Routes registered for `OPTIONS`, such as those using
+/// `AccessControlRequestMethodMatcher`, still handle their preflights themselves.
+///
+/// No origins are allowed by default.
+///
+/// ```rust,ignore
+/// let cors = CorsMiddleware::new()
+///     .allow_origin("https://example.com")
+///     .allow_origin_wildcard("https://*.example.com")
+///     .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+///     .allow_credentials(true)
+///     .max_age(Duration::from_secs(600));
+///
+/// let (chain, pipelines) = single_pipeline(new_pipeline().add(cors).build());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CorsMiddleware {
+    origins: Arc<Vec<OriginRule>>,
+    methods: Option<Vec<Method>>,
+    headers: Vec<HeaderName>,
+    any_header: bool,
+    exposed_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl CorsMiddleware {
+    /// Creates a `CorsMiddleware` which allows no origins.
+    pub fn new() -> CorsMiddleware {
+        CorsMiddleware::default()
+    }
+
+    fn origin_rule(mut self, rule: OriginRule) -> CorsMiddleware {
+        Arc::make_mut(&mut self.origins).push(rule);
+        self
+    }
+
+    /// Allows requests from any origin. Unless credentials are allowed, responses then use
+    /// `Access-Control-Allow-Origin: *`.
+    pub fn allow_any_origin(self) -> CorsMiddleware {
+        self.origin_rule(OriginRule::Any)
+    }
+
+    /// Allows requests from `origin`, such as `https://example.com`, ignoring case.
+    pub fn allow_origin(self, origin: &str) -> CorsMiddleware {
+        self.origin_rule(OriginRule::Exact(origin.to_owned()))
+    }
+
+    /// Allows requests from origins matching `pattern`, in which `*` stands for one or more
+    /// characters other than `/`, such as `https://*.example.com`.
+    pub fn allow_origin_wildcard(self, pattern: &str) -> CorsMiddleware {
+        let pattern = pattern
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join("[^/]+");
+        let regex = Regex::new(&format!("(?i)^{}$", pattern)).expect("escaped origin pattern");
+        self.origin_rule(OriginRule::Regex(regex))
+    }
+
+    /// Allows requests from origins matching `regex`. The regex should be anchored, since it may
+    /// match anywhere in the origin otherwise.
+    pub fn allow_origin_regex(self, regex: Regex) -> CorsMiddleware {
+        self.origin_rule(OriginRule::Regex(regex))
+    }
+
+    /// Allows requests from origins for which `predicate` returns `true`.
+    pub fn allow_origin_fn<F>(self, predicate: F) -> CorsMiddleware
+    where
+        F: Fn(&str) -> bool + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.origin_rule(OriginRule::Predicate(Arc::new(predicate)))
+    }
+
+    /// Sets the methods preflights may allow. Only those also registered for the requested path
+    /// are allowed.
+    pub fn allow_methods<I>(mut self, methods: I) -> CorsMiddleware
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// Sets the request headers preflights may allow, besides the CORS-safelisted ones.
+    pub fn allow_headers<I>(mut self, headers: I) -> CorsMiddleware
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Allows preflights to request any headers.
+    pub fn allow_any_header(mut self) -> CorsMiddleware {
+        self.any_header = true;
+        self
+    }
+
+    /// Sets the response headers, besides the CORS-safelisted ones, which scripts may read.
+    pub fn expose_headers<I>(mut self, headers: I) -> CorsMiddleware
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.exposed_headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Sets whether requests may include credentials, such as cookies.
+    pub fn allow_credentials(mut self, credentials: bool) -> CorsMiddleware {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Sets how long browsers may cache the answer to a preflight.
+    pub fn max_age(mut self, max_age: Duration) -> CorsMiddleware {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Returns `false` if every origin gets the same `Access-Control-Allow-Origin: *`.
+    fn varies_by_origin(&self) -> bool {
+        self.credentials
+            || !self
+                .origins
+                .iter()
+                .any(|rule| matches!(rule, OriginRule::Any))
+    }
+
+    /// Returns the `Access-Control-Allow-Origin` value for `origin`, if it's allowed.
+    fn allow_origin_value(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if !self.varies_by_origin() {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let origin_str = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|rule| rule.matches(origin_str))
+            .then(|| origin.clone())
+    }
+
+    /// Adds the CORS headers for an actual, non-preflight, request to `headers`.
+    fn add_headers(&self, headers: &mut HeaderMap, origin: Option<&HeaderValue>) {
+        if self.varies_by_origin() {
+            add_vary(headers, "origin");
+        }
+
+        let allow_origin = match origin.and_then(|origin| self.allow_origin_value(origin)) {
+            Some(allow_origin) => allow_origin,
+            None => return,
+        };
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(exposed) = join(&self.exposed_headers, HeaderName::as_str) {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+    }
+
+    /// Answers `preflight`, adding the CORS headers only if the announced request is allowed.
+    fn answer_preflight(&self, state: &State, preflight: &OptionsFallback) -> Response<Body> {
+        let mut res = create_empty_response(state, StatusCode::NO_CONTENT);
+        let headers = res.headers_mut();
+
+        if self.varies_by_origin() {
+            add_vary(headers, "origin");
+        }
+        add_vary(headers, "access-control-request-method");
+        add_vary(headers, "access-control-request-headers");
+
+        let request_headers = state.borrow::<HeaderMap>();
+        let allow_origin = match request_headers
+            .get(ORIGIN)
+            .and_then(|origin| self.allow_origin_value(origin))
+        {
+            Some(allow_origin) => allow_origin,
+            None => return res,
+        };
+
+        let methods: Vec<&Method> = preflight
+            .allowed_methods()
+            .iter()
+            .filter(|method| {
+                self.methods
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.contains(method))
+            })
+            .collect();
+        if !methods.contains(&preflight.method()) {
+            return res;
+        }
+
+        let requested: Vec<&str> = request_headers
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let headers_allowed = self.any_header
+            || requested.iter().all(|name| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+            });
+        if !headers_allowed {
+            return res;
+        }
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(methods) = join(&methods, |method| method.as_str()) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        let allow_headers = if self.any_header {
+            join(&requested, |name| name)
+        } else {
+            join(&self.headers, HeaderName::as_str)
+        };
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+
+        res
+    }
+}
+
+/// Joins `items` into a comma separated header value, or returns `None` if there are none.
+fn join<T>(items: &[T], name: impl Fn(&T) -> &str) -> Option<HeaderValue> {
+    if items.is_empty() {
+        return None;
+    }
+
+    let joined = items.iter().map(name).collect::<Vec<_>>().join(", ");
+    HeaderValue::from_str(&joined).ok()
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for CorsMiddleware {
+    /// Answers CORS preflights dispatched to this route, and adds CORS headers to other responses.
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        if let Some(preflight) = state.try_take::<OptionsFallback>() {
+            let res = self.answer_preflight(&state, &preflight);
+            return future::ok((state, res)).boxed();
+        }
+
+        let origin = state.borrow::<HeaderMap>().get(ORIGIN).cloned();
+        let f = chain(state).and_then(move |(state, mut response)| {
+            self.add_headers(response.headers_mut(), origin.as_ref());
+            future::ok((state, response))
+        });
+
+        f.boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for CorsMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+
+    /// Claims CORS preflights for the method they announce.
+    fn options_method(&self, state: &State) -> Option<Method> {
+        let headers = state.borrow::<HeaderMap>();
+        if !headers.contains_key(ORIGIN) {
+            return None;
+        }
+
+        // Methods are byte-uppercased, as in `AccessControlRequestMethodMatcher`.
+        headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.to_ascii_uppercase().parse::<Method>().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, VARY};
+    use hyper::Request;
+    use std::net::SocketAddr;
+
+    fn preflight_state(origin: &str, method: &str, headers: &str) -> State {
+        let req = Request::options("/things")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, headers)
+            .body(Body::empty())
+            .unwrap();
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    fn preflight(method: Method) -> OptionsFallback {
+        OptionsFallback::new(method, vec![Method::GET, Method::POST, Method::DELETE])
+    }
+
+    #[test]
+    fn matches_origins() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://Example.com")
+            .allow_origin_wildcard("https://*.example.org")
+            .allow_origin_regex(Regex::new(r"^http://localhost:\d+$").unwrap())
+            .allow_origin_fn(|origin| origin.ends_with(".internal"));
+        let allowed = |origin: &'static str| {
+            cors.allow_origin_value(&HeaderValue::from_static(origin))
+                .is_some()
+        };
+
+        assert!(allowed("https://example.com"));
+        assert!(allowed("https://api.example.org"));
+        assert!(allowed("https://a.b.example.org"));
+        assert!(allowed("http://localhost:8080"));
+        assert!(allowed("http://tools.internal"));
+
+        assert!(!allowed("https://example.org"));
+        assert!(!allowed("https://evil.com/.example.org"));
+        assert!(!allowed("https://example.com.evil.com"));
+        assert!(!allowed("null"));
+    }
+
+    #[test]
+    fn adds_headers_to_responses() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://example.com")
+            .expose_headers([ETAG])
+            .allow_credentials(true);
+        let origin = HeaderValue::from_static("https://example.com");
+
+        let mut headers = HeaderMap::new();
+        cors.add_headers(&mut headers, Some(&origin));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], "etag");
+        assert_eq!(headers[VARY], "origin");
+
+        // Responses to other origins, or same origin requests, still vary by origin.
+        let mut headers = HeaderMap::new();
+        cors.add_headers(
+            &mut headers,
+            Some(&HeaderValue::from_static("https://evil.com")),
+        );
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(headers[VARY], "origin");
+
+        let mut headers = HeaderMap::new();
+        cors.add_headers(&mut headers, None);
+        assert_eq!(headers[VARY], "origin");
+
+        let any = CorsMiddleware::new().allow_any_origin();
+        let mut headers = HeaderMap::new();
+        any.add_headers(&mut headers, Some(&origin));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(VARY));
+
+        // Credentials can't be combined with `*`, so the origin is echoed instead.
+        let mut headers = HeaderMap::new();
+        any.allow_credentials(true)
+            .add_headers(&mut headers, Some(&origin));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[VARY], "origin");
+    }
+
+    #[test]
+    fn answers_preflights() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://example.com")
+            .allow_methods([Method::GET, Method::POST, Method::PUT])
+            .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+            .max_age(Duration::from_secs(600));
+
+        let state = preflight_state("https://example.com", "POST", "Content-Type, authorization");
+        let res = cors.answer_preflight(&state, &preflight(Method::POST));
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        // PUT isn't registered for the path, and DELETE isn't allowed.
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, authorization"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(
+            headers
+                .get_all(VARY)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            [
+                "origin",
+                "access-control-request-method",
+                "access-control-request-headers"
+            ]
+        );
+
+        let rejected = [
+            (
+                preflight_state("https://evil.com", "POST", "content-type"),
+                Method::POST,
+            ),
+            (
+                preflight_state("https://example.com", "DELETE", ""),
+                Method::DELETE,
+            ),
+            (
+                preflight_state("https://example.com", "POST", "x-secret"),
+                Method::POST,
+            ),
+        ];
+        for (state, method) in rejected {
+            let res = cors.answer_preflight(&state, &preflight(method));
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+
+        let any_header = CorsMiddleware::new().allow_any_origin().allow_any_header();
+        let state = preflight_state("https://a.com", "DELETE", "x-secret");
+        let res = any_header.answer_preflight(&state, &preflight(Method::DELETE));
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, POST, DELETE"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-secret");
+    }
+}
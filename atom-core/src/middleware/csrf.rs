@@ -0,0 +1,631 @@
+//! Defines `CsrfMiddleware`, which protects against cross-site request forgery.
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::prelude::*;
+use bytes::BytesMut;
+use cookie::{Cookie, CookieJar, SameSite};
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use http_body::Body as HttpBody;
+use http_body_util::BodyExt;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, HOST, ORIGIN, REFERER, SET_COOKIE,
+};
+use hyper::{Method, StatusCode, Uri};
+use log::debug;
+use mime::Mime;
+use rand::RngCore;
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+
+use crate::body::Body;
+use crate::handler::HandlerFuture;
+use crate::helpers::http::request::query_string;
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::cookie::CookieParser;
+use crate::middleware::session::SessionIdentifier;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{real_client, request_id, FromState, State};
+use crate::tls::TlsInfo;
+
+/// The request header carrying the token, unless configured otherwise.
+pub const DEFAULT_HEADER: &str = "x-csrf-token";
+
+/// The form field carrying the token, unless configured otherwise.
+pub const DEFAULT_FORM_FIELD: &str = "csrf_token";
+
+/// The cookie holding the token in double-submit mode, unless configured otherwise.
+pub const DEFAULT_COOKIE_NAME: &str = "csrf_token";
+
+/// The largest form body read to find the token, unless configured otherwise.
+pub const DEFAULT_MAX_FORM_SIZE: usize = 64 * 1024;
+
+/// The CSRF token for the current request, which forms and scripts must send back with unsafe
+/// requests. `CsrfMiddleware` stores it in `State`:
+///
+/// ```rust,ignore
+/// let token = CsrfToken::borrow_from(&state).as_str();
+/// let form = format!(r#"<input type="hidden" name="csrf_token" value="{}">"#, token);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    /// The token, which only uses URL safe characters.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Mode {
+    /// Tokens are an HMAC of the session identifier.
+    Session(hmac::Key),
+    /// Tokens are random, and stored in a cookie.
+    Cookie,
+}
+
+/// Rejects unsafe requests, those not using `GET`, `HEAD`, `OPTIONS` or `TRACE`, which don't
+/// carry the CSRF token of the client, with `403 Forbidden`.
+///
+/// The token is stored in `State` as `CsrfToken`, to be included in forms or read by scripts.
+/// It's sent back in a request header, `x-csrf-token` by default, or for
+/// `application/x-www-form-urlencoded` bodies, in a form field, `csrf_token` by default.
+/// Forms larger than `max_form_size`, 64 KiB by default, are rejected with
+/// `413 Payload Too Large` unless they send the header. Multipart forms must use the header.
+///
+/// Tokens are issued in one of two ways:
+///
+/// - `CsrfMiddleware::session` ties the token to the session, using an HMAC of the session
+///   identifier, so `NewSessionMiddleware` must run earlier in the pipeline. A new session gets a
+///   new token.
+/// - `CsrfMiddleware::double_submit_cookie` stores a random token in a cookie, which scripts may
+///   read, and checks that requests send the same token again.
+///
+/// As a second layer, unsafe requests are also rejected when their `Origin`, or `Referer` if
+/// there's no `Origin`, names another origin than the server's own or a trusted one. The server's
+/// origin is taken from `RealClient` when `ForwardedMiddleware` runs earlier, or from the `Host`
+/// header and connection otherwise.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(NewSessionMiddleware::default().with_session_type::<AdminSession>())
+///         .add(CsrfMiddleware::session().trust_origin("https://admin.example.com"))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct CsrfMiddleware {
+    mode: Mode,
+    header: HeaderName,
+    form_field: String,
+    max_form_size: usize,
+    cookie_name: String,
+    secure_cookie: bool,
+    trusted_origins: Arc<Vec<String>>,
+}
+
+impl CsrfMiddleware {
+    fn with_mode(mode: Mode) -> CsrfMiddleware {
+        CsrfMiddleware {
+            mode,
+            header: HeaderName::from_static(DEFAULT_HEADER),
+            form_field: DEFAULT_FORM_FIELD.to_owned(),
+            max_form_size: DEFAULT_MAX_FORM_SIZE,
+            cookie_name: DEFAULT_COOKIE_NAME.to_owned(),
+            secure_cookie: true,
+            trusted_origins: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Creates a `CsrfMiddleware` deriving tokens from the session identifier, using a random
+    /// key. Tokens are then only valid for this process; use `session_with_key` when several
+    /// servers share sessions, or when sessions outlive the process.
+    pub fn session() -> CsrfMiddleware {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        CsrfMiddleware::session_with_key(&key)
+    }
+
+    /// Creates a `CsrfMiddleware` deriving tokens from the session identifier, using `key`, which
+    /// should be at least 32 random bytes and kept secret.
+    pub fn session_with_key(key: &[u8]) -> CsrfMiddleware {
+        CsrfMiddleware::with_mode(Mode::Session(hmac::Key::new(hmac::HMAC_SHA256, key)))
+    }
+
+    /// Creates a `CsrfMiddleware` storing a random token in a cookie.
+    pub fn double_submit_cookie() -> CsrfMiddleware {
+        CsrfMiddleware::with_mode(Mode::Cookie)
+    }
+
+    /// Sets the request header carrying the token.
+    pub fn header_name(mut self, header: HeaderName) -> CsrfMiddleware {
+        self.header = header;
+        self
+    }
+
+    /// Sets the form field carrying the token.
+    pub fn form_field(mut self, field: &str) -> CsrfMiddleware {
+        self.form_field = field.to_owned();
+        self
+    }
+
+    /// Sets the largest form body read to find the token, 64 KiB by default. Larger forms are
+    /// rejected with `413 Payload Too Large`, unless they send the token in the header.
+    pub fn max_form_size(mut self, size: usize) -> CsrfMiddleware {
+        self.max_form_size = size;
+        self
+    }
+
+    /// Sets the name of the cookie holding the token, in double-submit mode.
+    pub fn cookie_name(mut self, name: &str) -> CsrfMiddleware {
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    /// Sends the cookie holding the token without the `Secure` flag, for plaintext HTTP servers.
+    pub fn insecure(mut self) -> CsrfMiddleware {
+        self.secure_cookie = false;
+        self
+    }
+
+    /// Trusts unsafe requests from `origin`, such as `https://admin.example.com`, besides those
+    /// from the server's own origin.
+    ///
+    /// # Panics
+    ///
+    /// If `origin` isn't an absolute URI.
+    pub fn trust_origin(mut self, origin: &str) -> CsrfMiddleware {
+        let origin = origin
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| origin_of(&uri))
+            .expect("trusted origins must be absolute URIs");
+        Arc::make_mut(&mut self.trusted_origins).push(origin);
+        self
+    }
+
+    /// Returns the token the client should send, if it has one.
+    fn expected_token(&self, state: &State) -> Option<String> {
+        match self.mode {
+            Mode::Session(ref key) => {
+                let identifier = SessionIdentifier::try_borrow_from(state)?;
+                let tag = hmac::sign(key, identifier.value.as_bytes());
+                Some(BASE64_URL_SAFE_NO_PAD.encode(tag.as_ref()))
+            }
+            Mode::Cookie => {
+                // cookies might have been parsed already by middleware
+                let cookies = CookieJar::try_borrow_from(state)
+                    .cloned()
+                    .unwrap_or_else(|| CookieParser::from_state(state));
+                cookies
+                    .get(&self.cookie_name)
+                    .map(Cookie::value)
+                    .filter(|value| is_token(value))
+                    .map(str::to_owned)
+            }
+        }
+    }
+
+    fn token_cookie(&self, token: &str) -> HeaderValue {
+        let cookie = Cookie::build(self.cookie_name.as_str(), token)
+            .path("/")
+            .same_site(SameSite::Strict)
+            .secure(self.secure_cookie)
+            .finish();
+        HeaderValue::from_str(&cookie.to_string()).expect("valid cookie")
+    }
+
+    /// Returns `true` unless the request names another origin than the server's own, or a
+    /// trusted one, in `Origin` or `Referer`.
+    fn is_same_origin(&self, state: &State) -> bool {
+        let headers = state.borrow::<HeaderMap>();
+        let origin = match headers.get(ORIGIN).or_else(|| headers.get(REFERER)) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<Uri>().ok())
+                .and_then(|uri| origin_of(&uri)),
+            // Some clients leave both out, so only the token protects these requests.
+            None => return true,
+        };
+
+        // An opaque `null` origin, or a malformed header, never matches.
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return false,
+        };
+
+        own_origin(state).is_some_and(|own| own == origin) || self.trusted_origins.contains(&origin)
+    }
+
+    /// Reads the token sent with the request, from the header or the form body.
+    async fn submitted_token(&self, state: &mut State) -> Result<Option<String>, StatusCode> {
+        let headers = state.borrow::<HeaderMap>();
+        if let Some(value) = headers.get(&self.header) {
+            return Ok(value.to_str().ok().map(str::to_owned));
+        }
+
+        let is_form = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Mime>().ok())
+            .is_some_and(|mime| mime.essence_str() == "application/x-www-form-urlencoded");
+        if !is_form {
+            return Ok(None);
+        }
+
+        // The form is read before anything authenticates the request, so only small ones are.
+        let mut body = Body::take_from(state);
+        if HttpBody::size_hint(&body).lower() > self.max_form_size as u64 {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        let mut bytes = BytesMut::new();
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|err| {
+                if err.is_length_limit() {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    StatusCode::BAD_REQUEST
+                }
+            })?;
+
+            if let Ok(data) = frame.into_data() {
+                if bytes.len() + data.len() > self.max_form_size {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                bytes.extend_from_slice(&data);
+            }
+        }
+
+        let token = std::str::from_utf8(&bytes).ok().and_then(|body| {
+            query_string::split(Some(body))
+                .get(&self.form_field)
+                .and_then(|values| values.first())
+                .map(|value| value.as_ref().to_owned())
+        });
+
+        // Leave the body for the handler.
+        state.put(Body::from(bytes.freeze()));
+        Ok(token)
+    }
+}
+
+/// Returns `true` for tokens issued by the double-submit mode.
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 64
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Returns the origin of `uri`, in lowercase and without the default port, or `None` if it isn't
+/// an absolute HTTP URI.
+fn origin_of(uri: &Uri) -> Option<String> {
+    let scheme = uri.scheme_str()?.to_ascii_lowercase();
+    let host = uri.host()?.to_ascii_lowercase();
+
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+
+    Some(match uri.port_u16() {
+        Some(port) if port != default_port => format!("{}://{}:{}", scheme, host, port),
+        _ => format!("{}://{}", scheme, host),
+    })
+}
+
+/// Returns the origin the client used to reach the server.
+fn own_origin(state: &State) -> Option<String> {
+    let (scheme, host) = match real_client(state) {
+        Some(client) => (client.scheme(), client.host()?.to_owned()),
+        None => {
+            let scheme = match TlsInfo::try_borrow_from(state) {
+                Some(_) => "https",
+                None => "http",
+            };
+            let host = match state.borrow::<HeaderMap>().get(HOST) {
+                Some(host) => host.to_str().ok()?.to_owned(),
+                None => state.borrow::<Uri>().authority()?.to_string(),
+            };
+            (scheme, host)
+        }
+    };
+
+    format!("{}://{}", scheme, host)
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| origin_of(&uri))
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for CsrfMiddleware {
+    /// Stores the token in `State`, and rejects unsafe requests without it.
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let expected = self.expected_token(&state);
+
+        if let Some(ref token) = expected {
+            state.put(CsrfToken(token.clone()));
+        }
+
+        let safe = matches!(
+            *Method::borrow_from(&state),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        );
+
+        if safe {
+            // Issue a token to clients without one, in double-submit mode.
+            let issued = match (&self.mode, &expected) {
+                (Mode::Cookie, None) => {
+                    let token = random_token();
+                    state.put(CsrfToken(token.clone()));
+                    Some(self.token_cookie(&token))
+                }
+                _ => None,
+            };
+
+            let f = chain(state).and_then(move |(state, mut response)| {
+                if let Some(cookie) = issued {
+                    response.headers_mut().append(SET_COOKIE, cookie);
+                }
+                future::ok((state, response))
+            });
+
+            return f.boxed();
+        }
+
+        if !self.is_same_origin(&state) {
+            debug!("[{}] cross-origin request rejected", request_id(&state));
+            let res = create_empty_response(&state, StatusCode::FORBIDDEN);
+            return future::ok((state, res)).boxed();
+        }
+
+        async move {
+            let submitted = match self.submitted_token(&mut state).await {
+                Ok(submitted) => submitted,
+                Err(status) => {
+                    let res = create_empty_response(&state, status);
+                    return Ok((state, res));
+                }
+            };
+
+            let valid = match (expected, submitted) {
+                (Some(expected), Some(submitted)) => {
+                    verify_slices_are_equal(expected.as_bytes(), submitted.as_bytes()).is_ok()
+                }
+                _ => false,
+            };
+
+            if !valid {
+                debug!("[{}] missing or invalid CSRF token", request_id(&state));
+                let res = create_empty_response(&state, StatusCode::FORBIDDEN);
+                return Ok((state, res));
+            }
+
+            chain(state).await
+        }
+        .boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for CsrfMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::COOKIE;
+    use hyper::{Request, Response};
+    use std::net::SocketAddr;
+
+    fn new_state(req: Request<Body>) -> State {
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    fn ok(state: State) -> Pin<Box<HandlerFuture>> {
+        future::ok((state, Response::new(Body::empty()))).boxed()
+    }
+
+    async fn status(middleware: &CsrfMiddleware, state: State) -> StatusCode {
+        let (_, res) = middleware.clone().call(state, ok).await.ok().unwrap();
+        res.status()
+    }
+
+    fn post(session: Option<&str>) -> hyper::http::request::Builder {
+        let builder = Request::post("/admin/users").header(HOST, "example.com");
+        match session {
+            Some(id) => builder.header(COOKIE, format!("csrf_token={}", id)),
+            None => builder,
+        }
+    }
+
+    #[test]
+    fn normalizes_origins() {
+        let origin = |uri: &str| origin_of(&uri.parse().unwrap());
+
+        assert_eq!(
+            origin("https://Example.com:443").unwrap(),
+            "https://example.com"
+        );
+        assert_eq!(
+            origin("http://example.com:8080/path?q").unwrap(),
+            "http://example.com:8080"
+        );
+        assert_eq!(
+            origin("https://user@example.com/").unwrap(),
+            "https://example.com"
+        );
+        assert_eq!(origin("ftp://example.com"), None);
+        assert_eq!(origin("/relative"), None);
+    }
+
+    #[tokio::test]
+    async fn verifies_session_tokens() {
+        let csrf = CsrfMiddleware::session_with_key(b"0123456789abcdef0123456789abcdef");
+        let with_session = |req: Request<Body>| {
+            let mut state = new_state(req);
+            state.put(SessionIdentifier {
+                value: "session-1".to_owned(),
+            });
+            state
+        };
+
+        let (state, _) = csrf
+            .clone()
+            .call(
+                with_session(Request::get("/").body(Body::empty()).unwrap()),
+                ok,
+            )
+            .await
+            .ok()
+            .unwrap();
+        let token = CsrfToken::borrow_from(&state).as_str().to_owned();
+
+        let req = post(None)
+            .header(DEFAULT_HEADER, token.as_str())
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(&csrf, with_session(req)).await, StatusCode::OK);
+
+        let req = post(None)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("name=bob&csrf_token={}", token)))
+            .unwrap();
+        let (mut state, _) = csrf.clone().call(with_session(req), ok).await.ok().unwrap();
+        let body = Body::take_from(&mut state).to_bytes().await.unwrap();
+        assert!(body.starts_with(b"name=bob&"));
+
+        // Another session's token, or none at all.
+        let req = post(None)
+            .header(DEFAULT_HEADER, token.as_str())
+            .body(Body::empty())
+            .unwrap();
+        let mut other = new_state(req);
+        other.put(SessionIdentifier {
+            value: "session-2".to_owned(),
+        });
+        assert_eq!(status(&csrf, other).await, StatusCode::FORBIDDEN);
+
+        let req = post(None).body(Body::empty()).unwrap();
+        assert_eq!(
+            status(&csrf, with_session(req)).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn issues_and_verifies_cookie_tokens() {
+        let csrf = CsrfMiddleware::double_submit_cookie();
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let (state, res) = csrf.clone().call(new_state(req), ok).await.ok().unwrap();
+        let token = CsrfToken::borrow_from(&state).as_str().to_owned();
+        assert_eq!(
+            res.headers()[SET_COOKIE],
+            format!("csrf_token={}; SameSite=Strict; Secure; Path=/", token)
+        );
+
+        let req = post(Some(&token))
+            .header(DEFAULT_HEADER, token.as_str())
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(&csrf, new_state(req)).await, StatusCode::OK);
+
+        let req = post(Some(&token))
+            .header(DEFAULT_HEADER, "forged")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(&csrf, new_state(req)).await, StatusCode::FORBIDDEN);
+
+        let req = post(None)
+            .header(DEFAULT_HEADER, token.as_str())
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(&csrf, new_state(req)).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn limits_form_size() {
+        let csrf = CsrfMiddleware::double_submit_cookie().max_form_size(64);
+        let form = |body: String| {
+            let req = post(Some("token"))
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(body))
+                .unwrap();
+            new_state(req)
+        };
+
+        let small = "csrf_token=token".to_owned();
+        assert_eq!(status(&csrf, form(small)).await, StatusCode::OK);
+
+        let large = format!("name={}&csrf_token=token", "a".repeat(64));
+        assert_eq!(
+            status(&csrf, form(large.clone())).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // Large forms can still send the token in the header.
+        let req = post(Some("token"))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(DEFAULT_HEADER, "token")
+            .body(Body::from(large))
+            .unwrap();
+        assert_eq!(status(&csrf, new_state(req)).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn checks_origins() {
+        let csrf = CsrfMiddleware::double_submit_cookie().trust_origin("https://admin.example.org");
+        let request = |header: HeaderName, value: &str| {
+            let req = post(Some("token"))
+                .header(DEFAULT_HEADER, "token")
+                .header(header, value)
+                .body(Body::empty())
+                .unwrap();
+            new_state(req)
+        };
+
+        let allowed = [
+            (ORIGIN, "http://example.com"),
+            (ORIGIN, "https://admin.example.org"),
+            (REFERER, "http://example.com/admin/users"),
+        ];
+        for (header, value) in allowed {
+            assert_eq!(status(&csrf, request(header, value)).await, StatusCode::OK);
+        }
+
+        let rejected = [
+            (ORIGIN, "https://evil.com"),
+            (ORIGIN, "null"),
+            (ORIGIN, "https://example.com"),
+            (REFERER, "http://evil.com/example.com"),
+        ];
+        for (header, value) in rejected {
+            assert_eq!(
+                status(&csrf, request(header, value)).await,
+                StatusCode::FORBIDDEN
+            );
+        }
+    }
+}
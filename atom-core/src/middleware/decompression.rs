@@ -0,0 +1,367 @@
+//! Defines `DecompressionMiddleware`, which decodes compressed request bodies before they reach
+//! extractors and handlers.
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use bytes::Bytes;
+use futures_util::future::{self, FutureExt};
+use futures_util::stream::{Stream, TryStreamExt};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::StatusCode;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::body::Body;
+use crate::error::Error;
+use crate::handler::HandlerFuture;
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::compression::Encoding;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{FromState, State};
+
+/// Decoded request bodies may be at most this many bytes by default.
+pub const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Request bodies may expand at most this many times when decoded by default.
+pub const DEFAULT_MAX_RATIO: usize = 100;
+
+/// The compression ratio is only enforced once this many bytes have been decoded, so that small,
+/// highly repetitive bodies aren't rejected.
+const RATIO_THRESHOLD: usize = 64 * 1024;
+
+/// Decodes request bodies sent with a `Content-Encoding`, so that `Body::take_from` and the
+/// extractors see the original bytes. Bodies are decoded as they are read, without buffering the
+/// whole request.
+///
+/// To protect against decompression bombs, reading the body fails once the decoded body grows
+/// past the maximum size, `DEFAULT_MAX_SIZE` by default, or once it has expanded more than the
+/// maximum ratio, `DEFAULT_MAX_RATIO` by default. Either failure is a length limit error, see
+/// `Error::is_length_limit`, which extractors answer with `413 Payload Too Large`. Limits set
+/// with `ServerBuilder::max_body_size` still apply to the body as it was received.
+///
+/// Requests using an encoding which isn't enabled are rejected with
+/// `415 Unsupported Media Type`, with an `Accept-Encoding` header listing the enabled encodings.
+/// Once a body has been decoded, the request's `Content-Encoding` and `Content-Length` headers
+/// are removed, since they no longer describe it.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(
+///             DecompressionMiddleware::new()
+///                 .encodings([Encoding::Gzip, Encoding::Zstd])
+///                 .max_size(1024 * 1024),
+///         )
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct DecompressionMiddleware {
+    encodings: Vec<Encoding>,
+    max_size: usize,
+    max_ratio: usize,
+}
+
+impl Default for DecompressionMiddleware {
+    fn default() -> DecompressionMiddleware {
+        DecompressionMiddleware {
+            encodings: vec![
+                Encoding::Gzip,
+                Encoding::Deflate,
+                Encoding::Brotli,
+                Encoding::Zstd,
+            ],
+            max_size: DEFAULT_MAX_SIZE,
+            max_ratio: DEFAULT_MAX_RATIO,
+        }
+    }
+}
+
+impl DecompressionMiddleware {
+    /// Creates a `DecompressionMiddleware` supporting every `Encoding`, with the default limits.
+    pub fn new() -> DecompressionMiddleware {
+        DecompressionMiddleware::default()
+    }
+
+    /// Sets the encodings which request bodies may use.
+    pub fn encodings<I>(mut self, encodings: I) -> DecompressionMiddleware
+    where
+        I: IntoIterator<Item = Encoding>,
+    {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// Sets the maximum number of bytes a decoded body may contain.
+    pub fn max_size(mut self, max_size: usize) -> DecompressionMiddleware {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets how many times larger than the received body the decoded body may grow.
+    pub fn max_ratio(mut self, max_ratio: usize) -> DecompressionMiddleware {
+        self.max_ratio = max_ratio;
+        self
+    }
+
+    /// Parses the `Content-Encoding` of the request, in the order the encodings were applied.
+    /// Returns `None` if any of them isn't enabled.
+    fn content_encodings(&self, headers: &HeaderMap) -> Option<Vec<Encoding>> {
+        let mut encodings = Vec::new();
+
+        for value in headers.get_all(CONTENT_ENCODING) {
+            let value = value.to_str().ok()?;
+            for name in value.split(',').map(str::trim) {
+                if name.is_empty() || name.eq_ignore_ascii_case("identity") {
+                    continue;
+                }
+
+                let encoding = Encoding::from_name(name)
+                    .filter(|encoding| self.encodings.contains(encoding))?;
+                encodings.push(encoding);
+            }
+        }
+
+        Some(encodings)
+    }
+
+    /// Replaces the request body in `state` with one decoding `encodings`.
+    fn decode(&self, state: &mut State, encodings: &[Encoding]) {
+        let received = Arc::new(AtomicUsize::new(0));
+
+        let counter = received.clone();
+        let body = Body::take_from(state)
+            .inspect_ok(move |chunk| {
+                counter.fetch_add(chunk.len(), Ordering::Relaxed);
+            })
+            .map_err(io::Error::other);
+
+        // Encodings are listed in the order they were applied, so they're undone in reverse.
+        let mut reader: Box<dyn AsyncBufRead + Send + Unpin> = Box::new(StreamReader::new(body));
+        for encoding in encodings.iter().rev() {
+            let decoder: Box<dyn AsyncRead + Send + Unpin> = match encoding {
+                Encoding::Gzip => {
+                    let mut decoder = GzipDecoder::new(reader);
+                    decoder.multiple_members(true);
+                    Box::new(decoder)
+                }
+                Encoding::Deflate => Box::new(ZlibDecoder::new(reader)),
+                Encoding::Brotli => Box::new(BrotliDecoder::new(reader)),
+                Encoding::Zstd => Box::new(ZstdDecoder::new(reader)),
+            };
+            reader = Box::new(BufReader::new(decoder));
+        }
+
+        state.put(Body::from_stream(Limited {
+            inner: ReaderStream::new(reader),
+            received,
+            decoded: 0,
+            max_size: self.max_size,
+            max_ratio: self.max_ratio,
+            failed: false,
+        }));
+
+        let headers = state.borrow_mut::<HeaderMap>();
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(CONTENT_LENGTH);
+    }
+
+    fn unsupported(&self, state: &State) -> hyper::Response<Body> {
+        let mut response = create_empty_response(state, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let names: Vec<&str> = self
+            .encodings
+            .iter()
+            .map(|encoding| encoding.name())
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&names.join(", ")) {
+            response.headers_mut().insert(ACCEPT_ENCODING, value);
+        }
+
+        response
+    }
+}
+
+pin_project! {
+    /// A decoded body, which fails once it exceeds the maximum size or compression ratio.
+    struct Limited<S> {
+        #[pin]
+        inner: S,
+        received: Arc<AtomicUsize>,
+        decoded: usize,
+        max_size: usize,
+        max_ratio: usize,
+        failed: bool,
+    }
+}
+
+impl<S> Stream for Limited<S>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        // Stop decoding altogether once a limit has been hit.
+        if *this.failed {
+            return Poll::Ready(None);
+        }
+
+        let chunk = match futures_util::ready!(this.inner.poll_next(cx)) {
+            Some(Ok(chunk)) => chunk,
+            other => return Poll::Ready(other),
+        };
+
+        *this.decoded = this.decoded.saturating_add(chunk.len());
+        let received = this.received.load(Ordering::Relaxed);
+
+        let error = if *this.decoded > *this.max_size {
+            Error::length_limit(*this.max_size)
+        } else if *this.decoded > RATIO_THRESHOLD
+            && *this.decoded > received.saturating_mul(*this.max_ratio)
+        {
+            Error::compression_ratio_limit(*this.max_ratio)
+        } else {
+            return Poll::Ready(Some(Ok(chunk)));
+        };
+
+        *this.failed = true;
+        Poll::Ready(Some(Err(io::Error::other(error))))
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for DecompressionMiddleware {
+    /// Decodes the request body, or rejects the request if its encoding isn't supported.
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        match self.content_encodings(state.borrow::<HeaderMap>()) {
+            Some(encodings) if encodings.is_empty() => {}
+            Some(encodings) => self.decode(&mut state, &encodings),
+            None => {
+                let response = self.unsupported(&state);
+                return future::ok((state, response)).boxed();
+            }
+        }
+
+        chain(state)
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for DecompressionMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+    use hyper::Request;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+
+    async fn compress<R: AsyncRead + Unpin>(mut encoder: R) -> Vec<u8> {
+        let mut data = Vec::new();
+        encoder.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    fn request_state(encoding: &'static str, body: Vec<u8>) -> State {
+        let req = Request::post("/upload")
+            .header(CONTENT_ENCODING, encoding)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn parses_content_encodings() {
+        let decompression = DecompressionMiddleware::new();
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(
+            decompression.content_encodings(&headers("gzip, ZSTD")),
+            Some(vec![Encoding::Gzip, Encoding::Zstd])
+        );
+        assert_eq!(
+            decompression.content_encodings(&headers("identity")),
+            Some(vec![])
+        );
+        assert_eq!(
+            decompression.content_encodings(&HeaderMap::new()),
+            Some(vec![])
+        );
+        assert_eq!(decompression.content_encodings(&headers("compress")), None);
+
+        let gzip_only = DecompressionMiddleware::new().encodings([Encoding::Gzip]);
+        assert_eq!(gzip_only.content_encodings(&headers("br")), None);
+
+        let response = gzip_only.unsupported(&request_state("br", vec![]));
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.headers()[ACCEPT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn decodes_stacked_encodings() {
+        let original = "hello world ".repeat(100);
+        let gzipped = compress(GzipEncoder::new(original.as_bytes())).await;
+        let encoded = compress(ZstdEncoder::new(&gzipped[..])).await;
+
+        let mut state = request_state("gzip, zstd", encoded);
+        DecompressionMiddleware::new().decode(&mut state, &[Encoding::Gzip, Encoding::Zstd]);
+
+        let headers = state.borrow::<HeaderMap>();
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+
+        let body = Body::take_from(&mut state).to_bytes().await.unwrap();
+        assert_eq!(body, original);
+    }
+
+    #[tokio::test]
+    async fn rejects_decompression_bombs() {
+        let zeros = vec![0; 1024 * 1024];
+        let encoded = compress(GzipEncoder::new(&zeros[..])).await;
+
+        let mut state = request_state("gzip", encoded.clone());
+        DecompressionMiddleware::new()
+            .max_size(4096)
+            .decode(&mut state, &[Encoding::Gzip]);
+        let err = Body::take_from(&mut state).to_bytes().await.unwrap_err();
+        assert!(err.is_length_limit());
+        assert_eq!(err.message(), "length limit of 4096 bytes exceeded");
+
+        let mut state = request_state("gzip", encoded.clone());
+        DecompressionMiddleware::new().decode(&mut state, &[Encoding::Gzip]);
+        let err = Body::take_from(&mut state).to_bytes().await.unwrap_err();
+        assert!(err.is_length_limit());
+        assert_eq!(err.message(), "compression ratio of 100 exceeded");
+
+        let mut state = request_state("gzip", encoded);
+        DecompressionMiddleware::new()
+            .max_ratio(10_000)
+            .decode(&mut state, &[Encoding::Gzip]);
+        let body = Body::take_from(&mut state).to_bytes().await.unwrap();
+        assert_eq!(body.len(), zeros.len());
+    }
+}
@@ -0,0 +1,441 @@
+//! Defines `ForwardedMiddleware`, which resolves the original client of requests received through
+//! trusted reverse proxies.
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, HeaderName, FORWARDED, HOST};
+use hyper::http::uri::Authority;
+use hyper::Uri;
+use ipnet::IpNet;
+
+use crate::handler::HandlerFuture;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{peer_addr, ClientAddr, FromState, RealClient, State};
+use crate::tls::TlsInfo;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// One proxy hop, as recorded by the proxy which received it.
+#[derive(Debug, Default, PartialEq)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Resolves the original client of each request from the `Forwarded` header (RFC 7239), or the
+/// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers when it is absent, and
+/// stores it in `State` as a `RealClient`.
+///
+/// Headers are only believed when they were added by a trusted proxy. Starting with the peer of
+/// the connection, each hop is followed back towards the client for as long as the address it was
+/// received from belongs to a trusted proxy, so clients cannot spoof their address by sending the
+/// headers themselves. The scheme and host are those recorded by the outermost trusted proxy.
+/// When the peer isn't trusted, the `RealClient` simply describes the connection.
+///
+/// `RequestLogger` logs the resolved address, and the redirect helpers in
+/// `helpers::http::response` use the resolved scheme and host to build absolute locations.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(
+///             ForwardedMiddleware::new()
+///                 .trust_proxy("10.0.0.0/8".parse::<IpNet>().unwrap())
+///                 .trust_proxy(IpAddr::from([127, 0, 0, 1])),
+///         )
+///         .add(RequestLogger::new(log::Level::Info))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ForwardedMiddleware {
+    trusted: Arc<Vec<IpNet>>,
+    trust_unix_sockets: bool,
+}
+
+impl ForwardedMiddleware {
+    /// Creates a `ForwardedMiddleware` which trusts no proxies, until some are added.
+    pub fn new() -> ForwardedMiddleware {
+        ForwardedMiddleware::default()
+    }
+
+    /// Trusts the forwarding headers added by proxies within `network`, which may be a single
+    /// address.
+    pub fn trust_proxy<N: Into<IpNet>>(mut self, network: N) -> ForwardedMiddleware {
+        Arc::make_mut(&mut self.trusted).push(network.into());
+        self
+    }
+
+    /// Trusts the forwarding headers on connections received over a Unix domain socket, which
+    /// usually come from a reverse proxy on the same host.
+    pub fn trust_unix_sockets(mut self) -> ForwardedMiddleware {
+        self.trust_unix_sockets = true;
+        self
+    }
+
+    /// Returns `true` if `ip` belongs to a trusted proxy.
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.trusted.iter().any(|network| network.contains(&ip))
+    }
+
+    fn resolve(
+        &self,
+        peer: Option<&ClientAddr>,
+        tls: bool,
+        headers: &HeaderMap,
+        uri: &Uri,
+    ) -> RealClient {
+        let mut client = RealClient {
+            ip: peer.and_then(ClientAddr::ip),
+            scheme: String::from(if tls { "https" } else { "http" }),
+            host: request_host(headers, uri),
+        };
+
+        let peer_trusted = match peer {
+            Some(ClientAddr::Tcp(addr)) => self.is_trusted(addr.ip()),
+            #[cfg(unix)]
+            Some(ClientAddr::Unix(_)) => self.trust_unix_sockets,
+            None => false,
+        };
+
+        if !peer_trusted {
+            return client;
+        }
+
+        // Each hop was recorded by the proxy following it, which so far has been trusted.
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            if let Some(proto) = hop.proto {
+                client.scheme = proto;
+            }
+            if let Some(host) = hop.host {
+                client.host = Some(host);
+            }
+            match hop.ip {
+                Some(ip) => {
+                    client.ip = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // An unknown or obfuscated node can't be followed any further.
+                None => break,
+            }
+        }
+
+        client
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for ForwardedMiddleware {
+    /// Stores the resolved `RealClient` in `State`.
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        let client = self.resolve(
+            peer_addr(&state),
+            TlsInfo::try_borrow_from(&state).is_some(),
+            HeaderMap::borrow_from(&state),
+            Uri::borrow_from(&state),
+        );
+
+        state.put(client);
+        chain(state)
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for ForwardedMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+fn request_host(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(valid_host)
+        .or_else(|| uri.authority().map(|authority| authority.to_string()))
+}
+
+/// Returns the hops described by the forwarding headers, ordered from the client to the proxy
+/// nearest to the server.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    if headers.contains_key(FORWARDED) {
+        // A malformed header can't be trusted at all.
+        return header_values(headers, &FORWARDED)
+            .iter()
+            .map(|element| parse_forwarded_element(element))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+    }
+
+    let mut hops = header_values(headers, &X_FORWARDED_FOR)
+        .iter()
+        .map(|node| Hop {
+            ip: parse_node(node),
+            ..Hop::default()
+        })
+        .collect::<Vec<_>>();
+
+    let protos = header_values(headers, &X_FORWARDED_PROTO);
+    let hosts = header_values(headers, &X_FORWARDED_HOST);
+
+    if hops.is_empty() && (!protos.is_empty() || !hosts.is_empty()) {
+        hops.push(Hop::default());
+    }
+
+    // Lists matching X-Forwarded-For describe each hop; otherwise the nearest proxy set them.
+    for (index, proto) in align(protos, hops.len()) {
+        hops[index].proto = valid_proto(&proto);
+    }
+    for (index, host) in align(hosts, hops.len()) {
+        hops[index].host = valid_host(&host);
+    }
+
+    hops
+}
+
+/// Pairs each of `values` with the index of the hop it describes.
+fn align(values: Vec<String>, hops: usize) -> Vec<(usize, String)> {
+    if values.len() == hops {
+        values.into_iter().enumerate().collect()
+    } else {
+        values
+            .into_iter()
+            .last()
+            .map(|value| (hops - 1, value))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Returns the comma separated values of every `name` header, ignoring commas within quotes.
+fn header_values(headers: &HeaderMap, name: &HeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| split_unquoted(value, ','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+fn parse_forwarded_element(element: &str) -> Option<Hop> {
+    let mut hop = Hop::default();
+
+    for pair in split_unquoted(element, ';') {
+        let pair = pair.trim();
+        if pair.is_empty() {
+            continue;
+        }
+
+        let (name, value) = pair.split_once('=')?;
+        let value = unquote(value.trim())?;
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => hop.ip = parse_node(&value),
+            "proto" => hop.proto = Some(valid_proto(&value)?),
+            "host" => hop.host = Some(valid_host(&value)?),
+            _ => {}
+        }
+    }
+
+    Some(hop)
+}
+
+fn unquote(value: &str) -> Option<String> {
+    let inner = match value.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"')?,
+        None if value.contains('"') => return None,
+        None => return Some(value.to_owned()),
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            '"' => return None,
+            c => unquoted.push(c),
+        }
+    }
+
+    Some(unquoted)
+}
+
+/// Parses a node identifier, such as `192.0.2.60`, `192.0.2.60:4711` or `[2001:db8::17]:4711`.
+/// `unknown` and obfuscated identifiers have no address.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn valid_proto(proto: &str) -> Option<String> {
+    let mut chars = proto.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then(|| proto.to_ascii_lowercase())
+}
+
+fn valid_host(host: &str) -> Option<String> {
+    match host.parse::<Authority>() {
+        Ok(authority) if !host.contains('@') => Some(authority.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn middleware() -> ForwardedMiddleware {
+        ForwardedMiddleware::new()
+            .trust_proxy("10.0.0.0/8".parse::<IpNet>().unwrap())
+            .trust_proxy(IpAddr::from([127, 0, 0, 1]))
+    }
+
+    fn resolve(peer: &str, headers: &[(&'static str, &'static str)]) -> RealClient {
+        let mut map = HeaderMap::new();
+        map.insert(HOST, HeaderValue::from_static("internal:8080"));
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+
+        let peer = ClientAddr::Tcp(peer.parse().unwrap());
+        middleware().resolve(Some(&peer), false, &map, &Uri::from_static("/"))
+    }
+
+    fn client(ip: &str, scheme: &str, host: &str) -> RealClient {
+        RealClient {
+            ip: Some(ip.parse().unwrap()),
+            scheme: scheme.to_owned(),
+            host: Some(host.to_owned()),
+        }
+    }
+
+    #[test]
+    fn parses_forwarded_elements() {
+        assert_eq!(
+            parse_forwarded_element(
+                r#"for="[2001:db8:cafe::17]:4711";proto=HTTPS;host="a.example""#
+            ),
+            Some(Hop {
+                ip: Some("2001:db8:cafe::17".parse().unwrap()),
+                proto: Some(String::from("https")),
+                host: Some(String::from("a.example")),
+            })
+        );
+        assert_eq!(
+            parse_forwarded_element("For=192.0.2.43:47011; by=10.0.0.1"),
+            Some(Hop {
+                ip: Some("192.0.2.43".parse().unwrap()),
+                ..Hop::default()
+            })
+        );
+        assert_eq!(parse_forwarded_element("for=unknown"), Some(Hop::default()));
+        assert_eq!(parse_forwarded_element("for=_hidden"), Some(Hop::default()));
+        assert_eq!(parse_forwarded_element(r#"for="192.0.2.43"#), None);
+        assert_eq!(parse_forwarded_element("for"), None);
+        assert_eq!(parse_forwarded_element("proto=ht tp"), None);
+        assert_eq!(
+            split_unquoted(r#"for="a,b";proto=https, for=c"#, ','),
+            [r#"for="a,b";proto=https"#, " for=c"]
+        );
+    }
+
+    #[test]
+    fn follows_trusted_proxies() {
+        let headers = [(
+            "forwarded",
+            "for=198.51.100.4;proto=https;host=example.org, for=10.1.2.3",
+        )];
+        assert_eq!(
+            resolve("127.0.0.1:4000", &headers),
+            client("198.51.100.4", "https", "example.org")
+        );
+
+        // Spoofed hops before the first untrusted address are ignored.
+        let headers = [
+            ("x-forwarded-for", "203.0.113.9, 198.51.100.4"),
+            ("x-forwarded-for", "10.1.2.3"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.org"),
+        ];
+        assert_eq!(
+            resolve("10.0.0.1:4000", &headers),
+            client("198.51.100.4", "https", "example.org")
+        );
+
+        let headers = [("x-forwarded-for", "unknown, 10.1.2.3")];
+        assert_eq!(
+            resolve("10.0.0.1:4000", &headers),
+            client("10.1.2.3", "http", "internal:8080")
+        );
+    }
+
+    #[test]
+    fn ignores_untrusted_or_malformed_headers() {
+        let headers = [
+            ("x-forwarded-for", "198.51.100.4"),
+            ("x-forwarded-proto", "https"),
+        ];
+        assert_eq!(
+            resolve("192.0.2.1:4000", &headers),
+            client("192.0.2.1", "http", "internal:8080")
+        );
+
+        let headers = [("forwarded", "for=198.51.100.4;proto=\"https")];
+        assert_eq!(
+            resolve("127.0.0.1:4000", &headers),
+            client("127.0.0.1", "http", "internal:8080")
+        );
+
+        assert!(middleware().is_trusted("::ffff:10.0.0.5".parse().unwrap()));
+        assert!(!middleware().is_trusted("11.0.0.5".parse().unwrap()));
+    }
+}
@@ -14,7 +14,7 @@ use std::pin::Pin;
 use crate::handler::HandlerFuture;
 use crate::helpers::timing::Timer;
 use crate::middleware::{Middleware, MiddlewareBuild};
-use crate::state::{client_addr, request_id, FromState, State};
+use crate::state::{peer_addr, real_client, request_id, ClientAddr, FromState, RealClient, State};
 
 /// A struct that can act as a logging middleware for Gotham.
 ///
@@ -73,8 +73,12 @@ impl Middleware for RequestLogger {
                 timer.start_time().format(&DT_FORMAT).expect("Failed to format time")
             };
 
-            // grab the ip address from the state
-            let ip = client_addr(&state).unwrap().ip();
+            // grab the ip address from the state, preferring the client behind any trusted
+            // proxies; clients on other transports are logged as "-"
+            let ip = real_client(&state)
+                .and_then(RealClient::ip)
+                .or_else(|| peer_addr(&state).and_then(ClientAddr::ip))
+                .map_or_else(|| String::from("-"), |ip| ip.to_string());
 
             {
                 // borrows from the state
@@ -3,17 +3,27 @@
 
 use std::panic::RefUnwindSafe;
 use std::pin::Pin;
+use hyper::Method;
 use log::{log, trace};
 
 use crate::handler::HandlerFuture;
 use crate::state::State;
 
 pub mod chain;
+pub mod client_cert;
+pub mod compression;
+pub mod concurrency;
 pub mod cookie;
+pub mod cors;
+pub mod csrf;
+pub mod decompression;
+pub mod forwarded;
 pub mod logger;
+pub mod rate_limit;
 pub mod security;
 pub mod session;
 pub mod state;
+pub mod timeout;
 pub mod timer;
 
 #[cfg(feature = "derive")]
@@ -369,4 +379,15 @@ pub trait MiddlewareBuild: Sync + RefUnwindSafe {
 
     /// Create and return a new `Middleware` value.
     fn new_middleware(&self) -> anyhow::Result<Self::Instance>;
+
+    /// Claims an `OPTIONS` request which no route on its path accepts, by returning the method of
+    /// the request it asks about. The `Router` then dispatches it to the route accepting that
+    /// method, if this `Middleware` is in its pipelines, with an `OptionsFallback` in `State`
+    /// which the `Middleware` must take and answer. Unclaimed requests are answered with
+    /// `405 Method Not Allowed` without running any pipeline.
+    ///
+    /// Returns `None` by default.
+    fn options_method(&self, _state: &State) -> Option<Method> {
+        None
+    }
 }
@@ -0,0 +1,488 @@
+//! Defines `RateLimitMiddleware`, which limits how often each client may make requests.
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::StatusCode;
+use log::{debug, trace};
+
+use crate::handler::HandlerFuture;
+use crate::helpers::http::header::{RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET};
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::session::SessionIdentifier;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{peer_addr, real_client, request_id, FromState, State};
+
+/// The number of keys a `RateLimitMiddleware` tracks at once, unless configured otherwise.
+pub const DEFAULT_MAX_KEYS: usize = 100_000;
+
+/// Type alias for the `RateLimitMiddleware` storage container, holding the theoretical arrival
+/// time of the next request for each key.
+type LimitMap = Mutex<HashMap<String, Instant>>;
+
+/// Type alias for closures returning the key of a request.
+type KeyFn = dyn Fn(&State) -> Option<String> + Send + Sync + RefUnwindSafe;
+
+#[derive(Clone)]
+enum Key {
+    ClientAddr,
+    Header(HeaderName),
+    Session,
+    Custom(Arc<KeyFn>),
+}
+
+impl Key {
+    fn of(&self, state: &State) -> Option<String> {
+        match self {
+            Key::ClientAddr => match real_client(state).and_then(|client| client.ip()) {
+                Some(ip) => Some(ip.to_string()),
+                None => peer_addr(state).map(|addr| match addr.ip() {
+                    Some(ip) => ip.to_string(),
+                    None => addr.to_string(),
+                }),
+            },
+            Key::Header(name) => state
+                .borrow::<HeaderMap>()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned),
+            Key::Session => {
+                SessionIdentifier::try_borrow_from(state).map(|identifier| identifier.value.clone())
+            }
+            Key::Custom(key) => key(state),
+        }
+    }
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::ClientAddr => f.write_str("ClientAddr"),
+            Key::Header(name) => f.debug_tuple("Header").field(name).finish(),
+            Key::Session => f.write_str("Session"),
+            Key::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// The outcome of a request against a limit.
+#[derive(Debug, PartialEq, Eq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// The time until the limit is fully replenished.
+    reset: Duration,
+    /// The time until the next request is allowed, if this one isn't.
+    retry_after: Duration,
+}
+
+/// Allows `limit` requests per `period`, implemented with the generic cell rate algorithm.
+///
+/// Requests are spaced out evenly over the period, so that a client which made `limit` requests
+/// at once may make another after `period / limit` rather than waiting for the whole period.
+#[derive(Clone, Copy, Debug)]
+struct Quota {
+    limit: u32,
+    period: Duration,
+    /// The time it takes to regain a single request, `period / limit`.
+    interval: Duration,
+}
+
+impl Quota {
+    fn check(&self, storage: &mut HashMap<String, Instant>, key: String, now: Instant) -> Decision {
+        // A key without an entry has its full allowance, like one whose arrival time has passed.
+        let tat = match storage.get(&key) {
+            Some(&tat) if tat > now => tat,
+            _ => now,
+        };
+
+        let next = tat + self.interval;
+        let allowed_at = next.checked_sub(self.period).unwrap_or(now);
+
+        if allowed_at > now {
+            return Decision {
+                allowed: false,
+                remaining: 0,
+                reset: tat - now,
+                retry_after: allowed_at - now,
+            };
+        }
+
+        storage.insert(key, next);
+
+        let allowance = (now + self.period).saturating_duration_since(next);
+        Decision {
+            allowed: true,
+            remaining: (allowance.as_nanos() / self.interval.as_nanos()) as u32,
+            reset: next - now,
+            retry_after: Duration::ZERO,
+        }
+    }
+}
+
+/// Rejects requests with `429 Too Many Requests` once a client exceeds a rate limit.
+///
+/// Clients may make `limit` requests per `period`, in a burst or spread out, and regain one
+/// request every `period / limit`. Every response carries the `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers, and rejections also carry `Retry-After`.
+///
+/// Clients are told apart by their IP address by default, which is taken from `RealClient` when
+/// `ForwardedMiddleware` runs earlier in the pipeline. They may instead be keyed by a request
+/// header such as an API key, by their session or by a closure over `State`. Requests without a
+/// key aren't limited, so a limit keyed by a header should usually be combined with one keyed by
+/// address.
+///
+/// Each `RateLimitMiddleware` counts requests in its own in-memory store, which clones share.
+/// Adding separate instances to the pipelines of different route groups gives each group its own
+/// limit, while adding one instance to several pipelines gives them a common one. Keys are dropped
+/// from the store by a background thread once they've regained their full allowance. The store
+/// holds up to `max_keys` keys, and while it's full, requests with new keys are rejected until
+/// the next sweep makes room.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(RateLimitMiddleware::new(100, Duration::from_secs(60)))
+///         .add(RateLimitMiddleware::new(10, Duration::from_secs(1)).key_by_header(X_API_KEY))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    quota: Quota,
+    key: Key,
+    max_keys: usize,
+    storage: Arc<LimitMap>,
+}
+
+impl RateLimitMiddleware {
+    /// Creates a `RateLimitMiddleware` allowing each client `limit` requests per `period`.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` is zero, or `period` is shorter than `limit` nanoseconds.
+    pub fn new(limit: u32, period: Duration) -> RateLimitMiddleware {
+        assert!(limit > 0, "rate limits must allow at least one request");
+        let interval = period / limit;
+        assert!(!interval.is_zero(), "rate limit period is too short");
+
+        let storage = Arc::new(Mutex::new(HashMap::new()));
+
+        {
+            let storage = Arc::downgrade(&storage);
+            thread::spawn(move || cleanup_loop(storage, period));
+        }
+
+        RateLimitMiddleware {
+            quota: Quota {
+                limit,
+                period,
+                interval,
+            },
+            key: Key::ClientAddr,
+            max_keys: DEFAULT_MAX_KEYS,
+            storage,
+        }
+    }
+
+    /// Tells clients apart by the value of a request header, such as an API key.
+    ///
+    /// The header must be authenticated by earlier middleware, which rejects requests with
+    /// unknown keys. Otherwise, clients evade the limit by sending a new value with each request,
+    /// and fill the store with keys.
+    pub fn key_by_header(mut self, name: HeaderName) -> RateLimitMiddleware {
+        self.key = Key::Header(name);
+        self
+    }
+
+    /// Tells clients apart by their session, which requires `NewSessionMiddleware` to run earlier
+    /// in the pipeline.
+    pub fn key_by_session(mut self) -> RateLimitMiddleware {
+        self.key = Key::Session;
+        self
+    }
+
+    /// Tells clients apart by the key `key` returns for each request, which isn't limited when
+    /// `key` returns `None`.
+    pub fn key_by<F>(mut self, key: F) -> RateLimitMiddleware
+    where
+        F: Fn(&State) -> Option<String> + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.key = Key::Custom(Arc::new(key));
+        self
+    }
+
+    /// Sets the number of keys tracked at once, `DEFAULT_MAX_KEYS` by default.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is zero.
+    pub fn max_keys(mut self, max: usize) -> RateLimitMiddleware {
+        assert!(max > 0, "rate limits must track at least one key");
+        self.max_keys = max;
+        self
+    }
+
+    fn check(&self, key: String) -> Decision {
+        match self.storage.lock() {
+            Ok(storage) if storage.len() >= self.max_keys && !storage.contains_key(&key) => {
+                trace!(" rate limit store is full, rejecting new key");
+                let retry_after = sweep_interval(self.quota.period);
+                Decision {
+                    allowed: false,
+                    remaining: 0,
+                    reset: retry_after,
+                    retry_after,
+                }
+            }
+            Ok(mut storage) => self.quota.check(&mut storage, key, Instant::now()),
+            Err(PoisonError { .. }) => {
+                unreachable!("rate limit lock poisoned, HashMap panicked?")
+            }
+        }
+    }
+}
+
+impl Debug for RateLimitMiddleware {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitMiddleware")
+            .field("quota", &self.quota)
+            .field("key", &self.key)
+            .field("max_keys", &self.max_keys)
+            .finish()
+    }
+}
+
+/// Rounds `duration` up to whole seconds.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn set_headers(headers: &mut HeaderMap, quota: &Quota, decision: &Decision) {
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(quota.limit));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(
+        RATELIMIT_RESET,
+        HeaderValue::from(ceil_secs(decision.reset)),
+    );
+
+    if !decision.allowed {
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from(ceil_secs(decision.retry_after)),
+        );
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for RateLimitMiddleware {
+    /// Counts the request against the client's limit, and rejects it once that's exceeded.
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        let key = match self.key.of(&state) {
+            Some(key) => key,
+            None => return chain(state),
+        };
+
+        let decision = self.check(key);
+
+        if !decision.allowed {
+            debug!(
+                "[{}] rate limit exceeded, retry in {:?}",
+                request_id(&state),
+                decision.retry_after
+            );
+            let mut res = create_empty_response(&state, StatusCode::TOO_MANY_REQUESTS);
+            set_headers(res.headers_mut(), &self.quota, &decision);
+            return future::ok((state, res)).boxed();
+        }
+
+        let quota = self.quota;
+        chain(state)
+            .and_then(move |(state, mut response)| {
+                set_headers(response.headers_mut(), &quota, &decision);
+                future::ok((state, response))
+            })
+            .boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for RateLimitMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+/// Sweeps at least once a minute, so long periods don't let the store grow for too long, but not
+/// more than once a second.
+fn sweep_interval(period: Duration) -> Duration {
+    period.clamp(Duration::from_secs(1), Duration::from_secs(60))
+}
+
+fn cleanup_loop(storage: Weak<LimitMap>, period: Duration) {
+    let interval = sweep_interval(period);
+
+    loop {
+        // If the original `Arc<_>` goes away, the middleware is gone too, and we can bail out of
+        // this thread when the weak ref fails to upgrade.
+        let storage = match storage.upgrade() {
+            None => break,
+            Some(storage) => storage,
+        };
+
+        match storage.lock() {
+            Err(PoisonError { .. }) => break,
+            Ok(mut storage) => cleanup_once(&mut storage, Instant::now()),
+        };
+
+        // Don't keep the store alive while sleeping.
+        drop(storage);
+        thread::sleep(interval);
+    }
+}
+
+fn cleanup_once(storage: &mut HashMap<String, Instant>, now: Instant) {
+    let len = storage.len();
+
+    // Keys whose arrival time has passed have regained their full allowance, which is what a
+    // missing key gets as well.
+    storage.retain(|_, tat| *tat > now);
+
+    trace!(
+        " removed {} of {} keys from rate limit store",
+        len - storage.len(),
+        len
+    );
+
+    // Shrink the storage after a spike in clients, as `MemoryBackend` does.
+    if storage.capacity() >= 65536 && storage.capacity() / 8 > storage.len() {
+        storage.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use hyper::{Request, Response};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn limits_bursts_and_replenishes() {
+        let quota = RateLimitMiddleware::new(3, Duration::from_secs(3)).quota;
+        let mut storage = HashMap::new();
+        let start = Instant::now();
+        let check = |storage: &mut HashMap<_, _>, secs: u64| {
+            quota.check(
+                storage,
+                "client".to_owned(),
+                start + Duration::from_secs(secs),
+            )
+        };
+
+        for remaining in (0..3).rev() {
+            let decision = check(&mut storage, 0);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        assert_eq!(
+            check(&mut storage, 0),
+            Decision {
+                allowed: false,
+                remaining: 0,
+                reset: Duration::from_secs(3),
+                retry_after: Duration::from_secs(1),
+            }
+        );
+
+        // One request is regained every second, and other clients are unaffected.
+        let decision = check(&mut storage, 1);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(!check(&mut storage, 1).allowed);
+        assert!(quota.check(&mut storage, "other".to_owned(), start).allowed);
+
+        let decision = check(&mut storage, 10);
+        assert_eq!((decision.allowed, decision.remaining), (true, 2));
+
+        cleanup_once(&mut storage, start + Duration::from_secs(20));
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn cleanup_join_test() {
+        let storage = Arc::new(Mutex::new(HashMap::new()));
+        let weak = Arc::downgrade(&storage);
+
+        let handle = thread::spawn(move || cleanup_loop(weak, Duration::from_millis(1)));
+
+        drop(storage);
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_with_headers() {
+        let limit = RateLimitMiddleware::new(1, Duration::from_secs(60))
+            .key_by_header(HeaderName::from_static("x-api-key"));
+        let call = |key: Option<&str>| {
+            let mut req = Request::get("/");
+            if let Some(key) = key {
+                req = req.header("x-api-key", key);
+            }
+            let state = State::from_request(
+                req.body(Body::empty()).unwrap(),
+                "127.0.0.1:10000".parse::<SocketAddr>().unwrap(),
+            );
+            limit.clone().call(state, |state| {
+                future::ok((state, Response::new(Body::empty()))).boxed()
+            })
+        };
+
+        let (_, res) = call(Some("alice")).await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[RATELIMIT_LIMIT], "1");
+        assert_eq!(res.headers()[RATELIMIT_REMAINING], "0");
+        assert_eq!(res.headers()[RATELIMIT_RESET], "60");
+
+        let (_, res) = call(Some("alice")).await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "60");
+
+        let (_, res) = call(Some("bob")).await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let (_, res) = call(None).await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(RATELIMIT_LIMIT).is_none());
+    }
+
+    #[test]
+    fn limits_the_number_of_keys() {
+        let limit = RateLimitMiddleware::new(2, Duration::from_secs(60)).max_keys(2);
+
+        assert!(limit.check("alice".to_owned()).allowed);
+        assert!(limit.check("bob".to_owned()).allowed);
+
+        // Known keys keep their allowance, while new ones wait for room in the store.
+        assert!(limit.check("alice".to_owned()).allowed);
+        let decision = limit.check("carol".to_owned());
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(60));
+
+        limit.storage.lock().unwrap().remove("bob");
+        assert!(limit.check("carol".to_owned()).allowed);
+    }
+}
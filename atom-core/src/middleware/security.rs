@@ -4,48 +4,401 @@
 //! now been separated to allow optional usage. You can attach as a middleware
 //! at startup to include behaviour as was present before.
 //!
-//! Currently this middleware will set the following headers:
+//! By default this middleware will set the following headers:
 //!
 //! - X-CONTENT-TYPE-OPTIONS: "nosniff"
 //! - X-FRAME-OPTIONS: "DENY"
-//! - X-XSS-PROTECTION: "1; mode=block"
+//! - REFERRER-POLICY: "strict-origin-when-cross-origin"
 //!
-//! More may be added in future, but these headers provide compatibility with
-//! previous versions of Gotham.
+//! `Strict-Transport-Security`, `Content-Security-Policy`, `Permissions-Policy` and the
+//! cross-origin isolation headers can be added through `SecurityPolicy`. The deprecated
+//! `X-XSS-Protection` header is no longer sent, since it can introduce vulnerabilities in
+//! browsers which still implement it.
 use crate::handler::HandlerFuture;
+use crate::helpers::http::header::{
+    CROSS_ORIGIN_EMBEDDER_POLICY, CROSS_ORIGIN_OPENER_POLICY, CROSS_ORIGIN_RESOURCE_POLICY,
+    PERMISSIONS_POLICY,
+};
 use crate::middleware::{Middleware, MiddlewareBuild};
-use crate::state::State;
+use crate::state::{FromState, State};
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use futures_util::future::{self, FutureExt, TryFutureExt};
-use hyper::header::{HeaderValue, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS, X_XSS_PROTECTION};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY,
+    CONTENT_SECURITY_POLICY_REPORT_ONLY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use rand::RngCore;
 use std::pin::Pin;
+use std::time::Duration;
 
 // constant strings to be used as header values
 const XFO_VALUE: &str = "DENY";
-const XXP_VALUE: &str = "1; mode=block";
 const XCTO_VALUE: &str = "nosniff";
+const REFERRER_POLICY_VALUE: &str = "strict-origin-when-cross-origin";
 
-/// Middleware binding for the Gotham security handlers.
+/// Replaced by `'nonce-…'` in a `Content-Security-Policy`.
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// The nonce generated for the current request, when the `Content-Security-Policy` uses
+/// `NONCE_PLACEHOLDER`.
+///
+/// Pages add it to their inline `<script>` and `<style>` elements, which the policy then allows:
 ///
-/// This acts as nothing more than a trait implementation for the time
-/// being; there are no fields on the struct in use (yet).
-#[derive(Clone)]
-pub struct SecurityMiddleware;
+/// ```rust,ignore
+/// let nonce = CspNonce::borrow_from(&state).as_str();
+/// let page = format!("<script nonce=\"{}\">init()</script>", nonce);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    fn generate() -> CspNonce {
+        let mut bytes = [0; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        CspNonce(STANDARD.encode(bytes))
+    }
+
+    /// The base64 encoded nonce.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The `Strict-Transport-Security` policy, telling browsers to only connect over HTTPS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hsts {
+    max_age: Duration,
+    include_subdomains: bool,
+    preload: bool,
+}
+
+impl Hsts {
+    /// Creates a policy which browsers remember for `max_age`.
+    pub fn new(max_age: Duration) -> Hsts {
+        Hsts {
+            max_age,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+
+    /// Applies the policy to every subdomain as well.
+    pub fn include_subdomains(mut self) -> Hsts {
+        self.include_subdomains = true;
+        self
+    }
+
+    /// Asks for the domain to be included in the browsers' preload lists.
+    pub fn preload(mut self) -> Hsts {
+        self.preload = true;
+        self
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        HeaderValue::from_str(&value).expect("valid Strict-Transport-Security value")
+    }
+}
+
+/// The headers added by `SecurityMiddleware`.
+///
+/// The policy of the `SecurityMiddleware` is stored in `State` while the request is handled, so
+/// it can be changed for a single route: either by a handler, using `SecurityPolicy::update`, or
+/// by another `SecurityMiddleware` in a pipeline specific to the route, which replaces the policy
+/// of the outer one. Headers which the response already has are replaced, unless
+/// `preserve_handler_headers` is set.
+///
+/// Values must be valid header values; the builder methods panic otherwise.
+#[derive(Clone, Debug)]
+pub struct SecurityPolicy {
+    content_type_options: bool,
+    frame_options: Option<HeaderValue>,
+    hsts: Option<Hsts>,
+    csp: Option<String>,
+    csp_report_only: bool,
+    referrer_policy: Option<HeaderValue>,
+    permissions_policy: Option<HeaderValue>,
+    opener_policy: Option<HeaderValue>,
+    embedder_policy: Option<HeaderValue>,
+    resource_policy: Option<HeaderValue>,
+    preserve_handler_headers: bool,
+}
+
+impl Default for SecurityPolicy {
+    fn default() -> SecurityPolicy {
+        SecurityPolicy {
+            content_type_options: true,
+            frame_options: Some(HeaderValue::from_static(XFO_VALUE)),
+            hsts: None,
+            csp: None,
+            csp_report_only: false,
+            referrer_policy: Some(HeaderValue::from_static(REFERRER_POLICY_VALUE)),
+            permissions_policy: None,
+            opener_policy: None,
+            embedder_policy: None,
+            resource_policy: None,
+            preserve_handler_headers: false,
+        }
+    }
+}
+
+fn header_value<'a>(value: impl Into<Option<&'a str>>) -> Option<HeaderValue> {
+    value
+        .into()
+        .map(|value| HeaderValue::from_str(value).expect("valid header value"))
+}
+
+impl SecurityPolicy {
+    /// Creates the default policy, described in the module documentation.
+    pub fn new() -> SecurityPolicy {
+        SecurityPolicy::default()
+    }
+
+    /// Replaces the policy stored in `State` by `SecurityMiddleware` with the result of `f`, for
+    /// the current request only.
+    ///
+    /// ```rust,ignore
+    /// fn embeddable(mut state: State) -> (State, Response<Body>) {
+    ///     SecurityPolicy::update(&mut state, |policy| policy.frame_options("SAMEORIGIN"));
+    ///     // ...
+    /// }
+    /// ```
+    pub fn update<F>(state: &mut State, f: F)
+    where
+        F: FnOnce(SecurityPolicy) -> SecurityPolicy,
+    {
+        if let Some(policy) = SecurityPolicy::try_take_from(state) {
+            state.put(f(policy));
+        }
+    }
+
+    /// Sets whether to send `X-Content-Type-Options: nosniff`.
+    pub fn content_type_options(mut self, nosniff: bool) -> SecurityPolicy {
+        self.content_type_options = nosniff;
+        self
+    }
+
+    /// Sets `X-Frame-Options`, such as `DENY` or `SAMEORIGIN`, or removes it with `None`.
+    pub fn frame_options<'a>(mut self, value: impl Into<Option<&'a str>>) -> SecurityPolicy {
+        self.frame_options = header_value(value);
+        self
+    }
+
+    /// Sets `Strict-Transport-Security`, or removes it with `None`.
+    pub fn strict_transport_security(mut self, hsts: impl Into<Option<Hsts>>) -> SecurityPolicy {
+        self.hsts = hsts.into();
+        self
+    }
+
+    /// Sets `Content-Security-Policy`, or removes it with `None`. Each `NONCE_PLACEHOLDER` in
+    /// `policy` is replaced by a nonce generated for the request, which is stored in `State` as
+    /// `CspNonce`:
+    ///
+    /// ```rust,ignore
+    /// SecurityPolicy::new().content_security_policy("default-src 'self'; script-src {nonce}")
+    /// ```
+    pub fn content_security_policy<'a>(
+        mut self,
+        policy: impl Into<Option<&'a str>>,
+    ) -> SecurityPolicy {
+        self.csp = policy.into().map(|policy| {
+            // Make sure the policy is a valid header value, whatever the nonce.
+            header_value(policy.replace(NONCE_PLACEHOLDER, "'nonce-'").as_str());
+            policy.to_owned()
+        });
+        self
+    }
+
+    /// Sends the `Content-Security-Policy` as `Content-Security-Policy-Report-Only`, so that
+    /// violations are reported without being blocked.
+    pub fn content_security_policy_report_only(mut self, report_only: bool) -> SecurityPolicy {
+        self.csp_report_only = report_only;
+        self
+    }
+
+    /// Sets `Referrer-Policy`, or removes it with `None`.
+    pub fn referrer_policy<'a>(mut self, value: impl Into<Option<&'a str>>) -> SecurityPolicy {
+        self.referrer_policy = header_value(value);
+        self
+    }
+
+    /// Sets `Permissions-Policy`, such as `camera=(), geolocation=(self)`, or removes it with
+    /// `None`.
+    pub fn permissions_policy<'a>(mut self, value: impl Into<Option<&'a str>>) -> SecurityPolicy {
+        self.permissions_policy = header_value(value);
+        self
+    }
+
+    /// Sets `Cross-Origin-Opener-Policy`, such as `same-origin`, or removes it with `None`.
+    pub fn cross_origin_opener_policy<'a>(
+        mut self,
+        value: impl Into<Option<&'a str>>,
+    ) -> SecurityPolicy {
+        self.opener_policy = header_value(value);
+        self
+    }
+
+    /// Sets `Cross-Origin-Embedder-Policy`, such as `require-corp`, or removes it with `None`.
+    pub fn cross_origin_embedder_policy<'a>(
+        mut self,
+        value: impl Into<Option<&'a str>>,
+    ) -> SecurityPolicy {
+        self.embedder_policy = header_value(value);
+        self
+    }
+
+    /// Sets `Cross-Origin-Resource-Policy`, such as `same-site`, or removes it with `None`.
+    pub fn cross_origin_resource_policy<'a>(
+        mut self,
+        value: impl Into<Option<&'a str>>,
+    ) -> SecurityPolicy {
+        self.resource_policy = header_value(value);
+        self
+    }
+
+    /// Sets whether headers which the response already has, e.g. because the handler set them, are
+    /// left as they are rather than replaced by those of this policy.
+    pub fn preserve_handler_headers(mut self, preserve: bool) -> SecurityPolicy {
+        self.preserve_handler_headers = preserve;
+        self
+    }
+
+    fn uses_nonce(&self) -> bool {
+        self.csp
+            .as_ref()
+            .is_some_and(|csp| csp.contains(NONCE_PLACEHOLDER))
+    }
+
+    /// Adds the headers of this policy to `headers`, replacing those present already unless
+    /// `preserve_handler_headers` is set.
+    fn apply(&self, headers: &mut HeaderMap, nonce: Option<&CspNonce>) {
+        let preserve = self.preserve_handler_headers;
+        let mut set = |name: HeaderName, value: Option<HeaderValue>| match value {
+            Some(value) if preserve => {
+                headers.entry(name).or_insert(value);
+            }
+            Some(value) => {
+                headers.insert(name, value);
+            }
+            None => {}
+        };
+
+        set(
+            X_CONTENT_TYPE_OPTIONS,
+            self.content_type_options
+                .then(|| HeaderValue::from_static(XCTO_VALUE)),
+        );
+        set(X_FRAME_OPTIONS, self.frame_options.clone());
+        set(
+            STRICT_TRANSPORT_SECURITY,
+            self.hsts.map(|hsts| hsts.header_value()),
+        );
+
+        let csp = self.csp.as_ref().and_then(|csp| {
+            let nonce = nonce.map(|nonce| format!("'nonce-{}'", nonce.as_str()));
+            HeaderValue::from_str(&csp.replace(NONCE_PLACEHOLDER, nonce.as_deref().unwrap_or("")))
+                .ok()
+        });
+        if self.csp_report_only {
+            set(CONTENT_SECURITY_POLICY_REPORT_ONLY, csp);
+        } else {
+            set(CONTENT_SECURITY_POLICY, csp);
+        }
+
+        set(REFERRER_POLICY, self.referrer_policy.clone());
+        set(
+            HeaderName::from_static(PERMISSIONS_POLICY),
+            self.permissions_policy.clone(),
+        );
+        set(
+            HeaderName::from_static(CROSS_ORIGIN_OPENER_POLICY),
+            self.opener_policy.clone(),
+        );
+        set(
+            HeaderName::from_static(CROSS_ORIGIN_EMBEDDER_POLICY),
+            self.embedder_policy.clone(),
+        );
+        set(
+            HeaderName::from_static(CROSS_ORIGIN_RESOURCE_POLICY),
+            self.resource_policy.clone(),
+        );
+    }
+}
+
+/// Middleware binding for the Gotham security handlers, adding the headers of a
+/// `SecurityPolicy` to every response.
+///
+/// ```rust,ignore
+/// let policy = SecurityPolicy::new()
+///     .strict_transport_security(Hsts::new(Duration::from_secs(31_536_000)).include_subdomains())
+///     .content_security_policy("default-src 'self'; script-src 'self' {nonce}")
+///     .cross_origin_opener_policy("same-origin");
+///
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(SecurityMiddleware::with_policy(policy))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SecurityMiddleware {
+    policy: SecurityPolicy,
+}
+
+impl SecurityMiddleware {
+    /// Creates a `SecurityMiddleware` using the default `SecurityPolicy`.
+    pub fn new() -> SecurityMiddleware {
+        SecurityMiddleware::default()
+    }
+
+    /// Creates a `SecurityMiddleware` using `policy`.
+    pub fn with_policy(policy: SecurityPolicy) -> SecurityMiddleware {
+        SecurityMiddleware { policy }
+    }
+}
+
+impl From<SecurityPolicy> for SecurityMiddleware {
+    fn from(policy: SecurityPolicy) -> SecurityMiddleware {
+        SecurityMiddleware::with_policy(policy)
+    }
+}
+
+/// Stores a `CspNonce` in `state` if `policy` needs one and there isn't one already.
+fn ensure_nonce(state: &mut State, policy: &SecurityPolicy) {
+    if policy.uses_nonce() && !state.has::<CspNonce>() {
+        state.put(CspNonce::generate());
+    }
+}
 
 /// `Middleware` trait implementation.
 impl Middleware for SecurityMiddleware {
     /// Attaches security headers to the response.
-    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
     where
         Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
     {
-        let f = chain(state).and_then(|(state, mut response)| {
-            {
-                let headers = response.headers_mut();
+        ensure_nonce(&mut state, &self.policy);
+
+        // An outer `SecurityMiddleware` adds the headers; this one only overrides its policy.
+        if state.has::<SecurityPolicy>() {
+            state.put(self.policy);
+            return chain(state);
+        }
 
-                headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static(XFO_VALUE));
-                headers.insert(X_XSS_PROTECTION, HeaderValue::from_static(XXP_VALUE));
-                headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static(XCTO_VALUE));
+        state.put(self.policy);
+        let f = chain(state).and_then(|(mut state, mut response)| {
+            if let Some(policy) = SecurityPolicy::try_take_from(&mut state) {
+                ensure_nonce(&mut state, &policy);
+                policy.apply(response.headers_mut(), CspNonce::try_borrow_from(&state));
             }
             future::ok((state, response))
         });
@@ -63,3 +416,156 @@ impl MiddlewareBuild for SecurityMiddleware {
         Ok(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use hyper::{Request, Response, StatusCode};
+    use std::net::SocketAddr;
+
+    fn state() -> State {
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    fn respond(state: State) -> Pin<Box<HandlerFuture>> {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(X_FRAME_OPTIONS, "SAMEORIGIN")
+            .body(Body::empty())
+            .unwrap();
+        future::ok((state, response)).boxed()
+    }
+
+    #[tokio::test]
+    async fn adds_default_headers() {
+        let (_, res) = SecurityMiddleware::new()
+            .call(state(), |state| {
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::OK;
+                future::ok((state, res)).boxed()
+            })
+            .await
+            .ok()
+            .unwrap();
+
+        let headers = res.headers();
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[REFERRER_POLICY], "strict-origin-when-cross-origin");
+        assert!(!headers.contains_key("x-xss-protection"));
+        assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+    }
+
+    #[tokio::test]
+    async fn adds_configured_headers_with_a_nonce() {
+        let policy = SecurityPolicy::new()
+            .strict_transport_security(
+                Hsts::new(Duration::from_secs(3600))
+                    .include_subdomains()
+                    .preload(),
+            )
+            .content_security_policy("script-src 'self' {nonce}; style-src {nonce}")
+            .permissions_policy("camera=()")
+            .cross_origin_opener_policy("same-origin")
+            .cross_origin_embedder_policy("require-corp")
+            .cross_origin_resource_policy("same-site");
+
+        let (state, res) = SecurityMiddleware::with_policy(policy)
+            .call(state(), |state| {
+                assert!(state.has::<CspNonce>());
+                respond(state)
+            })
+            .await
+            .ok()
+            .unwrap();
+
+        let nonce = CspNonce::borrow_from(&state).as_str();
+        assert_eq!(STANDARD.decode(nonce).unwrap().len(), 16);
+
+        let headers = res.headers();
+        assert_eq!(
+            headers[STRICT_TRANSPORT_SECURITY],
+            "max-age=3600; includeSubDomains; preload"
+        );
+        assert_eq!(
+            headers[CONTENT_SECURITY_POLICY],
+            format!(
+                "script-src 'self' 'nonce-{0}'; style-src 'nonce-{0}'",
+                nonce
+            )
+        );
+        assert_eq!(headers[PERMISSIONS_POLICY], "camera=()");
+        assert_eq!(headers[CROSS_ORIGIN_OPENER_POLICY], "same-origin");
+        assert_eq!(headers[CROSS_ORIGIN_EMBEDDER_POLICY], "require-corp");
+        assert_eq!(headers[CROSS_ORIGIN_RESOURCE_POLICY], "same-site");
+        // Headers set by the handler are replaced.
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[tokio::test]
+    async fn preserves_handler_headers() {
+        let policy = SecurityPolicy::new().preserve_handler_headers(true);
+
+        let (_, res) = SecurityMiddleware::with_policy(policy)
+            .call(state(), respond)
+            .await
+            .ok()
+            .unwrap();
+
+        let headers = res.headers();
+        assert_eq!(headers[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+    }
+
+    #[tokio::test]
+    async fn routes_override_the_policy() {
+        let outer = SecurityMiddleware::with_policy(
+            SecurityPolicy::new().content_security_policy("default-src 'self'"),
+        );
+
+        // A handler relaxing the policy.
+        let (_, res) = outer
+            .clone()
+            .call(state(), |mut state| {
+                SecurityPolicy::update(&mut state, |policy| {
+                    policy
+                        .content_security_policy_report_only(true)
+                        .referrer_policy(None)
+                });
+                let res = Response::new(Body::empty());
+                future::ok((state, res)).boxed()
+            })
+            .await
+            .ok()
+            .unwrap();
+
+        let headers = res.headers();
+        assert_eq!(
+            headers[CONTENT_SECURITY_POLICY_REPORT_ONLY],
+            "default-src 'self'"
+        );
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert!(!headers.contains_key(REFERRER_POLICY));
+
+        // A route specific pipeline replacing the policy.
+        let inner = SecurityMiddleware::with_policy(SecurityPolicy::new().frame_options(None));
+        let (_, res) = outer
+            .call(state(), |state| {
+                inner.call(state, |state| {
+                    let res = Response::new(Body::empty());
+                    future::ok((state, res)).boxed()
+                })
+            })
+            .await
+            .ok()
+            .unwrap();
+
+        let headers = res.headers();
+        assert!(!headers.contains_key(X_FRAME_OPTIONS));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+    }
+}
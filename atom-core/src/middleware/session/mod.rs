@@ -36,6 +36,9 @@ const HOST_COOKIE_PREFIX: &str = "__Host-";
 const SESSION_DEFAULT_SIZE: Bounded = Bounded(4096);
 
 /// Represents the session identifier which is held in the user agent's session cookie.
+///
+/// `SessionMiddleware` stores the identifier of the current session in `State`, for middleware
+/// which needs to tell sessions apart without knowing the session type, such as `CsrfMiddleware`.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SessionIdentifier {
     /// The value which is passed as a cookie, identifying the session.
@@ -995,6 +998,7 @@ where
 
                 let session_data = SessionData::<T>::construct(self, identifier, v);
 
+                state.put(session_data.identifier.clone());
                 state.put(session_data);
                 future::ok(state)
             }
@@ -1028,6 +1032,7 @@ where
             session_data.identifier.value
         );
 
+        state.put(session_data.identifier.clone());
         state.put(session_data);
 
         future::ok(state)
@@ -0,0 +1,201 @@
+//! Defines `TimeoutMiddleware`, which cancels requests that take too long.
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::future::FutureExt;
+use hyper::StatusCode;
+use log::debug;
+use tokio::time::{timeout_at, Instant};
+
+use crate::handler::HandlerFuture;
+use crate::helpers::http::response::create_empty_response;
+use crate::middleware::{Middleware, MiddlewareBuild};
+use crate::state::{request_id, FromState, State};
+
+/// The time by which the current request must be answered, as set by `TimeoutMiddleware`.
+///
+/// Handlers can pass the remaining time on to the services they call, so those calls are
+/// abandoned along with the request:
+///
+/// ```rust,ignore
+/// let mut request = client.get(url);
+/// if let Some(deadline) = Deadline::try_borrow_from(&state) {
+///     request = request.timeout(deadline.remaining());
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// The instant at which the request is cancelled.
+    pub fn instant(&self) -> std::time::Instant {
+        self.0.into_std()
+    }
+
+    /// The time left to answer the request, or zero once it's passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Cancels the rest of the pipeline and the handler when they don't produce a response before a
+/// deadline, and responds with `503 Service Unavailable` instead, or another status configured
+/// with `status`. The response passes through the `ResponseFinalizer` like any other, so response
+/// extenders registered for the status apply to it.
+///
+/// The deadline is stored in `State` as `Deadline`. When several `TimeoutMiddleware` apply to a
+/// request, such as one in a pipeline shared by every route and another in the pipeline of a
+/// slow route group, the earliest deadline wins.
+///
+/// Only the time until the response is produced is limited; a streaming body may take longer to
+/// send. Once a request is cancelled, its `State` is gone, so the response is created from a copy
+/// of the request line, headers and client details taken beforehand.
+///
+/// ```rust,ignore
+/// let (chain, pipelines) = single_pipeline(
+///     new_pipeline()
+///         .add(TimeoutMiddleware::new(Duration::from_secs(5)).status(StatusCode::GATEWAY_TIMEOUT))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct TimeoutMiddleware {
+    timeout: Duration,
+    status: StatusCode,
+}
+
+impl TimeoutMiddleware {
+    /// Creates a `TimeoutMiddleware` cancelling requests which take longer than `timeout`.
+    pub fn new(timeout: Duration) -> TimeoutMiddleware {
+        TimeoutMiddleware {
+            timeout,
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Sets the status of the response to cancelled requests, usually `504 Gateway Timeout` when
+    /// handlers mostly wait on upstream services.
+    ///
+    /// # Panics
+    ///
+    /// If `status` isn't a server error.
+    pub fn status(mut self, status: StatusCode) -> TimeoutMiddleware {
+        assert!(
+            status.is_server_error(),
+            "timeouts must respond with a server error"
+        );
+        self.status = status;
+        self
+    }
+}
+
+/// `Middleware` trait implementation.
+impl Middleware for TimeoutMiddleware {
+    /// Runs the chain until the deadline, and responds with an error once it passes.
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        let mut deadline = Deadline(Instant::now() + self.timeout);
+        if let Some(outer) = Deadline::try_borrow_from(&state) {
+            deadline = deadline.min(*outer);
+        }
+        state.put(deadline);
+
+        let fork = state.fork_request();
+        let f = timeout_at(deadline.0, chain(state));
+
+        async move {
+            match f.await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!("[{}] request timed out, cancelling", request_id(&fork));
+                    let res = create_empty_response(&fork, self.status);
+                    Ok((fork, res))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// `NewMiddleware` trait implementation.
+impl MiddlewareBuild for TimeoutMiddleware {
+    type Instance = Self;
+
+    /// Clones the current middleware to a new instance.
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use futures_util::future;
+    use hyper::{Request, Response};
+    use std::net::SocketAddr;
+
+    fn new_state() -> State {
+        let req = Request::get("/slow").body(Body::empty()).unwrap();
+        State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap())
+    }
+
+    fn sleep_for(secs: u64) -> impl FnOnce(State) -> Pin<Box<HandlerFuture>> {
+        move |state| {
+            async move {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                Ok((state, Response::new(Body::from("done"))))
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancels_slow_requests() {
+        let state = new_state();
+        let id = request_id(&state).to_owned();
+
+        let timeout = TimeoutMiddleware::new(Duration::from_secs(5));
+        let (state, res) = timeout
+            .clone()
+            .call(state, sleep_for(10))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(request_id(&state), id);
+
+        let timeout = timeout.status(StatusCode::GATEWAY_TIMEOUT);
+        let (_, res) = timeout
+            .clone()
+            .call(new_state(), sleep_for(10))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let (_, res) = timeout.call(new_state(), sleep_for(1)).await.ok().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_earliest_deadline() {
+        let outer = TimeoutMiddleware::new(Duration::from_secs(5));
+        let inner = TimeoutMiddleware::new(Duration::from_secs(60));
+
+        let (_, res) = outer
+            .call(new_state(), move |state| {
+                inner.call(state, |state| {
+                    let remaining = Deadline::borrow_from(&state).remaining();
+                    assert_eq!(remaining, Duration::from_secs(5));
+                    future::ok((state, Response::new(Body::empty()))).boxed()
+                })
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
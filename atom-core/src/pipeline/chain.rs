@@ -2,6 +2,7 @@
 //! dispatcher for a route.
 
 use futures_util::future::{self, FutureExt};
+use hyper::Method;
 use log::trace;
 use std::panic::RefUnwindSafe;
 use std::pin::Pin;
@@ -32,6 +33,12 @@ pub trait PipelineHandleChain<P>: RefUnwindSafe {
     fn call<F>(&self, pipelines: &PipelineSet<P>, state: State, f: F) -> Pin<Box<HandlerFuture>>
     where
         F: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static;
+
+    /// Returns the method claimed by the `Middleware` in these pipelines for an `OPTIONS` request
+    /// which no route accepts, see `MiddlewareBuild::options_method`.
+    fn options_method(&self, _pipelines: &PipelineSet<P>, _state: &State) -> Option<Method> {
+        None
+    }
 }
 
 /// Part of a `PipelineHandleChain` which references a `Pipeline` and continues with a tail element.
@@ -56,6 +63,14 @@ where
             }
         }
     }
+
+    fn options_method(&self, pipelines: &PipelineSet<P>, state: &State) -> Option<Method> {
+        let (handle, ref chain) = *self;
+        pipelines
+            .borrow(handle)
+            .options_method(state)
+            .or_else(|| chain.options_method(pipelines, state))
+    }
 }
 
 /// The marker for the end of a `PipelineHandleChain`. 末尾
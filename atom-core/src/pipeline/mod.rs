@@ -9,6 +9,7 @@ pub use set::{finalize_pipeline_set, new_pipeline_set, EditablePipelineSet, Pipe
 mod single;
 pub use single::{single_pipeline, SinglePipelineChain, SinglePipelineHandle, SinglePipelineSet};
 
+use hyper::Method;
 use log::trace;
 use std::pin::Pin;
 
@@ -149,6 +150,12 @@ where
             chain: self.chain.construct()?,
         })
     }
+
+    /// Returns the method claimed by this `Pipeline` for an `OPTIONS` request which no route
+    /// accepts, see `MiddlewareBuild::options_method`.
+    fn options_method(&self, state: &State) -> Option<Method> {
+        self.chain.options_method(state)
+    }
 }
 
 impl<T> PipelineInstance<T>
@@ -1,9 +1,12 @@
-use futures_util::future;
-use log::info;
+use std::future::Future;
 use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
 
 use super::handler::NewHandler;
-use super::{bind_server, new_runtime, tcp_listener, StartError};
+use super::server::{IntoListener, ServerBuilder};
+use super::StartError;
 
 #[cfg(feature = "testing")]
 pub mod test;
@@ -27,8 +30,9 @@ where
     NH: NewHandler + 'static,
     A: ToSocketAddrs + 'static + Send,
 {
-    let runtime = new_runtime(threads);
-    runtime.block_on(init_server(addr, new_handler))
+    ServerBuilder::new()
+        .threads(threads)
+        .start(addr, new_handler)
 }
 
 /// Returns a `Future` used to spawn an Gotham application.
@@ -36,20 +40,79 @@ where
 /// This is used internally, but exposed in case the developer intends on doing any
 /// manual wiring that isn't supported by the Gotham API. It's unlikely that this will
 /// be required in most use cases; it's mainly exposed for shutdown handling.
+///
+/// Use `ServerBuilder::init_server` to configure timeouts and limits.
 pub async fn init_server<NH, A>(addr: A, new_handler: NH) -> Result<(), StartError>
 where
     NH: NewHandler + 'static,
     A: ToSocketAddrs + 'static + Send,
 {
-    let listener = tcp_listener(addr).await?;
-    let addr = listener.local_addr().unwrap();
+    ServerBuilder::new().init_server(addr, new_handler).await
+}
 
-    info! {
-        target: "gotham::start",
-        " Gotham listening on http://{}", addr
-    }
+/// Starts a Gotham application on plain HTTP over a Unix domain socket bound at `path`.
+///
+/// The client address stored in `State` is a `ClientAddr::Unix`, carrying the peer's credentials
+/// where the platform provides them.
+#[cfg(unix)]
+pub fn start_unix<NH, P>(path: P, new_handler: NH) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    P: AsRef<Path>,
+{
+    ServerBuilder::new().start_unix(path, new_handler)
+}
 
-    bind_server(listener, new_handler, future::ok).await
+/// Starts a Gotham application on plain HTTP, accepting connections from a listener which has
+/// already been bound, e.g. a `std::net::TcpListener` passed in by systemd socket activation.
+pub fn start_with_listener<NH, L>(listener: L, new_handler: NH) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    L: IntoListener,
+{
+    ServerBuilder::new().start_with_listener(listener, new_handler)
+}
+
+/// Starts a Gotham application on plain, unsecured HTTP, which shuts down gracefully once
+/// `shutdown` resolves.
+///
+/// In-flight requests are given up to `drain_timeout` to complete before their connections are
+/// closed, see `bind_server_with_shutdown`. Returns `Ok(())` once the server has stopped.
+pub fn start_with_graceful_shutdown<NH, A, S>(
+    addr: A,
+    new_handler: NH,
+    shutdown: S,
+    drain_timeout: Duration,
+) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    A: ToSocketAddrs + 'static + Send,
+    S: Future<Output = ()> + Send + 'static,
+{
+    ServerBuilder::new()
+        .graceful_shutdown(shutdown)
+        .shutdown_timeout(drain_timeout)
+        .start(addr, new_handler)
+}
+
+/// Returns a `Future` used to spawn a Gotham application which shuts down gracefully once
+/// `shutdown` resolves.
+pub async fn init_server_with_graceful_shutdown<NH, A, S>(
+    addr: A,
+    new_handler: NH,
+    shutdown: S,
+    drain_timeout: Duration,
+) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    A: ToSocketAddrs + 'static + Send,
+    S: Future<Output = ()> + Send + 'static,
+{
+    ServerBuilder::new()
+        .graceful_shutdown(shutdown)
+        .shutdown_timeout(drain_timeout)
+        .init_server(addr, new_handler)
+        .await
 }
 
 // #[cfg(test)]
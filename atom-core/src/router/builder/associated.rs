@@ -314,6 +314,8 @@ where
             matcher: AndRouteMatcher::new(MethodOnlyRouteMatcher::new(methods), matcher.clone()),
             pipeline_chain: *pipeline_chain,
             pipelines: pipelines.clone(),
+            body_limit: None,
+            body_extractor: None,
             phantom,
         }
     }
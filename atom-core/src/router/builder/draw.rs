@@ -469,6 +469,8 @@ where
             node_builder,
             pipeline_chain: *pipeline_chain,
             pipelines: pipelines.clone(),
+            body_limit: None,
+            body_extractor: None,
             phantom: PhantomData,
         }
     }
@@ -10,6 +10,7 @@ use std::panic::RefUnwindSafe;
 
 use hyper::{StatusCode};
 use crate::body::Body;
+use crate::extractor::form::BodyExtraction;
 use crate::extractor::path::{NoopPathExtractor, PathExtractor};
 use crate::extractor::query_string::{NoopQueryStringExtractor, QueryStringExtractor};
 
@@ -289,6 +290,8 @@ where
     matcher: M,
     pipeline_chain: C,
     pipelines: PipelineSet<P>,
+    body_limit: Option<usize>,
+    body_extractor: Option<BodyExtraction>,
     phantom: PhantomData<(PE, QSE)>,
 }
 
@@ -313,6 +316,8 @@ where
             matcher: self.matcher,
             pipeline_chain: self.pipeline_chain,
             pipelines: self.pipelines,
+            body_limit: self.body_limit,
+            body_extractor: self.body_extractor,
             phantom: PhantomData,
         }
     }
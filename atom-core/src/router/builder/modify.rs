@@ -106,6 +106,8 @@ where
             node_builder: self.node_builder,
             pipeline_chain: self.pipeline_chain,
             pipelines: self.pipelines,
+            body_limit: self.body_limit,
+            body_extractor: self.body_extractor,
         }
     }
 }
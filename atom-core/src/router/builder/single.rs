@@ -4,6 +4,7 @@ use std::future::Future;
 use std::panic::RefUnwindSafe;
 use std::pin::Pin;
 use crate::body::Body;
+use crate::extractor::form::{extract_form, FormExtractor};
 use crate::extractor::path::PathExtractor;
 use crate::extractor::query_string::QueryStringExtractor;
 
@@ -561,6 +562,87 @@ pub trait DefineSingleRoute {
         NRM: RouteMatcher + Send + Sync + 'static,
         Self: ExtendRouteMatcher<NRM>,
         Self::Output: DefineSingleRoute;
+
+    /// Limits the size of request bodies accepted by the current route to `limit` bytes,
+    /// replacing the limit set for the whole server via `ServerBuilder::max_body_size`.
+    ///
+    /// Requests declaring a larger `Content-Length` receive `413 Payload Too Large` without the
+    /// handler being invoked. Bodies sent without a length stop being read once the limit is
+    /// exceeded, and `Body::to_bytes` fails with an error which becomes a `413` response.
+    ///
+    /// ```
+    /// # use hyper::{Response, StatusCode};
+    /// # use gotham::body::Body;
+    /// # use gotham::state::{FromState, State};
+    /// # use gotham::router::Router;
+    /// # use gotham::router::builder::*;
+    /// # use gotham::handler::HandlerError;
+    /// #
+    /// async fn upload(state: &mut State) -> Result<Response<Body>, HandlerError> {
+    ///     let bytes = Body::take_from(state).to_bytes().await?;
+    /// #   let _ = bytes;
+    ///     // Handler implementation elided.
+    /// #   Ok(Response::builder().status(StatusCode::ACCEPTED).body(Body::empty()).unwrap())
+    /// }
+    ///
+    /// # fn router() -> Router {
+    /// build_simple_router(|route| {
+    ///     route.post("/upload")
+    ///          .with_body_limit(16 * 1024 * 1024)
+    ///          .to_async_borrowing(upload);
+    /// })
+    /// # }
+    /// # fn main() { router(); }
+    /// ```
+    fn with_body_limit(self, limit: usize) -> Self
+    where
+        Self: Sized;
+
+    /// Applies a `FormExtractor` type to the current route, to deserialize an
+    /// `application/x-www-form-urlencoded` request body into `State` before the handler is
+    /// invoked.
+    ///
+    /// The body is decoded with the same rules as the query string, so repeated keys can be
+    /// collected into a `Vec<T>`. Requests with another `Content-Type` receive
+    /// `415 Unsupported Media Type`, and bodies which fail to deserialize are passed to the
+    /// `StaticResponseExtender` of the extractor type, without the handler being invoked.
+    ///
+    /// ```
+    /// # use hyper::{Response, StatusCode};
+    /// # use gotham::body::Body;
+    /// # use gotham::state::{FromState, State};
+    /// # use gotham::router::Router;
+    /// # use gotham::router::builder::*;
+    /// # use gotham::prelude::*;
+    /// # use serde::Deserialize;
+    /// #
+    /// #[derive(Deserialize, StateData, StaticResponseExtender)]
+    /// struct Signup {
+    ///     name: String,
+    ///     interests: Vec<String>,
+    /// }
+    ///
+    /// fn signup(mut state: State) -> (State, Response<Body>) {
+    ///     let form = Signup::take_from(&mut state);
+    /// #   assert_eq!(form.name, "Jane");
+    /// #   assert_eq!(form.interests, vec!["rust", "http"]);
+    ///     // Handler implementation elided.
+    /// #   (state, Response::builder().status(StatusCode::ACCEPTED).body(Body::empty()).unwrap())
+    /// }
+    ///
+    /// # fn router() -> Router {
+    /// build_simple_router(|route| {
+    ///     route.post("/signup")
+    ///          .with_form_extractor::<Signup>()
+    ///          .to(signup);
+    /// })
+    /// # }
+    /// # fn main() { router(); }
+    /// ```
+    fn with_form_extractor<FE>(self) -> Self
+    where
+        FE: FormExtractor<Body> + Send + Sync + 'static,
+        Self: Sized;
 }
 
 impl<'a, M, C, P, PE, QSE> DefineSingleRoute for SingleRouteBuilder<'a, M, C, P, PE, QSE>
@@ -599,14 +681,21 @@ where
     where
         NH: NewHandler + 'static,
     {
-        let dispatcher = DispatcherImpl::new(new_handler, self.pipeline_chain, self.pipelines);
-        let route: RouteImpl<M, PE, QSE> = RouteImpl::new(
-            self.matcher,
-            Box::new(dispatcher),
-            Extractors::new(),
-            Delegation::Internal,
-        );
-        self.node_builder.add_route(Box::new(route));
+        match self.body_extractor {
+            Some(extract) => self.add_route(move || {
+                let handler = new_handler.new_handler()?;
+                Ok(move |state: State| {
+                    async move {
+                        match extract(state).await {
+                            Ok(state) => handler.handle(state).await,
+                            Err((state, res)) => Ok((state, res)),
+                        }
+                    }
+                    .boxed()
+                })
+            }),
+            None => self.add_route(new_handler),
+        }
     }
 
     fn with_path_extractor<NPE>(self) -> <Self as ReplacePathExtractor<NPE>>::Output
@@ -631,4 +720,45 @@ where
     {
         self.extend_route_matcher(matcher)
     }
+
+    fn with_body_limit(self, limit: usize) -> Self {
+        SingleRouteBuilder {
+            body_limit: Some(limit),
+            ..self
+        }
+    }
+
+    fn with_form_extractor<FE>(self) -> Self
+    where
+        FE: FormExtractor<Body> + Send + Sync + 'static,
+    {
+        SingleRouteBuilder {
+            body_extractor: Some(extract_form::<FE>),
+            ..self
+        }
+    }
+}
+
+impl<'a, M, C, P, PE, QSE> SingleRouteBuilder<'a, M, C, P, PE, QSE>
+where
+    M: RouteMatcher + Send + Sync + 'static,
+    C: PipelineHandleChain<P> + Send + Sync + 'static,
+    P: RefUnwindSafe + Send + Sync + 'static,
+    PE: PathExtractor<Body> + Send + Sync + 'static,
+    QSE: QueryStringExtractor<Body> + Send + Sync + 'static,
+{
+    fn add_route<NH>(self, new_handler: NH)
+    where
+        NH: NewHandler + 'static,
+    {
+        let dispatcher = DispatcherImpl::new(new_handler, self.pipeline_chain, self.pipelines);
+        let route: RouteImpl<M, PE, QSE> = RouteImpl::new(
+            self.matcher,
+            Box::new(dispatcher),
+            Extractors::new(),
+            Delegation::Internal,
+        )
+        .with_body_limit(self.body_limit);
+        self.node_builder.add_route(Box::new(route));
+    }
 }
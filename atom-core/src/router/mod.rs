@@ -10,12 +10,15 @@ pub mod tree;
 mod non_match;
 pub use self::non_match::RouteNonMatch;
 
+mod options;
+pub use self::options::OptionsFallback;
+
 use std::pin::Pin;
 use std::sync::Arc;
 
 use futures_util::future::{self, FutureExt, TryFutureExt};
 use hyper::header::ALLOW;
-use hyper::{Response, StatusCode};
+use hyper::{Method, Response, StatusCode};
 use log::{error, trace};
 use crate::body::Body;
 
@@ -78,7 +81,27 @@ impl Handler for Router {
         let future = match state.try_take::<RequestPathSegments>() {
             Some(rps) => {
                 if let Some((node, params, processed)) = self.data.tree.traverse(rps.segments()) {
-                    match node.select_route(&state) {
+                    let selected = match node.select_route(&state) {
+                        Ok(route) => Ok(route.as_ref()),
+                        Err(non_match) if *state.borrow::<Method>() == Method::OPTIONS => {
+                            // Middleware may answer `OPTIONS` requests for the routes they're in.
+                            match node.select_options_route(&mut state) {
+                                Some((route, method)) => {
+                                    trace!(
+                                        "[{}] dispatching OPTIONS fallback",
+                                        request_id(&state)
+                                    );
+                                    let (_, allow) = non_match.deconstruct();
+                                    state.put(OptionsFallback::new(method, allow));
+                                    Ok(route)
+                                }
+                                None => Err(non_match),
+                            }
+                        }
+                        Err(non_match) => Err(non_match),
+                    };
+
+                    match selected {
                         Ok(route) => match route.delegation() {
                             Delegation::External => {
                                 trace!("[{}] delegating to secondary router", request_id(&state));
@@ -137,7 +160,7 @@ impl Router {
         &self,
         mut state: State,
         params: SegmentMapping<'a>,
-        route: &Box<dyn Route<ResBody = Body> + Send + Sync>,
+        route: &(dyn Route<ResBody = Body> + Send + Sync),
     ) -> Pin<Box<HandlerFuture>> {
         match route.extract_request_path(&mut state, params) {
             Ok(()) => {
@@ -420,3 +443,98 @@ impl Router {
 //         };
 //     }
 // }
+
+#[cfg(test)]
+mod options_tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use hyper::header::HeaderMap;
+    use hyper::Request;
+
+    use crate::middleware::{Middleware, MiddlewareBuild};
+    use crate::pipeline::{new_pipeline, single_pipeline};
+    use crate::router::builder::{build_router, DefineSingleRoute, DrawRoutes};
+
+    /// Claims `OPTIONS` requests carrying `x-method`, and records whether it ran.
+    #[derive(Clone)]
+    struct Claims {
+        ran: Arc<AtomicBool>,
+    }
+
+    impl Middleware for Claims {
+        fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+        where
+            Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+        {
+            self.ran.store(true, Ordering::SeqCst);
+
+            if let Some(fallback) = state.try_take::<OptionsFallback>() {
+                let mut res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                let allow = fallback
+                    .allowed_methods()
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                res.headers_mut().insert(ALLOW, allow.parse().unwrap());
+                return future::ok((state, res)).boxed();
+            }
+
+            chain(state)
+        }
+    }
+
+    impl MiddlewareBuild for Claims {
+        type Instance = Self;
+
+        fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+            Ok(self.clone())
+        }
+
+        fn options_method(&self, state: &State) -> Option<Method> {
+            let value = state.borrow::<HeaderMap>().get("x-method")?;
+            value.to_str().ok()?.parse().ok()
+        }
+    }
+
+    fn handler(state: State) -> (State, Response<Body>) {
+        (state, Response::new(Body::empty()))
+    }
+
+    async fn options(router: &Router, method: Option<&str>) -> Response<Body> {
+        let mut req = Request::options("/things");
+        if let Some(method) = method {
+            req = req.header("x-method", method);
+        }
+
+        let req = req.body(Body::empty()).unwrap();
+        let state = State::from_request(req, "127.0.0.1:10000".parse::<SocketAddr>().unwrap());
+        let (_, res) = router.clone().handle(state).await.ok().unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn dispatches_claimed_options_requests() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let claims = Claims { ran: ran.clone() };
+        let (chain, pipelines) = single_pipeline(new_pipeline().add(claims).build());
+        let router = build_router(chain, pipelines, |route| {
+            route.get("/things").to(handler);
+            route.post("/things").to(handler);
+        });
+
+        let res = options(&router, Some("POST")).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ALLOW], "GET, POST");
+        assert!(ran.swap(false, Ordering::SeqCst));
+
+        // Unclaimed, or claimed for a method no route accepts: no pipeline runs.
+        for method in [None, Some("DELETE")] {
+            let res = options(&router, method).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert!(!ran.load(Ordering::SeqCst));
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Defines the type used to dispatch `OPTIONS` requests which no route accepts.
+
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Method, Response, StatusCode};
+
+use crate::body::Body;
+use crate::helpers::http::response::create_empty_response;
+use crate::state::State;
+
+/// An `OPTIONS` request which no route accepts, claimed by a `MiddlewareBuild` of the route
+/// accepting `method` through `MiddlewareBuild::options_method`.
+///
+/// The `Router` puts this in `State` before dispatching the request to that route, and the
+/// `Middleware` which claimed it takes it and answers the request, so the route's handler never
+/// sees it.
+#[derive(Clone, Debug)]
+pub struct OptionsFallback {
+    method: Method,
+    allowed_methods: Vec<Method>,
+}
+
+impl OptionsFallback {
+    pub(crate) fn new(method: Method, mut allowed_methods: Vec<Method>) -> OptionsFallback {
+        if !allowed_methods.contains(&method) {
+            allowed_methods.push(method.clone());
+        }
+
+        OptionsFallback {
+            method,
+            allowed_methods,
+        }
+    }
+
+    /// The method claimed for the request, whose route it was dispatched to.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// The methods registered for the requested path.
+    pub fn allowed_methods(&self) -> &[Method] {
+        &self.allowed_methods
+    }
+
+    /// The response to a request which reached the handler without being answered.
+    pub(crate) fn not_allowed(&self, state: &State) -> Response<Body> {
+        let mut res = create_empty_response(state, StatusCode::METHOD_NOT_ALLOWED);
+        for method in &self.allowed_methods {
+            if let Ok(value) = HeaderValue::from_str(method.as_str()) {
+                res.headers_mut().append(ALLOW, value);
+            }
+        }
+        res
+    }
+}
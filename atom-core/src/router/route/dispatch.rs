@@ -1,18 +1,26 @@
 //! Defines the route `Dispatcher` and supporting types.
 
 use futures_util::future::{self, FutureExt};
+use hyper::Method;
 use log::trace;
 use std::panic::RefUnwindSafe;
 use std::pin::Pin;
 
 use crate::handler::{Handler, HandlerFuture, NewHandler};
 use crate::pipeline::{PipelineHandleChain, PipelineSet};
+use crate::router::OptionsFallback;
 use crate::state::{request_id, State};
 
 /// Used by `Router` to dispatch requests via pipelines and finally into the configured `Handler`.
 pub trait Dispatcher: RefUnwindSafe {
     /// Dispatches a request via pipelines and `Handler` represented by this `Dispatcher`.
     fn dispatch(&self, state: State) -> Pin<Box<HandlerFuture>>;
+
+    /// Returns the method claimed by the pipelines of this `Dispatcher` for an `OPTIONS` request
+    /// which no route accepts, see `MiddlewareBuild::options_method`.
+    fn options_method(&self, _state: &State) -> Option<Method> {
+        None
+    }
 }
 
 /// Default implementation of the `Dispatcher` trait.
@@ -62,7 +70,15 @@ where
             Ok(h) => {
                 trace!("[{}] cloning handler", request_id(&state));
                 self.pipeline_chain
-                    .call(&self.pipelines, state, move |state| h.handle(state))
+                    .call(&self.pipelines, state, move |mut state| {
+                        // An `OPTIONS` request which the middleware claiming it didn't answer.
+                        if let Some(fallback) = state.try_take::<OptionsFallback>() {
+                            let res = fallback.not_allowed(&state);
+                            return future::ok((state, res)).boxed();
+                        }
+
+                        h.handle(state)
+                    })
             }
             Err(e) => {
                 trace!("[{}] error cloning handler", request_id(&state));
@@ -70,6 +86,10 @@ where
             }
         }
     }
+
+    fn options_method(&self, state: &State) -> Option<Method> {
+        self.pipeline_chain.options_method(&self.pipelines, state)
+    }
 }
 
 // #[cfg(test)]
@@ -5,12 +5,14 @@ mod access_control_request_method;
 mod and;
 mod any;
 mod content_type;
+mod websocket;
 
 pub use self::accept::AcceptHeaderRouteMatcher;
 pub use self::access_control_request_method::AccessControlRequestMethodMatcher;
 pub use self::and::AndRouteMatcher;
 pub use self::any::AnyRouteMatcher;
 pub use self::content_type::ContentTypeHeaderRouteMatcher;
+pub use self::websocket::WebSocketUpgradeRouteMatcher;
 
 mod lookup_table;
 use self::lookup_table::{LookupTable, LookupTableFromTypes};
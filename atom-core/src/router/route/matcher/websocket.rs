@@ -0,0 +1,82 @@
+//! Defines the `WebSocketUpgradeRouteMatcher`.
+
+use hyper::header::HeaderMap;
+use hyper::{Method, StatusCode};
+
+use crate::router::non_match::RouteNonMatch;
+use crate::router::route::matcher::RouteMatcher;
+use crate::state::{FromState, State};
+use crate::websocket::is_websocket_upgrade;
+
+/// A `RouteMatcher` that only matches `GET` requests asking to upgrade the connection to the
+/// WebSocket protocol, with `Connection: upgrade` and `Upgrade: websocket`.
+///
+/// Other requests are refused with `400 Bad Request`, so the same path can serve regular
+/// requests from another route:
+///
+/// ```rust,ignore
+/// build_simple_router(|route| {
+///     route
+///         .get("/chat")
+///         .add_route_matcher(WebSocketUpgradeRouteMatcher::new())
+///         .to_async_borrowing(chat_socket);
+///     route.get("/chat").to(chat_page);
+/// })
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WebSocketUpgradeRouteMatcher {
+    _priv: (),
+}
+
+impl WebSocketUpgradeRouteMatcher {
+    /// Creates a new `WebSocketUpgradeRouteMatcher`.
+    pub fn new() -> Self {
+        WebSocketUpgradeRouteMatcher::default()
+    }
+}
+
+impl RouteMatcher for WebSocketUpgradeRouteMatcher {
+    fn is_match(&self, state: &State) -> Result<(), RouteNonMatch> {
+        if Method::borrow_from(state) == Method::GET
+            && is_websocket_upgrade(state.borrow::<HeaderMap>())
+        {
+            Ok(())
+        } else {
+            Err(RouteNonMatch::new(StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use hyper::header::{CONNECTION, UPGRADE};
+    use hyper::Request;
+
+    fn is_match(method: Method, connection: &str, upgrade: &str) -> bool {
+        let req = Request::builder()
+            .method(method)
+            .uri("http://localhost/ws")
+            .header(CONNECTION, connection)
+            .header(UPGRADE, upgrade)
+            .body(Body::empty())
+            .unwrap();
+        let state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+
+        WebSocketUpgradeRouteMatcher::new().is_match(&state).is_ok()
+    }
+
+    #[test]
+    fn matches_websocket_upgrades() {
+        assert!(is_match(Method::GET, "Upgrade", "websocket"));
+        assert!(is_match(Method::GET, "keep-alive, upgrade", "WebSocket"));
+
+        assert!(!is_match(Method::POST, "upgrade", "websocket"));
+        assert!(!is_match(Method::GET, "keep-alive", "websocket"));
+        assert!(!is_match(Method::GET, "upgrade", "h2c"));
+    }
+}
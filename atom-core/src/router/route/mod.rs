@@ -11,15 +11,17 @@ use std::marker::PhantomData;
 use std::panic::RefUnwindSafe;
 use std::pin::Pin;
 
-use hyper::{Response, Uri};
+use futures_util::future::{self, FutureExt};
+use hyper::{Method, Response, StatusCode, Uri};
 use log::debug;
-use crate::body::Body;
+use crate::body::{self, Body};
 use crate::extractor;
 use crate::extractor::path::PathExtractor;
 use crate::extractor::query_string::QueryStringExtractor;
 
 use crate::handler::HandlerFuture;
 use crate::helpers::http::request::query_string;
+use crate::helpers::http::response::create_empty_response;
 use crate::router::non_match::RouteNonMatch;
 use crate::router::route::dispatch::Dispatcher;
 use crate::router::route::matcher::RouteMatcher;
@@ -86,6 +88,12 @@ pub trait Route: RefUnwindSafe {
     /// Dispatches the request to this `Route`, which will execute the pipelines and the handler
     /// assigned to the `Route.
     fn dispatch(&self, state: State) -> Pin<Box<HandlerFuture>>;
+
+    /// Returns the method claimed by the `Middleware` of this `Route` for an `OPTIONS` request
+    /// which no route accepts, see `MiddlewareBuild::options_method`.
+    fn options_method(&self, _state: &State) -> Option<Method> {
+        None
+    }
 }
 
 /// Returned in the `Err` variant from `extract_query_string` or `extract_request_path`, this
@@ -104,6 +112,7 @@ where
     dispatcher: Box<dyn Dispatcher + Send + Sync>,
     _extractors: Extractors<PE, QSE>,
     delegation: Delegation,
+    body_limit: Option<usize>,
 }
 
 /// Extractors used by `RouteImpl` to acquire request data and change into a type safe form
@@ -135,8 +144,15 @@ where
             dispatcher,
             _extractors,
             delegation,
+            body_limit: None,
         }
     }
+
+    /// Limits the size of request bodies accepted by this `RouteImpl`, replacing any limit set
+    /// for the server.
+    pub(crate) fn with_body_limit(self, body_limit: Option<usize>) -> Self {
+        RouteImpl { body_limit, ..self }
+    }
 }
 
 impl<PE, QSE> Extractors<PE, QSE>
@@ -169,10 +185,24 @@ where
         self.delegation
     }
 
-    fn dispatch(&self, state: State) -> Pin<Box<HandlerFuture>> {
+    fn dispatch(&self, mut state: State) -> Pin<Box<HandlerFuture>> {
+        if let Some(limit) = self.body_limit {
+            body::limit_request_body(&mut state, limit);
+        }
+
+        if body::exceeds_body_limit(&state) {
+            debug!("[{}] request body exceeds limit", request_id(&state));
+            let res = create_empty_response(&state, StatusCode::PAYLOAD_TOO_LARGE);
+            return future::ok((state, res)).boxed();
+        }
+
         self.dispatcher.dispatch(state)
     }
 
+    fn options_method(&self, state: &State) -> Option<Method> {
+        self.dispatcher.options_method(state)
+    }
+
     fn extract_request_path<'a>(
         &self,
         state: &mut State,
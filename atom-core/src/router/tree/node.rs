@@ -1,6 +1,6 @@
 //! Defines `Node` for `Tree`.
 
-use hyper::{StatusCode};
+use hyper::{Method, StatusCode};
 use log::trace;
 
 use crate::helpers::http::PercentDecoded;
@@ -166,6 +166,31 @@ impl Node {
         Err(RouteNonMatch::new(StatusCode::INTERNAL_SERVER_ERROR))
     }
 
+    /// Determines the `Route` for an `OPTIONS` request which no `Route` accepts, being the first
+    /// whose `Middleware` claims the request for a method which the `Route` accepts.
+    ///
+    /// Returns the `Route` along with the claimed method, or `None` if there's no such `Route`.
+    pub fn select_options_route(
+        &self,
+        state: &mut State,
+    ) -> Option<(&(dyn Route<ResBody = Body> + Send + Sync), Method)> {
+        for r in self.routes.iter() {
+            if let Some(method) = r.options_method(state) {
+                // Match the route as if the request used the claimed method.
+                state.put(method.clone());
+                let matched = r.is_match(state);
+                state.put(Method::OPTIONS);
+
+                if matched.is_ok() {
+                    trace!("[{}] found route claiming OPTIONS", request_id(state));
+                    return Some((r.as_ref(), method));
+                }
+            }
+        }
+
+        None
+    }
+
     /// Recursive implementation of `match_route` to populate parameters and keep
     /// track of the number of visited nodes.
     ///
@@ -0,0 +1,745 @@
+//! Defines `ServerBuilder`, which configures and starts a Gotham server.
+
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{self, BoxFuture, FutureExt};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls;
+
+use crate::handler::NewHandler;
+use crate::helpers::http::response::create_https_redirect;
+use crate::server::listener::{IntoListener, Listener};
+use crate::server::proxy_protocol::{ProxyProtocolListener, DEFAULT_HEADER_TIMEOUT};
+use crate::server::{ConnectionErrors, Protocols, Settings};
+use crate::state::State;
+use crate::tls::{rustls_wrap, AcceptWithInfo, TlsInfo};
+use crate::{new_runtime, resolve_addr, serve, tcp_listener, StartError};
+
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A listener added via `ServerBuilder::listen` and friends, bound by `init_listeners`.
+enum Binding {
+    Plain(io::Result<SocketAddr>),
+    Tls(io::Result<SocketAddr>, Box<rustls::ServerConfig>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    HttpsRedirect(io::Result<SocketAddr>, u16),
+}
+
+/// Configures and starts a Gotham server, on plain HTTP or with TLS.
+///
+/// `plain::start` and `tls::start` use a `ServerBuilder` with its default settings. Public facing
+/// services will usually want to tighten the timeouts and limits:
+///
+/// ```rust,ignore
+/// ServerBuilder::new()
+///     .thread_name("api-worker")
+///     .header_read_timeout(Duration::from_secs(10))
+///     .idle_timeout(Duration::from_secs(60))
+///     .max_header_size(16 * 1024)
+///     .max_headers(64)
+///     .max_body_size(1024 * 1024)
+///     .max_connections(10_000)
+///     .start("127.0.0.1:7878", || Ok(router()))
+/// ```
+pub struct ServerBuilder {
+    threads: usize,
+    thread_name: String,
+    settings: Settings,
+    proxy_protocol: bool,
+    shutdown: Option<ShutdownSignal>,
+    bindings: Vec<Binding>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    /// Creates a `ServerBuilder` with the default settings: one worker thread per CPU, named
+    /// `gotham-worker`, serving HTTP/1.1 and HTTP/2 with keep-alive and no other limits.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            threads: num_cpus::get(),
+            thread_name: String::from("gotham-worker"),
+            settings: Settings::default(),
+            proxy_protocol: false,
+            shutdown: None,
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Sets the number of worker threads used by `start` and `start_tls`.
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets the name given to the worker threads used by `start` and `start_tls`.
+    pub fn thread_name<S: Into<String>>(mut self, thread_name: S) -> ServerBuilder {
+        self.thread_name = thread_name.into();
+        self
+    }
+
+    /// Sets which HTTP versions the server will speak.
+    pub fn protocols(mut self, protocols: Protocols) -> ServerBuilder {
+        self.settings.protocols = protocols;
+        self
+    }
+
+    /// Closes HTTP/1 connections which take longer than `timeout` to send the headers of a
+    /// request. The same timeout applies to the TLS handshake and the first bytes of every
+    /// connection, which is `proxy_protocol::DEFAULT_HEADER_TIMEOUT` if none is set.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.settings.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables or disables HTTP/1 keep-alive. When disabled, each connection is closed after a
+    /// single response. Enabled by default.
+    pub fn keep_alive(mut self, keep_alive: bool) -> ServerBuilder {
+        self.settings.keep_alive = keep_alive;
+        self
+    }
+
+    /// Gracefully closes connections on which no data has been sent or received for `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.settings.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the total size of a request's headers, in bytes.
+    ///
+    /// For HTTP/1 this bounds the connection's read buffer, which hyper requires to be at least
+    /// 8KiB; smaller values are rounded up. Requests exceeding the limit are rejected by hyper
+    /// before reaching the handler.
+    pub fn max_header_size(mut self, size: usize) -> ServerBuilder {
+        self.settings.max_header_size = Some(size);
+        self
+    }
+
+    /// Rejects requests carrying more than `count` headers with
+    /// `431 Request Header Fields Too Large`.
+    pub fn max_headers(mut self, count: usize) -> ServerBuilder {
+        self.settings.max_headers = Some(count);
+        self
+    }
+
+    /// Limits the size of request bodies, in bytes. Individual routes can set their own limit via
+    /// `DefineSingleRoute::with_body_limit`.
+    ///
+    /// Requests declaring a larger `Content-Length` are answered with `413 Payload Too Large` by
+    /// the `Router`, and other bodies stop being read once they pass the limit, see `BodyLimit`.
+    pub fn max_body_size(mut self, size: usize) -> ServerBuilder {
+        self.settings.max_body_size = Some(size);
+        self
+    }
+
+    /// Limits the number of connections served at once, across every listener. Once the limit is
+    /// reached, new connections wait in the listen backlog until an open connection closes.
+    pub fn max_connections(mut self, max: usize) -> ServerBuilder {
+        self.settings.max_connections = Some(max);
+        self
+    }
+
+    /// Expects every connection to start with a PROXY protocol header, as sent by load balancers
+    /// such as HAProxy, and uses the client address it describes. Disabled by default.
+    ///
+    /// Connections with a malformed header are closed, as are those which don't send it within
+    /// the header read timeout, or `proxy_protocol::DEFAULT_HEADER_TIMEOUT` if none is set. See
+    /// `ProxyProtocolListener`.
+    pub fn proxy_protocol(mut self, enabled: bool) -> ServerBuilder {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Sets how connection errors are reported, see `ConnectionErrors`.
+    pub fn connection_errors(mut self, connection_errors: ConnectionErrors) -> ServerBuilder {
+        self.settings.connection_errors = connection_errors;
+        self
+    }
+
+    /// Stops the server gracefully once `signal` resolves, see `bind_server_with_shutdown`.
+    ///
+    /// Without a shutdown signal the server runs forever.
+    pub fn graceful_shutdown<S>(mut self, signal: S) -> ServerBuilder
+    where
+        S: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Sets how long open connections are given to complete once the shutdown signal resolves.
+    /// Defaults to `DEFAULT_SHUTDOWN_TIMEOUT`.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.settings.drain_timeout = timeout;
+        self
+    }
+
+    /// Adds a listener serving plain HTTP on `addr`, for use with `start_listeners`.
+    pub fn listen<A: ToSocketAddrs>(mut self, addr: A) -> ServerBuilder {
+        self.bindings.push(Binding::Plain(resolve_addr(addr)));
+        self
+    }
+
+    /// Adds a listener serving HTTPS on `addr`, for use with `start_listeners`.
+    ///
+    /// If `tls_config` does not specify any ALPN protocols, those matching the configured
+    /// `Protocols` are advertised.
+    pub fn listen_tls<A: ToSocketAddrs>(
+        mut self,
+        addr: A,
+        tls_config: rustls::ServerConfig,
+    ) -> ServerBuilder {
+        let binding = Binding::Tls(resolve_addr(addr), Box::new(tls_config));
+        self.bindings.push(binding);
+        self
+    }
+
+    /// Adds a listener serving plain HTTP over a Unix domain socket bound at `path`, for use with
+    /// `start_listeners`. The socket file is removed once the server has shut down gracefully.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(mut self, path: P) -> ServerBuilder {
+        self.bindings.push(Binding::Unix(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Adds a listener on `addr` which answers every request with a `308 Permanent Redirect` to
+    /// the same path and query on the HTTPS server listening on `https_port`, see
+    /// `create_https_redirect`. The application is never called for these requests.
+    pub fn redirect_to_https<A: ToSocketAddrs>(
+        mut self,
+        addr: A,
+        https_port: u16,
+    ) -> ServerBuilder {
+        let binding = Binding::HttpsRedirect(resolve_addr(addr), https_port);
+        self.bindings.push(binding);
+        self
+    }
+
+    /// Starts a Gotham application on plain, unsecured HTTP.
+    pub fn start<NH, A>(self, addr: A, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        A: ToSocketAddrs + 'static + Send,
+    {
+        let runtime = new_runtime(self.threads, &self.thread_name);
+        runtime.block_on(self.init_server(addr, new_handler))
+    }
+
+    /// Starts a Gotham application with TLS.
+    pub fn start_tls<NH, A>(
+        self,
+        addr: A,
+        new_handler: NH,
+        tls_config: rustls::ServerConfig,
+    ) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        A: ToSocketAddrs + 'static + Send,
+    {
+        let runtime = new_runtime(self.threads, &self.thread_name);
+        runtime.block_on(self.init_tls_server(addr, new_handler, tls_config))
+    }
+
+    /// Starts a Gotham application on plain HTTP over a Unix domain socket bound at `path`.
+    ///
+    /// See `init_unix_server`.
+    #[cfg(unix)]
+    pub fn start_unix<NH, P>(self, path: P, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        P: AsRef<Path>,
+    {
+        let runtime = new_runtime(self.threads, &self.thread_name);
+        runtime.block_on(self.init_unix_server(path, new_handler))
+    }
+
+    /// Starts a Gotham application on plain HTTP, accepting connections from a listener which
+    /// has already been bound, such as one passed in by systemd socket activation.
+    pub fn start_with_listener<NH, L>(self, listener: L, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        L: IntoListener,
+    {
+        let runtime = new_runtime(self.threads, &self.thread_name);
+        runtime.block_on(self.init_server_with_listener(listener, new_handler))
+    }
+
+    /// Starts a Gotham application on every listener added via `listen`, `listen_tls`,
+    /// `listen_unix` and `redirect_to_https`, sharing a single runtime.
+    ///
+    /// See `init_listeners`.
+    pub fn start_listeners<NH>(self, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+    {
+        let runtime = new_runtime(self.threads, &self.thread_name);
+        runtime.block_on(self.init_listeners(new_handler))
+    }
+
+    /// Returns a `Future` used to spawn a Gotham application on plain, unsecured HTTP.
+    ///
+    /// The thread settings are ignored, as the future runs on the caller's runtime.
+    pub async fn init_server<NH, A>(self, addr: A, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        A: ToSocketAddrs + 'static + Send,
+    {
+        let listener = tcp_listener(addr).await?;
+        self.serve_plain(listener, new_handler).await
+    }
+
+    /// Returns a `Future` used to spawn a Gotham application on plain HTTP over a Unix domain
+    /// socket bound at `path`.
+    ///
+    /// The socket file is removed once the server has shut down gracefully. Binding fails if the
+    /// file already exists, so a file left behind by a crashed process must be removed first.
+    #[cfg(unix)]
+    pub async fn init_unix_server<NH, P>(self, path: P, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)?;
+        let result = self.serve_plain(listener, new_handler).await;
+        remove_socket_file(path);
+        result
+    }
+
+    /// Returns a `Future` used to spawn a Gotham application on plain HTTP, accepting
+    /// connections from a listener which has already been bound.
+    pub async fn init_server_with_listener<NH, L>(
+        self,
+        listener: L,
+        new_handler: NH,
+    ) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        L: IntoListener,
+    {
+        let listener = listener.into_listener()?;
+        self.serve_plain(listener, new_handler).await
+    }
+
+    /// Returns a `Future` used to spawn a Gotham application with TLS.
+    ///
+    /// If `tls_config` does not specify any ALPN protocols, those matching the configured
+    /// `Protocols` are advertised. The thread settings are ignored, as the future runs on the
+    /// caller's runtime.
+    pub async fn init_tls_server<NH, A>(
+        mut self,
+        addr: A,
+        new_handler: NH,
+        tls_config: rustls::ServerConfig,
+    ) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        A: ToSocketAddrs + 'static + Send,
+    {
+        let listener = tcp_listener(addr).await?;
+        log_listening(&listener, "https")?;
+
+        let shutdown = self.shutdown_signal();
+        let wrap = self.tls_wrap(tls_config);
+        let limit = self.connection_limit();
+        self.serve_on(listener, new_handler, wrap, limit, shutdown)
+            .await
+    }
+
+    /// Returns a `Future` used to spawn a Gotham application on every listener added via `listen`,
+    /// `listen_tls`, `listen_unix` and `redirect_to_https`.
+    ///
+    /// Every listener is bound before any connections are accepted, failing if any of them can't
+    /// be. The listeners share the server's settings and connection limit, and stop together once
+    /// the shutdown signal resolves. Unix socket files are removed once they stop, or if a later
+    /// listener fails to bind. The thread settings are ignored, as the future runs on the caller's runtime.
+    ///
+    /// ```rust,ignore
+    /// ServerBuilder::new()
+    ///     .listen_tls("0.0.0.0:443", tls_config)
+    ///     .redirect_to_https("0.0.0.0:80", 443)
+    ///     .listen_unix("/run/app/admin.sock")
+    ///     .graceful_shutdown(shutdown_signal())
+    ///     .init_listeners(|| Ok(router()))
+    ///     .await
+    /// ```
+    pub async fn init_listeners<NH>(mut self, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+    {
+        if self.bindings.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "no listeners configured");
+            return Err(err.into());
+        }
+
+        let new_handler = Arc::new(new_handler);
+        let shutdown = self.shutdown_signal().shared();
+        let limit = self.connection_limit();
+        let mut servers = Vec::new();
+        // Removes the socket files once the servers stop, or a later listener fails to bind.
+        #[cfg(unix)]
+        let mut socket_files = SocketFiles(Vec::new());
+
+        for binding in std::mem::take(&mut self.bindings) {
+            let server = match binding {
+                Binding::Plain(addr) => {
+                    let listener = TcpListener::bind(addr?).await?;
+                    log_listening(&listener, "http")?;
+                    let handler = new_handler.clone();
+                    self.serve_on(
+                        listener,
+                        handler,
+                        plain_wrap,
+                        limit.clone(),
+                        shutdown.clone(),
+                    )
+                }
+                Binding::Tls(addr, tls_config) => {
+                    let listener = TcpListener::bind(addr?).await?;
+                    log_listening(&listener, "https")?;
+                    let wrap = self.tls_wrap(*tls_config);
+                    let handler = new_handler.clone();
+                    self.serve_on(listener, handler, wrap, limit.clone(), shutdown.clone())
+                }
+                #[cfg(unix)]
+                Binding::Unix(path) => {
+                    let listener = UnixListener::bind(&path)?;
+                    socket_files.0.push(path);
+                    log_listening(&listener, "http")?;
+                    let handler = new_handler.clone();
+                    self.serve_on(
+                        listener,
+                        handler,
+                        plain_wrap,
+                        limit.clone(),
+                        shutdown.clone(),
+                    )
+                }
+                Binding::HttpsRedirect(addr, https_port) => {
+                    let listener = TcpListener::bind(addr?).await?;
+                    log_listening(&listener, "http")?;
+                    let redirect = move || {
+                        Ok(move |state: State| {
+                            let res = create_https_redirect(&state, https_port);
+                            (state, res)
+                        })
+                    };
+                    self.serve_on(
+                        listener,
+                        redirect,
+                        plain_wrap,
+                        limit.clone(),
+                        shutdown.clone(),
+                    )
+                }
+            };
+
+            servers.push(server);
+        }
+
+        future::try_join_all(servers).await.map(|_| ())
+    }
+
+    async fn serve_plain<NH, L>(mut self, listener: L, new_handler: NH) -> Result<(), StartError>
+    where
+        NH: NewHandler + 'static,
+        L: Listener,
+    {
+        log_listening(&listener, "http")?;
+
+        let shutdown = self.shutdown_signal();
+        let limit = self.connection_limit();
+        self.serve_on(listener, new_handler, plain_wrap, limit, shutdown)
+            .await
+    }
+
+    /// Serves connections from `listener` until `shutdown` resolves, reading a PROXY protocol
+    /// header from each first if enabled.
+    fn serve_on<L, NH, F, Wrapped, Wrap, S>(
+        &self,
+        listener: L,
+        new_handler: NH,
+        wrap: Wrap,
+        limit: Option<Arc<Semaphore>>,
+        shutdown: S,
+    ) -> BoxFuture<'static, Result<(), StartError>>
+    where
+        L: Listener,
+        NH: NewHandler + 'static,
+        F: Future<Output = io::Result<(Wrapped, Option<TlsInfo>)>> + Unpin + Send + 'static,
+        Wrapped: Unpin + AsyncRead + AsyncWrite + Send + 'static,
+        Wrap: Fn(L::Io) -> F + Send + 'static,
+        S: Future<Output = ()> + Send + 'static,
+    {
+        let settings = self.settings.clone();
+
+        if self.proxy_protocol {
+            let listener = self.proxied(listener).connection_limit(limit.clone());
+            serve(listener, new_handler, wrap, settings, limit, shutdown).boxed()
+        } else {
+            serve(listener, new_handler, wrap, settings, limit, shutdown).boxed()
+        }
+    }
+
+    /// Creates the TLS handshake for connections, advertising the ALPN protocols matching the
+    /// configured `Protocols` if `tls_config` does not specify any.
+    fn tls_wrap(
+        &self,
+        mut tls_config: rustls::ServerConfig,
+    ) -> impl Fn(TcpStream) -> AcceptWithInfo {
+        if tls_config.alpn_protocols.is_empty() {
+            tls_config.alpn_protocols = self.settings.protocols.alpn_protocols();
+        }
+
+        rustls_wrap(tls_config)
+    }
+
+    /// Creates the semaphore enforcing `max_connections`, to be shared by every listener.
+    fn connection_limit(&self) -> Option<Arc<Semaphore>> {
+        self.settings
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)))
+    }
+
+    fn proxied<L: Listener>(&self, listener: L) -> ProxyProtocolListener<L> {
+        let timeout = self
+            .settings
+            .header_read_timeout
+            .unwrap_or(DEFAULT_HEADER_TIMEOUT);
+
+        ProxyProtocolListener::new(listener)
+            .header_timeout(timeout)
+            .connection_errors(self.settings.connection_errors.clone())
+    }
+
+    fn shutdown_signal(&mut self) -> ShutdownSignal {
+        self.shutdown
+            .take()
+            .unwrap_or_else(|| Box::pin(future::pending()))
+    }
+}
+
+fn log_listening<L: Listener>(listener: &L, scheme: &str) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+
+    info! {
+        target: "gotham::start",
+        " Gotham listening on {}://{}", scheme, addr
+    }
+
+    Ok(())
+}
+
+fn plain_wrap<Io>(socket: Io) -> future::Ready<io::Result<(Io, Option<TlsInfo>)>> {
+    future::ok((socket, None))
+}
+
+#[cfg(unix)]
+fn remove_socket_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        log::warn!(target: "gotham::start", " unable to remove {}: {}", path.display(), err);
+    }
+}
+
+/// The socket files bound by `init_listeners`, removed when dropped.
+#[cfg(unix)]
+struct SocketFiles(Vec<PathBuf>);
+
+#[cfg(unix)]
+impl Drop for SocketFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            remove_socket_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::http::response::create_empty_response;
+    use hyper::StatusCode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    fn new_handler() -> impl NewHandler {
+        || {
+            Ok(|state: State| {
+                let res = create_empty_response(&state, StatusCode::OK);
+                (state, res)
+            })
+        }
+    }
+
+    /// Returns an address which is free to bind, as far as the OS knows.
+    fn free_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("atom-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn get<S>(mut socket: S) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        socket
+            .write_all(b"GET /a?b HTTP/1.1\r\nHost: localhost:8080\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn server_futures_are_send() {
+        fn assert_send<T: Send>(_: T) {}
+
+        let tls_config = || {
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(rustls::server::ResolvesServerCertUsingSni::new()))
+        };
+        let shutdown = future::pending::<()>;
+        let drain_timeout = Duration::from_secs(1);
+
+        assert_send(crate::plain::init_server("127.0.0.1:0", new_handler()));
+        assert_send(crate::plain::init_server_with_graceful_shutdown(
+            "127.0.0.1:0",
+            new_handler(),
+            shutdown(),
+            drain_timeout,
+        ));
+        assert_send(crate::tls::init_server(
+            "127.0.0.1:0",
+            new_handler(),
+            tls_config(),
+        ));
+        assert_send(crate::tls::init_server_with_graceful_shutdown(
+            "127.0.0.1:0",
+            new_handler(),
+            tls_config(),
+            shutdown(),
+            drain_timeout,
+        ));
+
+        let builder = ServerBuilder::new;
+        assert_send(builder().init_server("127.0.0.1:0", new_handler()));
+        assert_send(builder().init_tls_server("127.0.0.1:0", new_handler(), tls_config()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_send(builder().init_server_with_listener(listener, new_handler()));
+        #[cfg(unix)]
+        assert_send(builder().init_unix_server(socket_path("send"), new_handler()));
+        assert_send(
+            builder()
+                .listen("127.0.0.1:0")
+                .init_listeners(new_handler()),
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_every_listener() {
+        let (plain, redirect) = (free_addr(), free_addr());
+        let path = socket_path("listeners");
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let server = ServerBuilder::new()
+            .listen(plain)
+            .redirect_to_https(redirect, 8443)
+            .listen_unix(&path)
+            .graceful_shutdown(stopped.map(|_| ()))
+            .init_listeners(new_handler());
+
+        let client = async {
+            let response = get(TcpStream::connect(plain).await.unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+            let response = get(TcpStream::connect(redirect).await.unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect"));
+            assert!(response.contains("location: https://localhost:8443/a?b\r\n"));
+
+            let response = get(tokio::net::UnixStream::connect(&path).await.unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+            stop.send(()).unwrap();
+        };
+
+        let (result, _) = tokio::join!(server, client);
+        result.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn removes_socket_files_when_binding_fails() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let path = socket_path("bind-failure");
+
+        let result = ServerBuilder::new()
+            .listen_unix(&path)
+            .listen(taken.local_addr().unwrap())
+            .init_listeners(new_handler())
+            .await;
+
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn shares_the_connection_limit() {
+        let (first, second) = (free_addr(), free_addr());
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let server = ServerBuilder::new()
+            .listen(first)
+            .listen(second)
+            .max_connections(1)
+            .graceful_shutdown(stopped.map(|_| ()))
+            .init_listeners(new_handler());
+
+        let client = async {
+            // The only permit goes to a client of the first listener, which sends nothing.
+            let silent = TcpStream::connect(first).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let response = tokio::spawn(get(TcpStream::connect(second).await.unwrap()));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!response.is_finished());
+
+            drop(silent);
+            let response = response.await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+            stop.send(()).unwrap();
+        };
+
+        let (result, _) = tokio::join!(server, client);
+        result.unwrap();
+    }
+}
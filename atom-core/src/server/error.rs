@@ -0,0 +1,336 @@
+//! Defines how errors which occur on a connection, outside of any request handler, are reported.
+//!
+//! A misbehaving client can cause a connection to fail in many ways: it may reset the socket, send
+//! a malformed request, stall until a timeout fires, or fail the TLS handshake. None of these are
+//! bugs in the application, so they are classified, logged with the client address and counted,
+//! rather than being allowed to panic the connection task.
+
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::{debug, error, warn};
+
+use crate::state::ClientAddr;
+
+/// The broad category of a `ConnectionError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ConnectionErrorKind {
+    /// The client sent data which could not be parsed as HTTP.
+    Parse,
+    /// The connection timed out.
+    Timeout,
+    /// The client reset or closed the connection unexpectedly.
+    Reset,
+    /// The TLS handshake (or other wrapping of the socket, see `bind_server`) failed.
+    TlsHandshake,
+    /// Any other connection level error.
+    Other,
+}
+
+impl ConnectionErrorKind {
+    const ALL: [ConnectionErrorKind; 5] = [
+        ConnectionErrorKind::Parse,
+        ConnectionErrorKind::Timeout,
+        ConnectionErrorKind::Reset,
+        ConnectionErrorKind::TlsHandshake,
+        ConnectionErrorKind::Other,
+    ];
+
+    fn index(self) -> usize {
+        match self {
+            ConnectionErrorKind::Parse => 0,
+            ConnectionErrorKind::Timeout => 1,
+            ConnectionErrorKind::Reset => 2,
+            ConnectionErrorKind::TlsHandshake => 3,
+            ConnectionErrorKind::Other => 4,
+        }
+    }
+
+    fn from_io(kind: io::ErrorKind) -> ConnectionErrorKind {
+        match kind {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => ConnectionErrorKind::Reset,
+            io::ErrorKind::TimedOut => ConnectionErrorKind::Timeout,
+            io::ErrorKind::InvalidData => ConnectionErrorKind::Parse,
+            _ => ConnectionErrorKind::Other,
+        }
+    }
+}
+
+impl Display for ConnectionErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionErrorKind::Parse => "parse error",
+            ConnectionErrorKind::Timeout => "timeout",
+            ConnectionErrorKind::Reset => "connection reset",
+            ConnectionErrorKind::TlsHandshake => "TLS handshake error",
+            ConnectionErrorKind::Other => "connection error",
+        })
+    }
+}
+
+/// An error which caused a connection to be closed, together with the address of the client.
+#[derive(Debug)]
+pub struct ConnectionError {
+    kind: ConnectionErrorKind,
+    client_addr: ClientAddr,
+    source: Box<dyn StdError + Send + Sync>,
+}
+
+impl ConnectionError {
+    /// Classifies an error returned by hyper while serving the connection.
+    pub(crate) fn from_hyper(error: hyper::Error, client_addr: ClientAddr) -> ConnectionError {
+        let kind = if error.is_parse() || error.is_parse_too_large() || error.is_parse_status() {
+            ConnectionErrorKind::Parse
+        } else if error.is_timeout() {
+            ConnectionErrorKind::Timeout
+        } else if error.is_incomplete_message() || error.is_canceled() || error.is_closed() {
+            ConnectionErrorKind::Reset
+        } else {
+            find_io_error(&error)
+                .map(|e| ConnectionErrorKind::from_io(e.kind()))
+                .unwrap_or(ConnectionErrorKind::Other)
+        };
+
+        ConnectionError {
+            kind,
+            client_addr,
+            source: Box::new(error),
+        }
+    }
+
+    /// Classifies an IO error which occurred before the connection was handed to hyper.
+    pub(crate) fn from_io(error: io::Error, client_addr: ClientAddr) -> ConnectionError {
+        ConnectionError {
+            kind: ConnectionErrorKind::from_io(error.kind()),
+            client_addr,
+            source: Box::new(error),
+        }
+    }
+
+    /// Creates an error for a socket which could not be wrapped, i.e. a failed TLS handshake.
+    pub(crate) fn handshake(error: io::Error, client_addr: ClientAddr) -> ConnectionError {
+        ConnectionError {
+            kind: ConnectionErrorKind::TlsHandshake,
+            client_addr,
+            source: Box::new(error),
+        }
+    }
+
+    /// The category of this error.
+    pub fn kind(&self) -> ConnectionErrorKind {
+        self.kind
+    }
+
+    /// The address of the client whose connection failed.
+    pub fn client_addr(&self) -> &ClientAddr {
+        &self.client_addr
+    }
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from {}: {}",
+            self.kind, self.client_addr, self.source
+        )
+    }
+}
+
+impl StdError for ConnectionError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+fn find_io_error<'a>(error: &'a (dyn StdError + 'static)) -> Option<&'a io::Error> {
+    let mut cause = error.source();
+    while let Some(err) = cause {
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            return Some(io);
+        }
+        cause = err.source();
+    }
+    None
+}
+
+/// A hook which is invoked for every `ConnectionError` after it has been logged and counted.
+///
+/// This is implemented for any `Fn(&ConnectionError)` closure which is `Send + Sync`.
+pub trait ConnectionErrorHook: Send + Sync + 'static {
+    /// Called once for each connection which fails.
+    fn on_error(&self, error: &ConnectionError);
+}
+
+impl<F> ConnectionErrorHook for F
+where
+    F: Fn(&ConnectionError) + Send + Sync + 'static,
+{
+    fn on_error(&self, error: &ConnectionError) {
+        self(error)
+    }
+}
+
+/// Running totals of connection errors, by `ConnectionErrorKind`.
+#[derive(Debug, Default)]
+pub struct ConnectionErrorCounts {
+    counts: [AtomicU64; ConnectionErrorKind::ALL.len()],
+}
+
+impl ConnectionErrorCounts {
+    /// The number of errors of the given kind seen so far.
+    pub fn get(&self, kind: ConnectionErrorKind) -> u64 {
+        self.counts[kind.index()].load(Ordering::Relaxed)
+    }
+
+    /// The number of errors of any kind seen so far.
+    pub fn total(&self) -> u64 {
+        ConnectionErrorKind::ALL
+            .iter()
+            .map(|kind| self.get(*kind))
+            .sum()
+    }
+
+    fn increment(&self, kind: ConnectionErrorKind) {
+        self.counts[kind.index()].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Configures how a server reports connection errors.
+///
+/// Every error is logged under the `gotham::connection` target along with the client address, and
+/// counted. An optional `ConnectionErrorHook` can be supplied to forward errors elsewhere, e.g. to
+/// a metrics system. Clones share the same counts, so a clone can be kept to inspect them while
+/// the server is running:
+///
+/// ```rust,ignore
+/// let errors = ConnectionErrors::new().with_hook(|err: &ConnectionError| eprintln!("{}", err));
+/// let counts = errors.clone();
+/// // ... bind_server_with_connection_errors(listener, new_handler, wrap, errors) ...
+/// println!("{} connections failed", counts.counts().total());
+/// ```
+#[derive(Clone, Default)]
+pub struct ConnectionErrors {
+    hook: Option<Arc<dyn ConnectionErrorHook>>,
+    counts: Arc<ConnectionErrorCounts>,
+}
+
+impl ConnectionErrors {
+    /// Creates a new `ConnectionErrors` which logs and counts errors, without any hook.
+    pub fn new() -> ConnectionErrors {
+        ConnectionErrors::default()
+    }
+
+    /// Sets the hook to invoke for each connection error.
+    pub fn with_hook<H>(self, hook: H) -> ConnectionErrors
+    where
+        H: ConnectionErrorHook,
+    {
+        ConnectionErrors {
+            hook: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// The counts of connection errors reported so far.
+    pub fn counts(&self) -> &ConnectionErrorCounts {
+        &self.counts
+    }
+
+    /// Logs, counts, and forwards the error to the hook.
+    pub(crate) fn report(&self, error: ConnectionError) {
+        match error.kind {
+            // These are a normal part of serving clients over the internet.
+            ConnectionErrorKind::Reset | ConnectionErrorKind::Timeout => {
+                debug!(target: "gotham::connection", " {}", error)
+            }
+            ConnectionErrorKind::Parse | ConnectionErrorKind::TlsHandshake => {
+                warn!(target: "gotham::connection", " {}", error)
+            }
+            ConnectionErrorKind::Other => error!(target: "gotham::connection", " {}", error),
+        }
+
+        self.counts.increment(error.kind);
+
+        if let Some(ref hook) = self.hook {
+            hook.on_error(&error);
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionErrors")
+            .field("hook", &self.hook.is_some())
+            .field("counts", &self.counts)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn addr() -> ClientAddr {
+        ClientAddr::Tcp("127.0.0.1:9000".parse().unwrap())
+    }
+
+    #[test]
+    fn classifies_io_errors() {
+        let kind = |k| ConnectionError::from_io(io::Error::from(k), addr()).kind();
+
+        assert_eq!(
+            kind(io::ErrorKind::ConnectionReset),
+            ConnectionErrorKind::Reset
+        );
+        assert_eq!(kind(io::ErrorKind::BrokenPipe), ConnectionErrorKind::Reset);
+        assert_eq!(kind(io::ErrorKind::TimedOut), ConnectionErrorKind::Timeout);
+        assert_eq!(
+            kind(io::ErrorKind::PermissionDenied),
+            ConnectionErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn handshake_errors_are_classified_as_tls() {
+        let err = io::Error::from(io::ErrorKind::ConnectionReset);
+        let err = ConnectionError::handshake(err, addr());
+        assert_eq!(err.kind(), ConnectionErrorKind::TlsHandshake);
+        assert_eq!(err.client_addr(), &addr());
+    }
+
+    #[test]
+    fn report_counts_and_invokes_hook() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let errors = {
+            let seen = seen.clone();
+            ConnectionErrors::new().with_hook(move |err: &ConnectionError| {
+                seen.lock().unwrap().push(err.kind());
+            })
+        };
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        let timeout = io::Error::from(io::ErrorKind::TimedOut);
+        errors
+            .clone()
+            .report(ConnectionError::from_io(reset, addr()));
+        errors.report(ConnectionError::from_io(timeout, addr()));
+
+        assert_eq!(errors.counts().get(ConnectionErrorKind::Reset), 1);
+        assert_eq!(errors.counts().get(ConnectionErrorKind::Timeout), 1);
+        assert_eq!(errors.counts().get(ConnectionErrorKind::Parse), 0);
+        assert_eq!(errors.counts().total(), 2);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![ConnectionErrorKind::Reset, ConnectionErrorKind::Timeout]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Defines the idle timeout applied to connections which have stopped sending or receiving data.
+//!
+//! Every accepted connection is wrapped in `Tracked`, which records the last time any bytes were
+//! read from or written to it. When an idle timeout is configured the connection is shut down
+//! gracefully once that long passes without any activity, so requests which are in progress are
+//! allowed to complete.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// The time of the most recent IO on a connection, shared between the IO and the watchdog.
+struct Activity {
+    started: Instant,
+    // Milliseconds since `started`, to allow updates without locking.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// An IO wrapper which records activity on the underlying transport.
+pub(crate) struct Tracked<I> {
+    inner: I,
+    activity: Arc<Activity>,
+}
+
+/// Resolves once the `Tracked` connection it was created with has been idle for `timeout`.
+#[derive(Clone)]
+pub(crate) struct Idle {
+    activity: Arc<Activity>,
+    timeout: Duration,
+}
+
+/// Wraps `io` so that its activity can be observed by the returned `Idle`.
+pub(crate) fn track<I>(io: I, timeout: Duration) -> (Tracked<I>, Idle) {
+    let activity = Arc::new(Activity {
+        started: Instant::now(),
+        last: AtomicU64::new(0),
+    });
+
+    let idle = Idle {
+        activity: activity.clone(),
+        timeout,
+    };
+
+    (
+        Tracked {
+            inner: io,
+            activity,
+        },
+        idle,
+    )
+}
+
+impl Idle {
+    /// Waits until no IO has been observed for the configured timeout.
+    pub(crate) async fn expired(self) {
+        loop {
+            let deadline = self.activity.last() + self.timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+impl<I> AsyncRead for Tracked<I>
+where
+    I: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+
+        poll
+    }
+}
+
+impl<I> AsyncWrite for Tracked<I>
+where
+    I: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+
+        poll
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn expires_without_activity() {
+        runtime().block_on(async {
+            let (_io, idle) = track(&b""[..], Duration::from_millis(50));
+            let started = Instant::now();
+            idle.expired().await;
+            assert!(started.elapsed() >= Duration::from_millis(50));
+        });
+    }
+
+    #[test]
+    fn activity_extends_deadline() {
+        runtime().block_on(async {
+            let (mut io, idle) = track(&b"hello"[..], Duration::from_millis(50));
+            let started = Instant::now();
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let mut buf = [0u8; 5];
+            io.read_exact(&mut buf).await.unwrap();
+
+            idle.expired().await;
+            assert!(started.elapsed() >= Duration::from_millis(75));
+        });
+    }
+}
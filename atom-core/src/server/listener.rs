@@ -0,0 +1,145 @@
+//! Defines the listeners which a Gotham server can accept connections from.
+//!
+//! TCP and (on Unix platforms) Unix domain socket listeners are supported out of the box, whether
+//! bound by Gotham or handed over already bound, e.g. by systemd socket activation:
+//!
+//! ```rust,ignore
+//! use std::os::unix::io::FromRawFd;
+//!
+//! // systemd passes the first activated socket as file descriptor 3
+//! let listener = unsafe { std::net::TcpListener::from_raw_fd(3) };
+//! plain::start_with_listener(listener, || Ok(router()))
+//! ```
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::state::ClientAddr;
+#[cfg(unix)]
+use crate::state::{PeerCredentials, UnixPeer};
+
+/// A source of connections for a Gotham server.
+pub trait Listener: Send + 'static {
+    /// The connection type produced by this listener.
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Polls to accept a new connection, returning it along with the address of the client.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, ClientAddr)>>;
+
+    /// The local address this listener is bound to.
+    fn local_addr(&self) -> io::Result<ListenAddr>;
+}
+
+/// The local address of a `Listener`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ListenAddr {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, or `None` if the socket is unnamed.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ListenAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            ListenAddr::Unix(None) => f.write_str("unix"),
+        }
+    }
+}
+
+impl Listener for TcpListener {
+    type Io = TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, ClientAddr)>> {
+        TcpListener::poll_accept(self, cx).map_ok(|(socket, addr)| (socket, ClientAddr::Tcp(addr)))
+    }
+
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        TcpListener::local_addr(self).map(ListenAddr::Tcp)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Io = UnixStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(UnixStream, ClientAddr)>> {
+        UnixListener::poll_accept(self, cx).map_ok(|(socket, addr)| {
+            // Not every platform supports peer credentials; the connection is still usable.
+            let credentials = socket.peer_cred().ok().map(|cred| PeerCredentials {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            });
+
+            let peer = UnixPeer {
+                path: addr.as_pathname().map(Into::into),
+                credentials,
+            };
+
+            (socket, ClientAddr::Unix(peer))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        UnixListener::local_addr(self)
+            .map(|addr| ListenAddr::Unix(addr.as_pathname().map(Into::into)))
+    }
+}
+
+/// Conversion into a `Listener`, implemented for both tokio and standard library listeners.
+///
+/// Standard library listeners are switched to non-blocking mode and registered with the tokio
+/// runtime, so the conversion must happen within a runtime.
+pub trait IntoListener {
+    /// The listener produced by the conversion.
+    type Listener: Listener;
+
+    /// Performs the conversion.
+    fn into_listener(self) -> io::Result<Self::Listener>;
+}
+
+impl<L> IntoListener for L
+where
+    L: Listener,
+{
+    type Listener = L;
+
+    fn into_listener(self) -> io::Result<L> {
+        Ok(self)
+    }
+}
+
+impl IntoListener for std::net::TcpListener {
+    type Listener = TcpListener;
+
+    fn into_listener(self) -> io::Result<TcpListener> {
+        self.set_nonblocking(true)?;
+        TcpListener::from_std(self)
+    }
+}
+
+#[cfg(unix)]
+impl IntoListener for std::os::unix::net::UnixListener {
+    type Listener = UnixListener;
+
+    fn into_listener(self) -> io::Result<UnixListener> {
+        self.set_nonblocking(true)?;
+        UnixListener::from_std(self)
+    }
+}
@@ -0,0 +1,61 @@
+//! Defines the connection-level machinery used by `bind_server` to serve a Gotham application.
+//!
+//! Most applications never need to touch this module directly; `plain::start` and `tls::start`
+//! wire everything together. It is exposed for applications which want to customise how accepted
+//! connections are handled.
+
+use std::time::Duration;
+
+mod builder;
+pub mod error;
+pub(crate) mod idle;
+pub mod listener;
+pub mod protocol;
+pub mod proxy_protocol;
+pub(crate) mod rt;
+pub mod shutdown;
+pub(crate) mod upgrade;
+
+pub use self::builder::ServerBuilder;
+pub use self::error::{
+    ConnectionError, ConnectionErrorCounts, ConnectionErrorHook, ConnectionErrorKind,
+    ConnectionErrors,
+};
+pub use self::listener::{IntoListener, ListenAddr, Listener};
+pub use self::protocol::{Protocol, Protocols};
+pub use self::proxy_protocol::ProxyProtocolListener;
+pub use self::shutdown::DEFAULT_SHUTDOWN_TIMEOUT;
+
+/// The settings shared by every connection accepted by a server.
+///
+/// These are configured through `ServerBuilder`; the defaults match hyper's own.
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) protocols: Protocols,
+    pub(crate) connection_errors: ConnectionErrors,
+    pub(crate) drain_timeout: Duration,
+    pub(crate) header_read_timeout: Option<Duration>,
+    pub(crate) keep_alive: bool,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_header_size: Option<usize>,
+    pub(crate) max_headers: Option<usize>,
+    pub(crate) max_body_size: Option<usize>,
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            protocols: Protocols::default(),
+            connection_errors: ConnectionErrors::default(),
+            drain_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            header_read_timeout: None,
+            keep_alive: true,
+            idle_timeout: None,
+            max_header_size: None,
+            max_headers: None,
+            max_body_size: None,
+            max_connections: None,
+        }
+    }
+}
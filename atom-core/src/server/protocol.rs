@@ -0,0 +1,398 @@
+//! Defines HTTP protocol selection for accepted connections.
+//!
+//! In the default `Protocols::Auto` mode, TLS connections use the protocol negotiated via ALPN,
+//! falling back to HTTP/1.1 for clients which don't negotiate one. On plain connections the first
+//! bytes sent by the client are inspected before any request is parsed. If they match the HTTP/2
+//! connection preface the connection is served by hyper's HTTP/2 implementation, otherwise it is
+//! served as HTTP/1.1, which gives h2c (with prior knowledge) alongside HTTP/1.1.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use futures_util::future::{self, Either, FutureExt};
+use hyper::server::conn::{http1, http2};
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::handler::NewHandler;
+use crate::server::idle;
+use crate::server::rt::{TokioExecutor, TokioTimer};
+use crate::server::shutdown::{self, Watcher};
+use crate::server::upgrade::UpgradeTasks;
+use crate::server::Settings;
+use crate::service::ConnectedGothamService;
+use crate::tls::TlsInfo;
+
+/// The connection preface every HTTP/2 client sends before its first frame (RFC 7540, 3.5).
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// ALPN protocol identifiers, as registered with IANA.
+const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// The smallest read buffer hyper's HTTP/1 implementation accepts.
+const MINIMUM_MAX_BUF_SIZE: usize = 8192;
+
+/// Selects which HTTP versions a listener is willing to speak.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocols {
+    /// Only serve HTTP/1.x. This was the only behaviour before HTTP/2 support was added.
+    Http1Only,
+    /// Only serve HTTP/2; clients must use prior knowledge (h2c) or negotiate `h2` via ALPN.
+    Http2Only,
+    /// Serve both, choosing per connection based on ALPN and the HTTP/2 connection preface.
+    #[default]
+    Auto,
+}
+
+impl Protocols {
+    /// The ALPN protocol identifiers advertised by TLS listeners, in order of preference.
+    pub(crate) fn alpn_protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            Protocols::Http1Only => vec![ALPN_HTTP1.to_vec()],
+            Protocols::Http2Only => vec![ALPN_H2.to_vec()],
+            Protocols::Auto => vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()],
+        }
+    }
+}
+
+/// The HTTP protocol in use on the connection which carried the current request.
+///
+/// This is stored in `State` by Gotham before the `Router` is invoked, and can be accessed via
+/// `Protocol::borrow_from(&state)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP/1.0 or HTTP/1.1.
+    Http1,
+    /// HTTP/2, either over TLS (h2) or cleartext (h2c).
+    Http2,
+}
+
+/// Holds the per-protocol hyper connection builders used to serve accepted connections.
+pub(crate) struct Http {
+    protocols: Protocols,
+    http1: http1::Builder,
+    http2: http2::Builder<TokioExecutor>,
+}
+
+impl Http {
+    pub(crate) fn new(settings: &Settings) -> Http {
+        let mut http1 = http1::Builder::new();
+        let mut http2 = http2::Builder::new(TokioExecutor);
+
+        http1.keep_alive(settings.keep_alive).timer(TokioTimer);
+        http2.timer(TokioTimer);
+
+        if let Some(timeout) = settings.header_read_timeout {
+            http1.header_read_timeout(timeout);
+        }
+
+        if let Some(size) = settings.max_header_size {
+            // hyper refuses to use a read buffer smaller than this.
+            http1.max_buf_size(size.max(MINIMUM_MAX_BUF_SIZE));
+            http2.max_header_list_size(u32::try_from(size).unwrap_or(u32::MAX));
+        }
+
+        Http {
+            protocols: settings.protocols,
+            http1,
+            http2,
+        }
+    }
+
+    /// Determines which protocol the client is speaking, without consuming any of its bytes.
+    ///
+    /// Plain connections are sniffed, which waits for the client to send data; callers are
+    /// expected to bound this with a timeout.
+    pub(crate) async fn detect<I>(
+        &self,
+        mut io: I,
+        tls_info: Option<&TlsInfo>,
+    ) -> io::Result<(Rewind<I>, Protocol)>
+    where
+        I: AsyncRead + Unpin,
+    {
+        match (self.protocols, tls_info) {
+            (Protocols::Http1Only, _) => Ok((Rewind::new(io), Protocol::Http1)),
+            (Protocols::Http2Only, _) => Ok((Rewind::new(io), Protocol::Http2)),
+            (Protocols::Auto, Some(tls_info)) => {
+                // HTTP/2 over TLS is only spoken once negotiated via ALPN (RFC 7540, 3.3).
+                let protocol = match tls_info.alpn_protocol() {
+                    Some(ALPN_H2) => Protocol::Http2,
+                    _ => Protocol::Http1,
+                };
+
+                trace!(" selected {:?} from ALPN", protocol);
+                Ok((Rewind::new(io), protocol))
+            }
+            (Protocols::Auto, None) => {
+                let mut buf = [0u8; H2_PREFACE.len()];
+                let mut len = 0;
+
+                // Read until we either have the full preface, or the bytes seen so far can no
+                // longer be the start of it.
+                while len < buf.len() && H2_PREFACE.starts_with(&buf[..len]) {
+                    match io.read(&mut buf[len..]).await? {
+                        0 => break,
+                        n => len += n,
+                    }
+                }
+
+                let protocol = if &buf[..len] == H2_PREFACE {
+                    Protocol::Http2
+                } else {
+                    Protocol::Http1
+                };
+
+                trace!(
+                    " detected {:?} from {} byte connection prefix",
+                    protocol,
+                    len
+                );
+
+                let prefix = Bytes::copy_from_slice(&buf[..len]);
+                Ok((Rewind::with_prefix(io, prefix), protocol))
+            }
+        }
+    }
+
+    /// Serves the connection with the given protocol until the client disconnects, or until the
+    /// connection has been closed gracefully after `watcher` observes a shutdown request or the
+    /// connection has been idle for `idle_timeout`.
+    ///
+    /// Connections upgraded by its requests are served by `upgrades` until they complete, or are
+    /// closed once the connection has been idle for `idle_timeout`.
+    pub(crate) async fn serve_connection<I, T>(
+        &self,
+        io: Rewind<I>,
+        protocol: Protocol,
+        service: ConnectedGothamService<T>,
+        upgrades: UpgradeTasks,
+        watcher: Watcher,
+        idle_timeout: Option<Duration>,
+    ) -> hyper::Result<()>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        T: NewHandler + 'static,
+    {
+        let (io, idle) = idle::track(io, idle_timeout.unwrap_or(Duration::MAX));
+
+        let expired = |idle: idle::Idle| match idle_timeout {
+            Some(_) => Either::Left(idle.expired()),
+            None => Either::Right(future::pending()),
+        };
+        let trigger = future::select(
+            Box::pin(watcher.requested()),
+            Box::pin(expired(idle.clone())),
+        )
+        .map(|_| ());
+
+        let served = async {
+            match protocol {
+                Protocol::Http1 => {
+                    let conn = self.http1.serve_connection(io, service).with_upgrades();
+                    shutdown::graceful(conn, trigger, |conn| conn.graceful_shutdown()).await
+                }
+                Protocol::Http2 => {
+                    let conn = self.http2.serve_connection(io, service);
+                    shutdown::graceful(conn, trigger, |conn| conn.graceful_shutdown()).await
+                }
+            }
+        };
+
+        // Upgraded connections use the same IO, so they're idle whenever the connection is.
+        let upgraded = future::select(Box::pin(upgrades.run()), Box::pin(expired(idle)));
+
+        let (result, _) = future::join(served, upgraded).await;
+        result
+    }
+}
+
+/// An IO wrapper which replays bytes that were read ahead (e.g. during protocol detection)
+/// before reading from the underlying transport again.
+pub(crate) struct Rewind<I> {
+    prefix: Option<Bytes>,
+    inner: I,
+}
+
+impl<I> Rewind<I> {
+    fn new(inner: I) -> Rewind<I> {
+        Rewind {
+            prefix: None,
+            inner,
+        }
+    }
+
+    fn with_prefix(inner: I, prefix: Bytes) -> Rewind<I> {
+        Rewind {
+            prefix: Some(prefix).filter(|p| !p.is_empty()),
+            inner,
+        }
+    }
+}
+
+impl<I> AsyncRead for Rewind<I>
+where
+    I: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(mut prefix) = self.prefix.take() {
+            let n = prefix.len().min(buf.remaining());
+            buf.put_slice(&prefix[..n]);
+            prefix.advance(n);
+
+            if !prefix.is_empty() {
+                self.prefix = Some(prefix);
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<I> AsyncWrite for Rewind<I>
+where
+    I: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(protocols: Protocols, input: &'static [u8]) -> (Protocol, Vec<u8>) {
+        detect_tls(protocols, None, input)
+    }
+
+    fn detect_tls(
+        protocols: Protocols,
+        tls_info: Option<TlsInfo>,
+        input: &'static [u8],
+    ) -> (Protocol, Vec<u8>) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let settings = Settings {
+                protocols,
+                ..Settings::default()
+            };
+            let (mut io, protocol) = Http::new(&settings)
+                .detect(input, tls_info.as_ref())
+                .await
+                .unwrap();
+            let mut replayed = Vec::new();
+            io.read_to_end(&mut replayed).await.unwrap();
+            (protocol, replayed)
+        })
+    }
+
+    #[test]
+    fn detects_http2_preface() {
+        let input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04";
+        assert_eq!(
+            detect(Protocols::Auto, input),
+            (Protocol::Http2, input.to_vec())
+        );
+    }
+
+    #[test]
+    fn falls_back_to_http1() {
+        let input = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            detect(Protocols::Auto, input),
+            (Protocol::Http1, input.to_vec())
+        );
+    }
+
+    #[test]
+    fn short_input_is_http1() {
+        assert_eq!(
+            detect(Protocols::Auto, b"PRI"),
+            (Protocol::Http1, b"PRI".to_vec())
+        );
+        assert_eq!(detect(Protocols::Auto, b""), (Protocol::Http1, vec![]));
+    }
+
+    #[test]
+    fn fixed_protocols_skip_detection() {
+        let input = b"GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            detect(Protocols::Http2Only, input),
+            (Protocol::Http2, input.to_vec())
+        );
+        assert_eq!(
+            detect(Protocols::Http1Only, input),
+            (Protocol::Http1, input.to_vec())
+        );
+    }
+
+    #[test]
+    fn tls_connections_use_alpn() {
+        let preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let alpn = |protocol: Option<&[u8]>| Some(TlsInfo::with_alpn(protocol));
+
+        assert_eq!(
+            detect_tls(Protocols::Auto, alpn(Some(b"h2")), request),
+            (Protocol::Http2, request.to_vec())
+        );
+        assert_eq!(
+            detect_tls(Protocols::Auto, alpn(Some(b"http/1.1")), preface),
+            (Protocol::Http1, preface.to_vec())
+        );
+        assert_eq!(
+            detect_tls(Protocols::Auto, alpn(None), preface),
+            (Protocol::Http1, preface.to_vec())
+        );
+    }
+
+    #[test]
+    fn alpn_preference() {
+        assert_eq!(
+            Protocols::Auto.alpn_protocols(),
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        assert_eq!(
+            Protocols::Http1Only.alpn_protocols(),
+            vec![b"http/1.1".to_vec()]
+        );
+    }
+}
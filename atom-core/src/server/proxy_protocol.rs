@@ -0,0 +1,490 @@
+//! Defines `ProxyProtocolListener`, which accepts connections forwarded by a load balancer using
+//! the PROXY protocol.
+//!
+//! Load balancers such as HAProxy or AWS NLB/ELB open a new connection to the server for each
+//! client, so the TCP peer address is the balancer's own. With the PROXY protocol enabled, the
+//! balancer sends a short header describing the original connection before any other data,
+//! in either the text (v1) or binary (v2) format:
+//!
+//! ```text
+//! PROXY TCP4 203.0.113.7 192.0.2.10 51234 443\r\n
+//! ```
+//!
+//! The header is read before the TLS or HTTP handshake, and the source address it contains is
+//! stored in `State` as the `ClientAddr`. See the [specification] for details.
+//!
+//! [specification]: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Semaphore;
+
+use crate::server::listener::{ListenAddr, Listener};
+use crate::server::{ConnectionError, ConnectionErrors};
+use crate::state::ClientAddr;
+
+/// How long a connection is given to send its PROXY protocol header, and to complete the TLS
+/// handshake and send its first bytes, unless configured otherwise.
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// The signature which starts every v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The length of the v2 address block for each address family, without any TLVs.
+const V2_IPV4_LENGTH: usize = 12;
+const V2_IPV6_LENGTH: usize = 36;
+const V2_UNIX_LENGTH: usize = 216;
+
+/// The most TLV data accepted after the addresses of a v2 header. TLVs are skipped, but the
+/// largest sent by common proxies (e.g. the TLS details from HAProxy) fit comfortably.
+const V2_MAX_TLV_LENGTH: usize = 2048;
+
+/// The number of connections whose headers may be read at once before accepting pauses.
+const MAX_PENDING: usize = 1024;
+
+type PendingConnection<Io> = Pin<Box<dyn Future<Output = Option<(Io, ClientAddr)>> + Send>>;
+
+/// A `Listener` which expects every connection to start with a PROXY protocol v1 or v2 header,
+/// and reports the client address described by that header.
+///
+/// Connections which send a malformed header, or none within the header timeout, are closed and
+/// reported via `ConnectionErrors`. Headers sent for health checks (`LOCAL` in v2, `UNKNOWN` in
+/// v1) and for non-IP transports are accepted, and the connection keeps the address of the peer
+/// which opened it. When the server limits its connections with `ServerBuilder::max_connections`,
+/// connections whose headers are still being read count towards that limit.
+///
+/// Only enable this when every connection comes through a trusted proxy, since otherwise any
+/// client can choose the address it appears to connect from. `ServerBuilder::proxy_protocol`
+/// wraps the server's listener automatically:
+///
+/// ```rust,ignore
+/// ServerBuilder::new()
+///     .proxy_protocol(true)
+///     .start_tls("0.0.0.0:8443", || Ok(router()), tls_config)
+/// ```
+pub struct ProxyProtocolListener<L: Listener> {
+    listener: L,
+    header_timeout: Duration,
+    connection_errors: ConnectionErrors,
+    limit: Option<Arc<Semaphore>>,
+    pending: FuturesUnordered<PendingConnection<L::Io>>,
+}
+
+impl<L: Listener> ProxyProtocolListener<L> {
+    /// Wraps `listener`, using the default header timeout and reporting malformed headers to a
+    /// new `ConnectionErrors`.
+    pub fn new(listener: L) -> ProxyProtocolListener<L> {
+        ProxyProtocolListener {
+            listener,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            connection_errors: ConnectionErrors::default(),
+            limit: None,
+            pending: FuturesUnordered::new(),
+        }
+    }
+
+    /// Closes connections which take longer than `timeout` to send their header.
+    pub fn header_timeout(mut self, timeout: Duration) -> ProxyProtocolListener<L> {
+        self.header_timeout = timeout;
+        self
+    }
+
+    /// Sets how connections with missing or malformed headers are reported.
+    pub fn connection_errors(mut self, errors: ConnectionErrors) -> ProxyProtocolListener<L> {
+        self.connection_errors = errors;
+        self
+    }
+
+    /// Counts connections whose headers are being read against the server's connection limit.
+    pub(crate) fn connection_limit(
+        mut self,
+        limit: Option<Arc<Semaphore>>,
+    ) -> ProxyProtocolListener<L> {
+        self.limit = limit;
+        self
+    }
+
+    /// The number of connections whose headers may be read at once. With a connection limit,
+    /// this is the permit held by the server while it accepts, plus those still available.
+    fn max_pending(&self) -> usize {
+        match self.limit {
+            Some(ref limit) => MAX_PENDING.min(limit.available_permits() + 1),
+            None => MAX_PENDING,
+        }
+    }
+}
+
+impl<L: Listener> Listener for ProxyProtocolListener<L> {
+    type Io = L::Io;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(L::Io, ClientAddr)>> {
+        // Headers are read concurrently, so a slow connection doesn't hold up the others.
+        while self.pending.len() < self.max_pending() {
+            match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok((socket, addr))) => {
+                    let timeout = self.header_timeout;
+                    let errors = self.connection_errors.clone();
+                    self.pending
+                        .push(Box::pin(accept_proxied(socket, addr, timeout, errors)));
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match self.pending.poll_next_unpin(cx) {
+                Poll::Ready(Some(Some(accepted))) => return Poll::Ready(Ok(accepted)),
+                Poll::Ready(Some(None)) => continue,
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        self.listener.local_addr()
+    }
+}
+
+async fn accept_proxied<Io>(
+    mut socket: Io,
+    addr: ClientAddr,
+    timeout: Duration,
+    errors: ConnectionErrors,
+) -> Option<(Io, ClientAddr)>
+where
+    Io: AsyncRead + Unpin,
+{
+    let header = match tokio::time::timeout(timeout, read_header(&mut socket)).await {
+        Ok(header) => header,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out reading the PROXY protocol header",
+        )),
+    };
+
+    match header {
+        Ok(Some(source)) => Some((socket, ClientAddr::Tcp(source))),
+        Ok(None) => Some((socket, addr)),
+        Err(err) => {
+            errors.report(ConnectionError::from_io(err, addr));
+            None
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {}", message),
+    )
+}
+
+/// Reads a v1 or v2 header from `io`, consuming nothing beyond it, and returns the source
+/// address of the proxied connection if it describes one.
+pub(crate) async fn read_header<Io>(io: &mut Io) -> io::Result<Option<SocketAddr>>
+where
+    Io: AsyncRead + Unpin,
+{
+    // Both versions are at least this long, so it's safe to read before knowing which is used.
+    let mut start = [0; 12];
+    io.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(io).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(io, &start).await
+    } else {
+        Err(invalid("missing signature"))
+    }
+}
+
+async fn read_v1<Io>(io: &mut Io, start: &[u8]) -> io::Result<Option<SocketAddr>>
+where
+    Io: AsyncRead + Unpin,
+{
+    // The header is read a byte at a time, as any data following it belongs to the connection.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("line too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not ASCII"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = line.split(' ').skip(1);
+
+    let family = fields.next().ok_or_else(|| invalid("missing protocol"))?;
+    if family == "UNKNOWN" {
+        return Ok(None);
+    }
+
+    let fields = fields.collect::<Vec<_>>();
+    let (source, port) = match (family, fields.as_slice()) {
+        ("TCP4", [source, _, port, _]) => (
+            source.parse::<Ipv4Addr>().map(IpAddr::from),
+            port.parse::<u16>(),
+        ),
+        ("TCP6", [source, _, port, _]) => (
+            source.parse::<Ipv6Addr>().map(IpAddr::from),
+            port.parse::<u16>(),
+        ),
+        ("TCP4" | "TCP6", _) => return Err(invalid("wrong number of fields")),
+        _ => return Err(invalid("unsupported protocol")),
+    };
+
+    let source = source.map_err(|_| invalid("malformed source address"))?;
+    let port = port.map_err(|_| invalid("malformed source port"))?;
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+async fn read_v2<Io>(io: &mut Io) -> io::Result<Option<SocketAddr>>
+where
+    Io: AsyncRead + Unpin,
+{
+    let mut fixed = [0; 4];
+    io.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    // LOCAL: the proxy connected on its own behalf, e.g. for a health check.
+    let local = match version_command & 0x0f {
+        0x0 => true,
+        0x1 => false,
+        _ => return Err(invalid("unsupported command")),
+    };
+
+    let addresses_len = match family >> 4 {
+        0x0 => 0,
+        0x1 => V2_IPV4_LENGTH,
+        0x2 => V2_IPV6_LENGTH,
+        0x3 => V2_UNIX_LENGTH,
+        _ => return Err(invalid("unsupported address family")),
+    };
+
+    // The block is checked before it's read, so a client can't make us buffer more than a
+    // header needs.
+    let len = u16::from_be_bytes([len_high, len_low]) as usize;
+    if len > addresses_len + V2_MAX_TLV_LENGTH {
+        return Err(invalid("address block too long"));
+    }
+    if len < addresses_len && !local {
+        return Err(invalid("truncated addresses"));
+    }
+
+    let mut addresses = vec![0; len];
+    io.read_exact(&mut addresses).await?;
+
+    if local {
+        return Ok(None);
+    }
+
+    let source = match family >> 4 {
+        0x1 => {
+            let ip = <[u8; 4]>::try_from(&addresses[..4]).unwrap();
+            SocketAddr::new(
+                IpAddr::from(ip),
+                u16::from_be_bytes([addresses[8], addresses[9]]),
+            )
+        }
+        0x2 => {
+            let ip = <[u8; 16]>::try_from(&addresses[..16]).unwrap();
+            SocketAddr::new(
+                IpAddr::from(ip),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )
+        }
+        // Unspecified or Unix socket addresses say nothing useful about the client.
+        _ => return Ok(None),
+    };
+
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let header = read_header(&mut input).await;
+        (header, input)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (header, rest) = parse(b"PROXY TCP4 203.0.113.7 192.0.2.10 51234 443\r\nGET /").await;
+        assert_eq!(header.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 8080 80\r\n").await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:8080".parse().unwrap()));
+
+        let (header, rest) = parse(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut ipv4 = vec![203, 0, 113, 7, 192, 0, 2, 10];
+        ipv4.extend_from_slice(&51234u16.to_be_bytes());
+        ipv4.extend_from_slice(&443u16.to_be_bytes());
+        // Trailing TLVs are skipped.
+        ipv4.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+
+        let input = v2(0x1, 0x11, &ipv4);
+        let (header, rest) = parse(&input).await;
+        assert_eq!(header.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let mut ipv6 = vec![0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&8080u16.to_be_bytes());
+        let (header, _) = parse(&v2(0x1, 0x21, &ipv6)).await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:8080".parse().unwrap()));
+
+        let local = v2(0x0, 0x00, &[]);
+        let (header, rest) = parse(&local).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_headers() {
+        let too_long = b"PROXY ".repeat(20);
+        let malformed: &[&[u8]] = &[
+            b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.10 51234\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.10 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.10 99999 443\r\n",
+            b"PROXY UDP4 203.0.113.7 192.0.2.10 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.10 51234 443",
+            &too_long,
+            &v2(0x1, 0x11, &[203, 0, 113, 7]),
+            &v2(0x2, 0x11, &[0; 12]),
+            &v2(0x1, 0x41, &[0; 12]),
+        ];
+
+        for input in malformed {
+            let (header, _) = parse(input).await;
+            assert!(header.is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_v2_headers_before_reading_the_addresses() {
+        // None of these include the address block their length announces.
+        let announce = |version_command: u8, family: u8, len: u16| {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend_from_slice(&[version_command, family]);
+            header.extend_from_slice(&len.to_be_bytes());
+            header
+        };
+
+        let inputs = [
+            (announce(0x31, 0x11, 12), "unsupported version"),
+            (announce(0x22, 0x11, 12), "unsupported command"),
+            (announce(0x21, 0x41, 12), "unsupported address family"),
+            (announce(0x21, 0x11, u16::MAX), "address block too long"),
+            (announce(0x20, 0x00, u16::MAX), "address block too long"),
+            (announce(0x21, 0x21, 12), "truncated addresses"),
+        ];
+
+        for (input, message) in inputs {
+            let err = parse(&input).await.0.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().ends_with(message), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_reports_client_address() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let errors = ConnectionErrors::new();
+        let mut listener = ProxyProtocolListener::new(listener).connection_errors(errors.clone());
+
+        let mut malformed = TcpStream::connect(addr).await.unwrap();
+        malformed.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut proxied = TcpStream::connect(addr).await.unwrap();
+        proxied
+            .write_all(b"PROXY TCP4 203.0.113.7 192.0.2.10 51234 443\r\nping")
+            .await
+            .unwrap();
+
+        let (mut socket, client) = futures_util::future::poll_fn(|cx| listener.poll_accept(cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.socket_addr(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+
+        // The malformed connection is closed rather than accepted.
+        let next = futures_util::future::poll_fn(|cx| listener.poll_accept(cx));
+        assert!(tokio::time::timeout(Duration::from_millis(100), next)
+            .await
+            .is_err());
+        assert_eq!(errors.counts().total(), 1);
+
+        let mut data = [0; 4];
+        socket.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"ping");
+    }
+
+    #[tokio::test]
+    async fn pending_connections_count_towards_the_connection_limit() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limit = Arc::new(Semaphore::new(2));
+        let mut listener =
+            ProxyProtocolListener::new(listener).connection_limit(Some(limit.clone()));
+
+        // The server holds a permit while it accepts, leaving room for one more connection.
+        let _accepting = limit.clone().acquire_owned().await.unwrap();
+        let mut silent = Vec::new();
+        for _ in 0..4 {
+            silent.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let accept = futures_util::future::poll_fn(|cx| listener.poll_accept(cx));
+        assert!(tokio::time::timeout(Duration::from_millis(100), accept)
+            .await
+            .is_err());
+        assert_eq!(listener.pending.len(), 2);
+    }
+}
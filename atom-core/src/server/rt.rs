@@ -0,0 +1,59 @@
+//! Glue between hyper's runtime traits and tokio.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::rt::{Executor, Sleep, Timer};
+use pin_project::pin_project;
+
+/// Spawns the background tasks hyper requires (e.g. one per HTTP/2 stream) onto the tokio
+/// runtime which is driving the connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TokioExecutor;
+
+impl<F> Executor<F> for TokioExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::spawn(future);
+    }
+}
+
+/// Provides hyper with tokio timers, which it needs to enforce timeouts such as the header read
+/// timeout.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TokioTimer;
+
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Sleep>> {
+        Box::pin(TokioSleep {
+            inner: tokio::time::sleep(duration),
+        })
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Sleep>> {
+        Box::pin(TokioSleep {
+            inner: tokio::time::sleep_until(deadline.into()),
+        })
+    }
+}
+
+#[pin_project]
+struct TokioSleep {
+    #[pin]
+    inner: tokio::time::Sleep,
+}
+
+impl Future for TokioSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.project().inner.poll(cx)
+    }
+}
+
+impl Sleep for TokioSleep {}
@@ -0,0 +1,164 @@
+//! Defines the bookkeeping used to drain connections when a server is shut down.
+//!
+//! Every connection accepted by `bind_server` is spawned into a `Connections` set, and is handed
+//! a `Watcher`. Once shutdown is requested the accept loop stops, each `Watcher` asks its
+//! connection to finish gracefully (completing in-flight requests, but not accepting new ones),
+//! and the set is awaited until it is empty or the drain timeout elapses. Any connection still
+//! open at that point is aborted.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use log::{info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// The default amount of time given to in-flight connections to complete once shutdown has been
+/// requested.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The set of connection tasks spawned by a running server.
+pub(crate) struct Connections {
+    tasks: JoinSet<()>,
+    signal: watch::Sender<bool>,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Connections {
+        let (signal, _) = watch::channel(false);
+        Connections {
+            tasks: JoinSet::new(),
+            signal,
+        }
+    }
+
+    /// Spawns a connection task, which receives a `Watcher` to observe shutdown requests.
+    pub(crate) fn spawn<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce(Watcher) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let watcher = Watcher {
+            signal: self.signal.subscribe(),
+        };
+        self.tasks.spawn(f(watcher));
+    }
+
+    /// Returns `true` if there are no connection tasks still running.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Waits for any one connection task to complete, releasing its resources.
+    pub(crate) async fn reap(&mut self) {
+        self.tasks.join_next().await;
+    }
+
+    /// Requests that all connections shut down gracefully, and waits up to `timeout` for them to
+    /// do so. Connections which are still open after `timeout` are aborted.
+    pub(crate) async fn drain(mut self, timeout: Duration) {
+        info!(
+            target: "gotham::shutdown",
+            " draining {} open connection(s)", self.tasks.len()
+        );
+
+        self.signal.send_replace(true);
+
+        let drained = tokio::time::timeout(timeout, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                target: "gotham::shutdown",
+                " {} connection(s) still open after {:?}, aborting",
+                self.tasks.len(),
+                timeout
+            );
+            self.tasks.shutdown().await;
+        }
+    }
+}
+
+/// Observes shutdown requests on behalf of a single connection.
+pub(crate) struct Watcher {
+    signal: watch::Receiver<bool>,
+}
+
+impl Watcher {
+    /// Resolves once shutdown has been requested.
+    pub(crate) async fn requested(mut self) {
+        // An error means the server is gone, which is treated like a shutdown request.
+        while !*self.signal.borrow() && self.signal.changed().await.is_ok() {}
+    }
+}
+
+/// Drives `conn` to completion, invoking `on_shutdown` if `trigger` resolves first.
+pub(crate) async fn graceful<C, T, F>(conn: C, trigger: T, on_shutdown: F) -> C::Output
+where
+    C: Future,
+    T: Future<Output = ()>,
+    F: FnOnce(Pin<&mut C>),
+{
+    let mut conn = Box::pin(conn);
+
+    if let Either::Left((output, _)) = future::select(conn.as_mut(), Box::pin(trigger)).await {
+        return output;
+    }
+
+    on_shutdown(conn.as_mut());
+    conn.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn drain_notifies_watchers() {
+        runtime().block_on(async {
+            let mut connections = Connections::new();
+            let notified = Arc::new(AtomicBool::new(false));
+
+            {
+                let notified = notified.clone();
+                connections.spawn(move |watcher| async move {
+                    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+                    let mut tx = Some(tx);
+                    let _ = graceful(rx, watcher.requested(), |_| {
+                        notified.store(true, Ordering::SeqCst);
+                        tx.take().unwrap().send(()).unwrap();
+                    })
+                    .await;
+                });
+            }
+
+            connections.drain(Duration::from_secs(5)).await;
+            assert!(notified.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn drain_aborts_stragglers() {
+        runtime().block_on(async {
+            let mut connections = Connections::new();
+            connections.spawn(|watcher| async move {
+                graceful(future::pending::<()>(), watcher.requested(), |_| {}).await;
+            });
+
+            connections.drain(Duration::from_millis(10)).await;
+        });
+    }
+}
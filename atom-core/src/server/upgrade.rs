@@ -0,0 +1,102 @@
+//! Defines how upgraded connections, such as WebSockets, are served.
+//!
+//! Rather than being spawned onto the runtime, the task serving an upgraded connection runs
+//! alongside the connection it was upgraded from. It keeps holding that connection's permit, is
+//! drained (or aborted) with it on shutdown, and is closed once the connection has been idle for
+//! the configured idle timeout.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use tokio::sync::mpsc;
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawns tasks onto the connection which carried the current request.
+///
+/// This is stored in `State` for every request served by `bind_server`.
+#[derive(Clone)]
+pub(crate) struct Upgrades {
+    tasks: mpsc::UnboundedSender<Task>,
+}
+
+/// The tasks spawned through the `Upgrades` of a connection, run by that connection.
+pub(crate) struct UpgradeTasks {
+    tasks: mpsc::UnboundedReceiver<Task>,
+}
+
+/// Creates the `Upgrades` handed to the requests of a connection, along with the `UpgradeTasks`
+/// which the connection runs.
+pub(crate) fn channel() -> (Upgrades, UpgradeTasks) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (Upgrades { tasks: tx }, UpgradeTasks { tasks: rx })
+}
+
+impl Upgrades {
+    /// Runs `task` within the connection's task. If the connection has already closed, `task` is
+    /// dropped without being run.
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.tasks.send(Box::pin(task)).is_err() {
+            debug!(" connection closed before its upgrade could be served");
+        }
+    }
+}
+
+impl UpgradeTasks {
+    /// Runs every spawned task, completing once they have all completed and every `Upgrades`
+    /// has been dropped.
+    pub(crate) async fn run(mut self) {
+        let mut running = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                task = self.tasks.recv() => match task {
+                    Some(task) => running.push(task),
+                    None => break,
+                },
+                _ = running.next(), if !running.is_empty() => {}
+            }
+        }
+
+        while running.next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn runs_spawned_tasks_until_complete() {
+        let (upgrades, tasks) = channel();
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let completed = completed.clone();
+            upgrades.spawn(async move {
+                tokio::task::yield_now().await;
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let spawner = upgrades.clone();
+        drop(upgrades);
+        let run = tokio::spawn(tasks.run());
+
+        let late = completed.clone();
+        spawner.spawn(async move {
+            late.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(spawner);
+
+        run.await.unwrap();
+        assert_eq!(completed.load(Ordering::SeqCst), 4);
+    }
+}
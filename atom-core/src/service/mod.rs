@@ -1,21 +1,24 @@
 //! Defines the `GothamService` type which is used to wrap a Gotham application and interface with
 //! Hyper.
 
-use std::net::SocketAddr;
 use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 
-use futures_util::future::{BoxFuture, FutureExt};
-use hyper::service::Service;
-use hyper::{Request, Response};
+use futures_util::future::{self, BoxFuture, FutureExt};
 use hyper::body::Incoming;
+use hyper::service::Service;
+use hyper::{Request, Response, StatusCode};
 
 use crate::handler::NewHandler;
-use crate::state::State;
+use crate::helpers::http::response::create_empty_response;
+use crate::server::upgrade::Upgrades;
+use crate::server::Protocol;
+use crate::state::{ClientAddr, State};
+use crate::tls::TlsInfo;
 
 mod trap;
 
-use crate::body::Body;
+use crate::body::{self, Body};
 pub use trap::call_handler;
 
 /// Wraps a `NewHandler` which will be used to serve requests. Used in `gotham::os::*` to bind
@@ -25,6 +28,8 @@ where
     T: NewHandler + 'static,
 {
     handler: Arc<T>,
+    max_headers: Option<usize>,
+    max_body_size: Option<usize>,
 }
 
 impl<T> GothamService<T>
@@ -34,25 +39,62 @@ where
     pub(crate) fn new(handler: T) -> GothamService<T> {
         GothamService {
             handler: Arc::new(handler),
+            max_headers: None,
+            max_body_size: None,
+        }
+    }
+
+    /// Rejects requests carrying more than `max_headers` headers with
+    /// `431 Request Header Fields Too Large`, without invoking the handler.
+    pub(crate) fn with_max_headers(self, max_headers: Option<usize>) -> GothamService<T> {
+        GothamService {
+            max_headers,
+            ..self
+        }
+    }
+
+    /// Limits the size of request bodies to `max_body_size` bytes, unless the route sets its own
+    /// limit.
+    pub(crate) fn with_max_body_size(self, max_body_size: Option<usize>) -> GothamService<T> {
+        GothamService {
+            max_body_size,
+            ..self
         }
     }
 
-    pub(crate) fn connect(&self, client_addr: SocketAddr) -> ConnectedGothamService<T> {
+    pub(crate) fn connect(
+        &self,
+        client_addr: ClientAddr,
+        protocol: Protocol,
+        tls_info: Option<TlsInfo>,
+        upgrades: Upgrades,
+    ) -> ConnectedGothamService<T> {
         ConnectedGothamService {
             client_addr,
+            protocol,
+            tls_info,
+            upgrades,
             handler: self.handler.clone(),
+            max_headers: self.max_headers,
+            max_body_size: self.max_body_size,
         }
     }
 }
 
 /// A `GothamService` which has been connected to a client. The major difference is that a
-/// `client_addr` has been assigned (as this isn't available from Hyper).
+/// `client_addr`, the negotiated `protocol`, any `tls_info` and the connection's `upgrades` have
+/// been assigned (as these aren't available from Hyper).
 pub(crate) struct ConnectedGothamService<T>
 where
     T: NewHandler + 'static,
 {
     handler: Arc<T>,
-    client_addr: SocketAddr,
+    client_addr: ClientAddr,
+    protocol: Protocol,
+    tls_info: Option<TlsInfo>,
+    upgrades: Upgrades,
+    max_headers: Option<usize>,
+    max_body_size: Option<usize>,
 }
 
 // impl<T> Service<Request<Body>> for ConnectedGothamService<T>
@@ -70,15 +112,34 @@ where
 // }
 
 impl<T> Service<Request<Incoming>> for ConnectedGothamService<T>
-    where
-        T: NewHandler,
+where
+    T: NewHandler,
 {
     type Response = Response<Body>;
     type Error = anyhow::Error;
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn call(&mut self, req: Request<Incoming>) -> Self::Future {
-        let state = State::from_request_incoming(req, self.client_addr);
+        let too_many_headers = matches!(self.max_headers, Some(max) if req.headers().len() > max);
+
+        let mut state = State::from_request_incoming(req, self.client_addr.clone());
+        state.put(self.protocol);
+        state.put(self.upgrades.clone());
+
+        if let Some(ref tls_info) = self.tls_info {
+            state.put(tls_info.clone());
+        }
+
+        if let Some(limit) = self.max_body_size {
+            body::limit_request_body(&mut state, limit);
+        }
+
+        if too_many_headers {
+            let response =
+                create_empty_response(&state, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            return future::ok(response).boxed();
+        }
+
         call_handler(self.handler.clone(), AssertUnwindSafe(state)).boxed()
     }
 }
@@ -91,7 +152,7 @@ impl<T> Service<Request<Incoming>> for ConnectedGothamService<T>
 //
 //     use crate::helpers::http::response::create_empty_response;
 //     use crate::router::builder::*;
-//     use crate::state::State;
+//     use crate::state::{ClientAddr, State};
 //
 //     fn handler(state: State) -> (State, Response<Body>) {
 //         let res = create_empty_response(&state, StatusCode::ACCEPTED);
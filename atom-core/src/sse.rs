@@ -0,0 +1,404 @@
+//! Defines `Sse`, a responder which streams Server-Sent Events to the client.
+//!
+//! ```rust,ignore
+//! use futures_util::stream::{self, StreamExt};
+//!
+//! async fn ticks(state: &mut State) -> Result<Sse<impl TryStream<Ok = Event>>, HandlerError> {
+//!     // Resume after the last event seen by a reconnecting client.
+//!     let start = match last_event_id(state) {
+//!         Some(id) => id.parse::<u64>()? + 1,
+//!         None => 0,
+//!     };
+//!
+//!     let events = stream::iter(start..).then(|n| async move {
+//!         tokio::time::sleep(Duration::from_secs(1)).await;
+//!         Ok::<_, Infallible>(Event::default().id(n.to_string()).data("tick"))
+//!     });
+//!
+//!     Ok(Sse::new(events))
+//! }
+//! ```
+
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::stream::{Stream, TryStream};
+use hyper::header::{HeaderMap, HeaderValue, CACHE_CONTROL};
+use hyper::{Response, StatusCode};
+use pin_project_lite::pin_project;
+use serde::Serialize;
+use tokio::time::{Instant, Sleep};
+
+use crate::body::Body;
+use crate::error::BoxError;
+use crate::handler::IntoResponse;
+use crate::helpers::http::header::LAST_EVENT_ID;
+use crate::helpers::http::response::create_response;
+use crate::state::State;
+
+/// The interval at which `Sse` sends keep-alive comments unless configured otherwise.
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Returns the `Last-Event-ID` sent by a client reconnecting to an event stream, i.e. the `id`
+/// of the last event it received, so the stream can resume from there.
+pub fn last_event_id(state: &State) -> Option<&str> {
+    state
+        .borrow::<HeaderMap>()
+        .get(LAST_EVENT_ID)
+        .and_then(|id| id.to_str().ok())
+}
+
+/// A single Server-Sent Event.
+///
+/// Every field is optional; an event with only a `comment` is ignored by clients, which makes it
+/// useful for keeping connections alive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    /// Sets the data of the event. Multi-line data is sent as multiple `data:` lines, which the
+    /// client joins back together.
+    pub fn data<T: Into<String>>(mut self, data: T) -> Event {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Sets the data of the event to `data`, serialized as JSON.
+    pub fn json_data<T: Serialize>(self, data: &T) -> Result<Event, serde_json::Error> {
+        Ok(self.data(serde_json::to_string(data)?))
+    }
+
+    /// Sets the type of the event, which the client dispatches to its listeners for that type
+    /// rather than to `onmessage`.
+    ///
+    /// # Panics
+    ///
+    /// If `event` contains a line break.
+    pub fn event<T: Into<String>>(mut self, event: T) -> Event {
+        let event = event.into();
+        assert!(
+            !has_line_break(&event),
+            "SSE event type must not contain line breaks"
+        );
+        self.event = Some(event);
+        self
+    }
+
+    /// Sets the ID of the event, which the client sends back as `Last-Event-ID` when it
+    /// reconnects.
+    ///
+    /// # Panics
+    ///
+    /// If `id` contains a line break or a null character.
+    pub fn id<T: Into<String>>(mut self, id: T) -> Event {
+        let id = id.into();
+        assert!(
+            !has_line_break(&id) && !id.contains('\0'),
+            "SSE event ID must not contain line breaks or null characters"
+        );
+        self.id = Some(id);
+        self
+    }
+
+    /// Sets how long the client waits before reconnecting if the connection is lost.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Sets a comment, which is sent to the client but ignored by it. Multi-line comments are
+    /// sent as multiple comment lines.
+    pub fn comment<T: Into<String>>(mut self, comment: T) -> Event {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    fn into_bytes(self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+}
+
+fn has_line_break(s: &str) -> bool {
+    s.contains(['\n', '\r'])
+}
+
+/// Writes `value` as one `field` line per line of `value`. Lines end at CRLF, LF or a lone CR,
+/// as they do for the client, so `value` can't start a field of its own.
+fn write_lines(f: &mut fmt::Formatter<'_>, field: &str, value: &str) -> fmt::Result {
+    for line in value
+        .split("\r\n")
+        .flat_map(|line| line.split(['\r', '\n']))
+    {
+        writeln!(f, "{}{}", field, line)?;
+    }
+    Ok(())
+}
+
+/// Formats the event in the `text/event-stream` wire format, including the blank line which
+/// terminates it.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(comment) = &self.comment {
+            write_lines(f, ":", comment)?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event:{}", event)?;
+        }
+        if let Some(data) = &self.data {
+            write_lines(f, "data:", data)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id:{}", id)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry:{}", retry.as_millis())?;
+        }
+        f.write_char('\n')
+    }
+}
+
+/// Configures the comments `Sse` sends while no events are ready, which stop proxies and clients
+/// from closing idle connections.
+#[derive(Clone, Debug)]
+pub struct KeepAlive {
+    interval: Duration,
+    comment: Bytes,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive::new()
+    }
+}
+
+impl KeepAlive {
+    /// Creates a `KeepAlive` which sends an empty comment every `DEFAULT_KEEP_ALIVE_INTERVAL`.
+    pub fn new() -> KeepAlive {
+        KeepAlive {
+            interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            comment: Bytes::from_static(b":\n\n"),
+        }
+    }
+
+    /// Sets how long the stream may be idle before a keep-alive comment is sent.
+    pub fn interval(mut self, interval: Duration) -> KeepAlive {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the text of the keep-alive comment.
+    pub fn text<T: AsRef<str>>(mut self, text: T) -> KeepAlive {
+        self.comment = Event::default().comment(text.as_ref()).into_bytes();
+        self
+    }
+}
+
+/// A `text/event-stream` response, streaming each `Event` from `S` to the client as it is
+/// produced.
+///
+/// The response disables caching and proxy buffering, and sends a keep-alive comment whenever
+/// the stream has been idle for the `KeepAlive` interval. If `S` yields an error the response is
+/// aborted, and the client will reconnect with the `Last-Event-ID` it last saw.
+pub struct Sse<S> {
+    events: S,
+    keep_alive: Option<KeepAlive>,
+}
+
+impl<S> Sse<S>
+where
+    S: TryStream<Ok = Event> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    /// Creates an `Sse` response from a stream of events, with the default `KeepAlive`.
+    pub fn new(events: S) -> Sse<S> {
+        Sse {
+            events,
+            keep_alive: Some(KeepAlive::new()),
+        }
+    }
+
+    /// Replaces the default `KeepAlive` configuration.
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Sse<S> {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Disables keep-alive comments, for streams which produce events frequently.
+    pub fn without_keep_alive(mut self) -> Sse<S> {
+        self.keep_alive = None;
+        self
+    }
+}
+
+impl<S> fmt::Debug for Sse<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sse")
+            .field("keep_alive", &self.keep_alive)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> IntoResponse for Sse<S>
+where
+    S: TryStream<Ok = Event> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    fn into_response(self, state: &State) -> Response<Body> {
+        let sleep = self
+            .keep_alive
+            .as_ref()
+            .map(|keep_alive| tokio::time::sleep(keep_alive.interval));
+
+        let body = Body::from_stream(SseStream {
+            events: self.events,
+            keep_alive: self.keep_alive,
+            sleep,
+        });
+
+        let mut res = create_response(state, StatusCode::OK, mime::TEXT_EVENT_STREAM, body);
+        let headers = res.headers_mut();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+        res
+    }
+}
+
+pin_project! {
+    struct SseStream<S> {
+        #[pin]
+        events: S,
+        keep_alive: Option<KeepAlive>,
+        #[pin]
+        sleep: Option<Sleep>,
+    }
+}
+
+impl<S> Stream for SseStream<S>
+where
+    S: TryStream<Ok = Event>,
+    S::Error: Into<BoxError>,
+{
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        let (keep_alive, mut sleep) = match (this.keep_alive.as_ref(), this.sleep.as_pin_mut()) {
+            (Some(keep_alive), Some(sleep)) => (keep_alive, sleep),
+            _ => {
+                return this
+                    .events
+                    .try_poll_next(cx)
+                    .map_ok(Event::into_bytes)
+                    .map_err(Into::into)
+            }
+        };
+
+        let chunk = match this.events.try_poll_next(cx) {
+            Poll::Ready(Some(Ok(event))) => event.into_bytes(),
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err.into()))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {
+                futures_util::ready!(sleep.as_mut().poll(cx));
+                keep_alive.comment.clone()
+            }
+        };
+
+        // Any data sent restarts the idle interval.
+        sleep.reset(Instant::now() + keep_alive.interval);
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::{self, StreamExt};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::Request;
+    use std::convert::Infallible;
+
+    #[test]
+    fn formats_events() {
+        let event = Event::default()
+            .comment("hello")
+            .event("update")
+            .data("line one\nline two\r\n")
+            .id("42")
+            .retry(Duration::from_secs(3));
+
+        assert_eq!(
+            event.to_string(),
+            ":hello\nevent:update\ndata:line one\ndata:line two\ndata:\nid:42\nretry:3000\n\n"
+        );
+
+        let event = Event::default().data("x\rid:evil").comment("a\rb");
+        assert_eq!(event.to_string(), ":a\n:b\ndata:x\ndata:id:evil\n\n");
+
+        let event = Event::default().json_data(&[1, 2]).unwrap();
+        assert_eq!(event.to_string(), "data:[1,2]\n\n");
+
+        let keep_alive = KeepAlive::new().text("ping");
+        assert_eq!(keep_alive.comment, ":ping\n\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "must not contain line breaks")]
+    fn rejects_multi_line_ids() {
+        let _ = Event::default().id("1\n2");
+    }
+
+    fn state(last_event_id: Option<&str>) -> State {
+        let mut req = Request::get("http://localhost/events");
+        if let Some(id) = last_event_id {
+            req = req.header(LAST_EVENT_ID, id);
+        }
+
+        State::from_request(
+            req.body(Body::empty()).unwrap(),
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        )
+    }
+
+    #[test]
+    fn reads_last_event_id() {
+        assert_eq!(last_event_id(&state(Some("41"))), Some("41"));
+        assert_eq!(last_event_id(&state(None)), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_events_with_keep_alives() {
+        let events = stream::iter(["a", "b"])
+            .then(|data| async move {
+                tokio::time::sleep(Duration::from_secs(20)).await;
+                Ok::<_, Infallible>(Event::default().data(data))
+            })
+            .boxed();
+
+        let res = Sse::new(events)
+            .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+            .into_response(&state(None));
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-cache");
+
+        let chunks = res
+            .into_body()
+            .map(|chunk| chunk.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(chunks, [":\n\n", "data:a\n\n", ":\n\n", "data:b\n\n"]);
+    }
+}
@@ -1,18 +1,178 @@
 //! Defines storage for the remote address of the client
 
 use crate::state::{FromState, State};
-use std::net::SocketAddr;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 
-struct ClientAddr {
-    addr: SocketAddr,
+/// The address of the client which sent the current request.
+///
+/// Gotham stores this in `State` for every request, and it can be retrieved via `peer_addr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClientAddr {
+    /// A client connected over TCP.
+    Tcp(SocketAddr),
+    /// A client connected over a Unix domain socket.
+    #[cfg(unix)]
+    Unix(UnixPeer),
 }
 
+impl ClientAddr {
+    /// The socket address of a TCP client, or `None` for other transports.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ClientAddr::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            ClientAddr::Unix(_) => None,
+        }
+    }
 
-pub(crate) fn put_client_addr(state: &mut State, addr: SocketAddr) {
-    state.put(ClientAddr { addr })
+    /// The IP address of a TCP client, or `None` for other transports.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.socket_addr().map(|addr| addr.ip())
+    }
 }
 
-pub fn client_addr(state: &State) -> Option<SocketAddr> {
+impl From<SocketAddr> for ClientAddr {
+    fn from(addr: SocketAddr) -> ClientAddr {
+        ClientAddr::Tcp(addr)
+    }
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ClientAddr::Unix(peer) => peer.fmt(f),
+        }
+    }
+}
+
+/// The peer of a Unix domain socket connection.
+///
+/// Clients rarely bind their end of the socket, so `path` is usually `None`. The credentials of the
+/// peer process are available on platforms which support `SO_PEERCRED` or an equivalent.
+#[cfg(unix)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnixPeer {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) credentials: Option<PeerCredentials>,
+}
+
+#[cfg(unix)]
+impl UnixPeer {
+    /// The path the peer's socket is bound to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The credentials of the peer process, where available.
+    pub fn credentials(&self) -> Option<&PeerCredentials> {
+        self.credentials.as_ref()
+    }
+}
+
+#[cfg(unix)]
+impl Display for UnixPeer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.path, &self.credentials) {
+            (Some(path), _) => write!(f, "unix:{}", path.display()),
+            (None, Some(cred)) => write!(f, "unix:uid={}", cred.uid),
+            (None, None) => f.write_str("unix"),
+        }
+    }
+}
+
+/// The credentials of the process on the other end of a Unix domain socket.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) pid: Option<i32>,
+}
+
+#[cfg(unix)]
+impl PeerCredentials {
+    /// The effective user ID of the peer process.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// The effective group ID of the peer process.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The process ID of the peer, where the platform reports it.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+}
+
+pub(crate) fn put_client_addr(state: &mut State, addr: ClientAddr) {
+    state.put(addr)
+}
+
+/// Returns the address of the client which sent the current request.
+pub fn peer_addr(state: &State) -> Option<&ClientAddr> {
     // 获取提交IP todo:: 相同 IP 如何判断不同的请求内容
-    ClientAddr::try_borrow_from(state).map(|c| c.addr)
+    ClientAddr::try_borrow_from(state)
+}
+
+/// Returns the socket address of the client which sent the current request, or `None` if it
+/// didn't connect over TCP.
+#[deprecated(note = "use `peer_addr`, which also covers clients connected over Unix sockets")]
+pub fn client_addr(state: &State) -> Option<SocketAddr> {
+    peer_addr(state).and_then(ClientAddr::socket_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_client_addr() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let client = ClientAddr::from(addr);
+
+        assert_eq!(client.socket_addr(), Some(addr));
+        assert_eq!(client.ip(), Some(addr.ip()));
+        assert_eq!(client.to_string(), "127.0.0.1:9000");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_client_addr() {
+        let anonymous = ClientAddr::Unix(UnixPeer::default());
+        assert_eq!(anonymous.ip(), None);
+        assert_eq!(anonymous.to_string(), "unix");
+
+        let peer = UnixPeer {
+            path: None,
+            credentials: Some(PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: Some(42),
+            }),
+        };
+        assert_eq!(peer.credentials().and_then(|c| c.pid()), Some(42));
+        assert_eq!(ClientAddr::Unix(peer).to_string(), "unix:uid=1000");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn accessors() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let mut state = State::new();
+        assert_eq!(peer_addr(&state), None);
+        assert_eq!(client_addr(&state), None);
+
+        put_client_addr(&mut state, ClientAddr::Tcp(addr));
+        assert_eq!(peer_addr(&state), Some(&ClientAddr::Tcp(addr)));
+        assert_eq!(client_addr(&state), Some(addr));
+    }
 }
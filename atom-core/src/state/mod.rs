@@ -2,26 +2,33 @@
 
 pub(crate) mod client_addr;
 mod from_state;
+mod real_client;
 mod request_id;
 
 use hyper::http::request;
 use hyper::upgrade::OnUpgrade;
-use hyper::{Request};
+use hyper::{HeaderMap, Method, Request, Uri, Version};
 use log::{debug, trace};
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::hash::{BuildHasherDefault, Hasher};
-use std::net::SocketAddr;
 use hyper::body::Incoming;
 use crate::body::Body;
 use crate::helpers::http::request::path::RequestPathSegments;
+use crate::tls::TlsInfo;
 
+#[allow(deprecated)]
 pub use crate::state::client_addr::client_addr;
+pub use crate::state::client_addr::{peer_addr, ClientAddr};
+#[cfg(unix)]
+pub use crate::state::client_addr::{PeerCredentials, UnixPeer};
 pub use crate::state::from_state::FromState;
+pub use crate::state::real_client::{real_client, RealClient};
 pub use crate::state::request_id::request_id;
 
 use crate::state::client_addr::put_client_addr;
 pub(crate) use crate::state::request_id::set_request_id;
+use crate::state::request_id::RequestId;
 
 // https://docs.rs/http/0.2.5/src/http/extensions.rs.html#8-28
 // With TypeIds as keys, there's no need to hash them. They are already hashes
@@ -79,10 +86,10 @@ impl State {
 
     /// Instantiate a new `State` for a given `Request`. This is primarily useful if you're calling
     /// Gotham from your own Hyper service.
-    pub fn from_request(req: Request<Body>, client_addr: SocketAddr) -> Self {
+    pub fn from_request(req: Request<Body>, client_addr: impl Into<ClientAddr>) -> Self {
         let mut state = Self::new();
 
-        put_client_addr(&mut state, client_addr);
+        put_client_addr(&mut state, client_addr.into());
 
         let (
             request::Parts {
@@ -123,10 +130,13 @@ impl State {
         state
     }
 
-    pub fn from_request_incoming(req: Request<Incoming>, client_addr: SocketAddr) -> Self {
+    pub fn from_request_incoming(
+        req: Request<Incoming>,
+        client_addr: impl Into<ClientAddr>,
+    ) -> Self {
         let mut state = Self::new();
 
-        put_client_addr(&mut state, client_addr);
+        put_client_addr(&mut state, client_addr.into());
 
         let (
             request::Parts {
@@ -168,6 +178,31 @@ impl State {
         state
     }
 
+    /// Creates a new `State` holding copies of the request line, headers, request ID and client
+    /// details, for responding to a request whose `State` was lost along with a cancelled
+    /// handler.
+    pub(crate) fn fork_request(&self) -> State {
+        fn copy<T>(from: &State, to: &mut State)
+        where
+            T: Any + Send + Clone,
+        {
+            if let Some(value) = from.try_borrow::<T>() {
+                to.put(value.clone());
+            }
+        }
+
+        let mut state = State::new();
+        copy::<ClientAddr>(self, &mut state);
+        copy::<RealClient>(self, &mut state);
+        copy::<TlsInfo>(self, &mut state);
+        copy::<Method>(self, &mut state);
+        copy::<Uri>(self, &mut state);
+        copy::<Version>(self, &mut state);
+        copy::<HeaderMap>(self, &mut state);
+        copy::<RequestId>(self, &mut state);
+        state
+    }
+
 
     pub fn put<T>(&mut self, t: T)
         where
@@ -0,0 +1,71 @@
+//! Defines storage for the client as seen by the edge of the network, before any reverse proxies
+
+use crate::state::{FromState, State};
+use std::net::IpAddr;
+
+/// The original client of a request which arrived through one or more trusted reverse proxies,
+/// together with the scheme and host it used to reach the first of them.
+///
+/// This is resolved from the `Forwarded` or `X-Forwarded-*` headers by `ForwardedMiddleware`,
+/// which stores it in `State` for every request. Without that middleware it is absent, and
+/// `client_addr` should be used instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RealClient {
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) scheme: String,
+    pub(crate) host: Option<String>,
+}
+
+impl RealClient {
+    /// The IP address of the client, or `None` if it connected over a transport without one and
+    /// no trusted proxy supplied it.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// The scheme the client used, `http` or `https`.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// The host the client asked for, including the port if one was given.
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Returns the absolute URL of `path` as the client would request it, or `None` when the host
+    /// is unknown. `path` must start with `/`.
+    pub fn url_for(&self, path: &str) -> Option<String> {
+        self.host()
+            .map(|host| format!("{}://{}{}", self.scheme, host, path))
+    }
+}
+
+/// Returns the original client of the current request, if resolved by `ForwardedMiddleware`.
+pub fn real_client(state: &State) -> Option<&RealClient> {
+    RealClient::try_borrow_from(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_urls() {
+        let client = RealClient {
+            ip: None,
+            scheme: String::from("https"),
+            host: Some(String::from("example.org:8443")),
+        };
+        assert_eq!(
+            client.url_for("/login?next=%2F").as_deref(),
+            Some("https://example.org:8443/login?next=%2F")
+        );
+
+        let client = RealClient {
+            host: None,
+            ..client
+        };
+        assert_eq!(client.url_for("/login"), None);
+    }
+}
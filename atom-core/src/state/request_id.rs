@@ -8,6 +8,7 @@ use uuid::Uuid;
 use crate::state::{FromState, State};
 
 /// A container type for the value returned by `request_id`.
+#[derive(Clone)]
 pub(super) struct RequestId {
     val: String,
 }
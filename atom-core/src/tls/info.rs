@@ -0,0 +1,248 @@
+//! Defines `TlsInfo`, which describes the TLS session a request was received on.
+
+use std::fmt::{self, Debug, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio_rustls::rustls::{Certificate, ProtocolVersion, ServerConnection, SupportedCipherSuite};
+use x509_parser::extensions::GeneralName;
+
+/// The TLS session a request was received on, stored in `State` for requests served with TLS.
+///
+/// The information is captured once the handshake completes, and shared by every request on the
+/// connection. When the server is configured to request client certificates (mutual TLS), the
+/// verified certificate chain and the identity it describes are available here.
+///
+/// ```rust,ignore
+/// fn handler(state: State) -> (State, Response<Body>) {
+///     let caller = TlsInfo::try_borrow_from(&state)
+///         .and_then(TlsInfo::peer_identity)
+///         .and_then(PeerIdentity::common_name)
+///         .unwrap_or("anonymous");
+///     // ...
+/// }
+/// ```
+#[derive(Clone)]
+pub struct TlsInfo {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    peer_certificates: Vec<Certificate>,
+    peer_identity: Option<PeerIdentity>,
+    server_name: Option<String>,
+    alpn_protocol: Option<Vec<u8>>,
+    cipher_suite: Option<SupportedCipherSuite>,
+    protocol_version: Option<ProtocolVersion>,
+}
+
+impl TlsInfo {
+    /// Captures the negotiated parameters of a completed handshake.
+    pub(crate) fn from_connection(conn: &ServerConnection) -> TlsInfo {
+        let peer_certificates = conn.peer_certificates().unwrap_or_default().to_vec();
+        let peer_identity =
+            peer_certificates
+                .first()
+                .and_then(|cert| match PeerIdentity::from_der(&cert.0) {
+                    Ok(identity) => Some(identity),
+                    Err(err) => {
+                        debug!("unable to parse the client certificate: {}", err);
+                        None
+                    }
+                });
+
+        TlsInfo {
+            inner: Arc::new(Inner {
+                peer_certificates,
+                peer_identity,
+                server_name: conn.sni_hostname().map(String::from),
+                alpn_protocol: conn.alpn_protocol().map(<[u8]>::to_vec),
+                cipher_suite: conn.negotiated_cipher_suite(),
+                protocol_version: conn.protocol_version(),
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_alpn(protocol: Option<&[u8]>) -> TlsInfo {
+        TlsInfo {
+            inner: Arc::new(Inner {
+                peer_certificates: Vec::new(),
+                peer_identity: None,
+                server_name: None,
+                alpn_protocol: protocol.map(<[u8]>::to_vec),
+                cipher_suite: None,
+                protocol_version: None,
+            }),
+        }
+    }
+
+    /// The certificate chain presented by the client, leaf first. Empty unless the client
+    /// authenticated with a certificate.
+    pub fn peer_certificates(&self) -> &[Certificate] {
+        &self.inner.peer_certificates
+    }
+
+    /// The identity described by the client's certificate, if it presented one.
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.inner.peer_identity.as_ref()
+    }
+
+    /// The server name the client asked for via SNI.
+    pub fn server_name(&self) -> Option<&str> {
+        self.inner.server_name.as_deref()
+    }
+
+    /// The application protocol negotiated via ALPN, such as `h2`.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.inner.alpn_protocol.as_deref()
+    }
+
+    /// The negotiated cipher suite.
+    pub fn cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        self.inner.cipher_suite
+    }
+
+    /// The negotiated TLS version.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.inner.protocol_version
+    }
+}
+
+impl Debug for TlsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsInfo")
+            .field("peer_identity", &self.inner.peer_identity)
+            .field("server_name", &self.inner.server_name)
+            .field(
+                "alpn_protocol",
+                &self.alpn_protocol().map(String::from_utf8_lossy),
+            )
+            .field("cipher_suite", &self.inner.cipher_suite)
+            .field("protocol_version", &self.inner.protocol_version)
+            .finish()
+    }
+}
+
+/// The names a certificate was issued to: its subject, and the entries of its Subject
+/// Alternative Name extension.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    subject: String,
+    common_name: Option<String>,
+    dns_names: Vec<String>,
+    uris: Vec<String>,
+    emails: Vec<String>,
+    ip_addresses: Vec<IpAddr>,
+}
+
+impl PeerIdentity {
+    /// Parses the identity from a DER encoded X.509 certificate.
+    pub fn from_der(der: &[u8]) -> Result<PeerIdentity, x509_parser::error::X509Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).map_err(|err| match err {
+            x509_parser::nom::Err::Error(err) | x509_parser::nom::Err::Failure(err) => err,
+            x509_parser::nom::Err::Incomplete(_) => {
+                x509_parser::error::X509Error::InvalidCertificate
+            }
+        })?;
+
+        let mut identity = PeerIdentity {
+            subject: cert.subject().to_string(),
+            common_name: cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(String::from),
+            ..PeerIdentity::default()
+        };
+
+        if let Some(san) = cert.subject_alternative_name()? {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => identity.dns_names.push(dns.to_string()),
+                    GeneralName::URI(uri) => identity.uris.push(uri.to_string()),
+                    GeneralName::RFC822Name(email) => identity.emails.push(email.to_string()),
+                    GeneralName::IPAddress(bytes) => {
+                        if let Some(ip) = ip_from_bytes(bytes) {
+                            identity.ip_addresses.push(ip);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(identity)
+    }
+
+    /// The subject's distinguished name, e.g. `CN=billing, O=Example Corp`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// The common name (`CN`) of the subject.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The DNS names listed in the Subject Alternative Name extension.
+    pub fn dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    /// The URIs listed in the Subject Alternative Name extension, such as SPIFFE IDs.
+    pub fn uris(&self) -> &[String] {
+        &self.uris
+    }
+
+    /// The email addresses listed in the Subject Alternative Name extension.
+    pub fn emails(&self) -> &[String] {
+        &self.emails
+    }
+
+    /// The IP addresses listed in the Subject Alternative Name extension.
+    pub fn ip_addresses(&self) -> &[IpAddr] {
+        &self.ip_addresses
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_certificate_identity() {
+        let identity = PeerIdentity::from_der(include_bytes!("tls_cert.der")).unwrap();
+
+        assert_eq!(identity.subject(), "CN=example.org");
+        assert_eq!(identity.common_name(), Some("example.org"));
+        assert_eq!(
+            identity.dns_names(),
+            ["example.org", "example.com", "localhost"]
+        );
+        assert_eq!(
+            identity.ip_addresses(),
+            [
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert!(identity.uris().is_empty());
+
+        let ca = PeerIdentity::from_der(include_bytes!("tls_ca_cert.der")).unwrap();
+        assert_eq!(ca.common_name(), Some("Gotham Test CA"));
+        assert!(ca.dns_names().is_empty());
+
+        assert!(PeerIdentity::from_der(b"not a certificate").is_err());
+    }
+}
@@ -1,16 +1,24 @@
-use futures_util::future::{MapErr, TryFutureExt};
-use log::{error, info};
+use std::future::Future;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
+use std::time::Duration;
+use futures_util::future::{MapOk, TryFutureExt};
 use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
 use tokio_rustls::{rustls, Accept, TlsAcceptor};
 
 use super::handler::NewHandler;
-use super::{bind_server, new_runtime, tcp_listener, StartError};
+use super::server::ServerBuilder;
+use super::StartError;
 
+mod info;
+mod resolver;
 #[cfg(feature = "testing")]
 pub mod test;
 
+pub use info::{PeerIdentity, TlsInfo};
+pub use resolver::{load_certified_key, CertError, CertResolver, CertResolverBuilder};
+
 /// Starts a Gotham application with the default number of threads.
 pub fn start<NH, A>(
     addr: A,
@@ -35,8 +43,9 @@ where
     NH: NewHandler + 'static,
     A: ToSocketAddrs + 'static + Send,
 {
-    let runtime = new_runtime(threads);
-    runtime.block_on(init_server(addr, new_handler, tls_config))
+    ServerBuilder::new()
+        .threads(threads)
+        .start_tls(addr, new_handler, tls_config)
 }
 
 /// Returns a `Future` used to spawn an Gotham application.
@@ -44,6 +53,10 @@ where
 /// This is used internally, but exposed in case the developer intends on doing any
 /// manual wiring that isn't supported by the Gotham API. It's unlikely that this will
 /// be required in most use cases; it's mainly exposed for shutdown handling.
+///
+/// If `tls_config` does not specify any ALPN protocols, `h2` and `http/1.1` are advertised so that
+/// clients can negotiate HTTP/2. Use `ServerBuilder::init_tls_server` to configure timeouts and
+/// limits.
 pub async fn init_server<NH, A>(
     addr: A,
     new_handler: NH,
@@ -53,26 +66,67 @@ where
     NH: NewHandler + 'static,
     A: ToSocketAddrs + 'static + Send,
 {
-    let listener = tcp_listener(addr).await?;
-    let addr = listener.local_addr().unwrap();
+    ServerBuilder::new()
+        .init_tls_server(addr, new_handler, tls_config)
+        .await
+}
 
-    info! {
-        target: "gotham::start",
-        " Gotham listening on http://{}", addr
-    }
+/// Starts a Gotham application with TLS, which shuts down gracefully once `shutdown` resolves.
+///
+/// In-flight requests are given up to `drain_timeout` to complete before their connections are
+/// closed, see `bind_server_with_shutdown`. Returns `Ok(())` once the server has stopped.
+pub fn start_with_graceful_shutdown<NH, A, S>(
+    addr: A,
+    new_handler: NH,
+    tls_config: rustls::ServerConfig,
+    shutdown: S,
+    drain_timeout: Duration,
+) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    A: ToSocketAddrs + 'static + Send,
+    S: Future<Output = ()> + Send + 'static,
+{
+    ServerBuilder::new()
+        .graceful_shutdown(shutdown)
+        .shutdown_timeout(drain_timeout)
+        .start_tls(addr, new_handler, tls_config)
+}
 
-    let wrap = rustls_wrap(tls_config);
-    bind_server(listener, new_handler, wrap).await
+/// Returns a `Future` used to spawn a Gotham application with TLS, which shuts down gracefully
+/// once `shutdown` resolves.
+pub async fn init_server_with_graceful_shutdown<NH, A, S>(
+    addr: A,
+    new_handler: NH,
+    tls_config: rustls::ServerConfig,
+    shutdown: S,
+    drain_timeout: Duration,
+) -> Result<(), StartError>
+where
+    NH: NewHandler + 'static,
+    A: ToSocketAddrs + 'static + Send,
+    S: Future<Output = ()> + Send + 'static,
+{
+    ServerBuilder::new()
+        .graceful_shutdown(shutdown)
+        .shutdown_timeout(drain_timeout)
+        .init_tls_server(addr, new_handler, tls_config)
+        .await
 }
 
+pub(crate) type Accepted = (TlsStream<TcpStream>, Option<TlsInfo>);
+pub(crate) type AcceptWithInfo = MapOk<Accept<TcpStream>, fn(TlsStream<TcpStream>) -> Accepted>;
+
+/// Performs the TLS handshake on each connection, capturing the session's `TlsInfo` for the
+/// requests served on it.
 pub(crate) fn rustls_wrap(
     tls_config: rustls::ServerConfig,
-) -> impl Fn(TcpStream) -> MapErr<Accept<TcpStream>, fn(std::io::Error) -> ()> {
-    // function instead of closure, so the type is nameable, since impl ... impl is not allowed
-    fn log_error(error: std::io::Error) {
-        error!(target: "gotham::tls", "TLS handshake error: {:?}", error);
-    }
-
+) -> impl Fn(TcpStream) -> AcceptWithInfo {
     let tls = TlsAcceptor::from(Arc::new(tls_config));
-    move |socket| tls.accept(socket).map_err(log_error)
+    move |socket| tls.accept(socket).map_ok(with_tls_info as fn(_) -> _)
+}
+
+fn with_tls_info(stream: TlsStream<TcpStream>) -> Accepted {
+    let info = TlsInfo::from_connection(stream.get_ref().1);
+    (stream, Some(info))
 }
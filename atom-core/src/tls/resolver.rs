@@ -0,0 +1,480 @@
+//! Defines `CertResolver`, which selects the server certificate by SNI hostname and reloads
+//! certificates from disk while the server is running.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info};
+use rustls_pemfile::Item;
+use thiserror::Error;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+/// Resolves the certificate for each TLS handshake from the SNI hostname sent by the client,
+/// using PEM encoded certificate chains and private keys loaded from disk.
+///
+/// Certificates can be replaced without restarting the server: `reload` reads every file again,
+/// and `watch` starts a background thread which reloads files whenever they change. A failed
+/// reload keeps serving the certificates which were loaded before.
+///
+/// Hostnames are matched exactly, then against wildcard entries such as `*.example.org`, which
+/// match a single label. Handshakes without SNI or with an unknown hostname use the default
+/// certificate, or fail if there is none.
+///
+/// ```rust,ignore
+/// let resolver = CertResolver::builder()
+///     .cert("example.org", "certs/example.org.crt", "certs/example.org.key")
+///     .cert("*.example.org", "certs/wildcard.crt", "certs/wildcard.key")
+///     .default_cert("certs/default.crt", "certs/default.key")
+///     .build()?;
+///
+/// // Pick up renewed certificates within a minute of them being written.
+/// resolver.watch(Duration::from_secs(60));
+///
+/// let tls_config = rustls::ServerConfig::builder()
+///     .with_safe_defaults()
+///     .with_no_client_auth()
+///     .with_cert_resolver(Arc::new(resolver.clone()));
+///
+/// tls::start("0.0.0.0:443", router(), tls_config)
+/// ```
+#[derive(Clone)]
+pub struct CertResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sources: Vec<Source>,
+    loaded: RwLock<Loaded>,
+}
+
+/// Where the certificate for a hostname, or the default certificate, is loaded from.
+#[derive(Clone, Debug)]
+struct Source {
+    hostname: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[derive(Default)]
+struct Loaded {
+    by_hostname: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// Builds a `CertResolver`, created by `CertResolver::builder`.
+#[derive(Debug, Default)]
+pub struct CertResolverBuilder {
+    sources: Vec<Source>,
+}
+
+impl CertResolverBuilder {
+    /// Serves the certificate chain in `cert_path` and the private key in `key_path` to clients
+    /// requesting `hostname`, which may be a wildcard like `*.example.org`.
+    pub fn cert<H, C, K>(mut self, hostname: H, cert_path: C, key_path: K) -> CertResolverBuilder
+    where
+        H: Into<String>,
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        self.sources.push(Source {
+            hostname: Some(hostname.into().to_ascii_lowercase()),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    /// Serves the certificate chain in `cert_path` and the private key in `key_path` when no
+    /// other certificate matches the handshake.
+    pub fn default_cert<C, K>(mut self, cert_path: C, key_path: K) -> CertResolverBuilder
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        self.sources.push(Source {
+            hostname: None,
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    /// Loads every certificate, failing if any of them cannot be loaded.
+    pub fn build(self) -> Result<CertResolver, CertError> {
+        let loaded = load(&self.sources)?;
+
+        Ok(CertResolver {
+            inner: Arc::new(Inner {
+                sources: self.sources,
+                loaded: RwLock::new(loaded),
+            }),
+        })
+    }
+}
+
+impl CertResolver {
+    /// Creates a `CertResolverBuilder` with no certificates.
+    pub fn builder() -> CertResolverBuilder {
+        CertResolverBuilder::default()
+    }
+
+    /// Loads every certificate from disk again, e.g. in response to `SIGHUP`.
+    ///
+    /// The new certificates are only used if all of them load successfully; otherwise the error
+    /// is returned and the previous certificates remain in use.
+    pub fn reload(&self) -> Result<(), CertError> {
+        let loaded = load(&self.inner.sources)?;
+        *self
+            .inner
+            .loaded
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = loaded;
+        info!(target: "gotham::tls", " reloaded TLS certificates");
+        Ok(())
+    }
+
+    /// Reloads the certificates if any of their files have been modified since they were last
+    /// loaded, returning whether a reload happened.
+    pub fn reload_if_changed(&self) -> Result<bool, CertError> {
+        let modified = modified_times(&self.inner.sources);
+        let changed = self
+            .inner
+            .loaded
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .modified
+            != modified;
+
+        if changed {
+            self.reload()?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Starts a background thread which checks the certificate files for changes every
+    /// `interval`, reloading them when they change.
+    ///
+    /// Failed reloads are logged and retried at the next check. The thread stops once every
+    /// clone of this `CertResolver`, including those held by a `rustls::ServerConfig`, has been
+    /// dropped.
+    pub fn watch(&self, interval: Duration) {
+        let resolver = Arc::downgrade(&self.inner);
+        thread::spawn(move || watch_loop(resolver, interval));
+    }
+
+    /// Returns the certificate to use for a handshake requesting `server_name`.
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self
+            .inner
+            .loaded
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let matched = server_name.and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            loaded.by_hostname.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                loaded.by_hostname.get(&format!("*.{}", parent))
+            })
+        });
+
+        matched.or(loaded.default.as_ref()).cloned()
+    }
+}
+
+impl Debug for CertResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("sources", &self.inner.sources)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        let key = self.lookup(server_name);
+
+        if key.is_none() {
+            debug!("no TLS certificate for server name {:?}", server_name);
+        }
+
+        key
+    }
+}
+
+fn watch_loop(resolver: Weak<Inner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        // Stop watching once the resolver is no longer used by anything.
+        let inner = match resolver.upgrade() {
+            None => break,
+            Some(inner) => inner,
+        };
+
+        if let Err(err) = (CertResolver { inner }).reload_if_changed() {
+            error!(target: "gotham::tls", " unable to reload TLS certificates: {}", err);
+        }
+    }
+}
+
+fn load(sources: &[Source]) -> Result<Loaded, CertError> {
+    let mut loaded = Loaded {
+        modified: modified_times(sources),
+        ..Loaded::default()
+    };
+
+    for source in sources {
+        let key = Arc::new(load_certified_key(&source.cert_path, &source.key_path)?);
+        match source.hostname {
+            Some(ref hostname) => {
+                loaded.by_hostname.insert(hostname.clone(), key);
+            }
+            None => loaded.default = Some(key),
+        }
+    }
+
+    Ok(loaded)
+}
+
+fn modified_times(sources: &[Source]) -> Vec<Option<SystemTime>> {
+    sources
+        .iter()
+        .flat_map(|source| [&source.cert_path, &source.key_path])
+        .map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// Loads a PEM encoded certificate chain and private key into a `CertifiedKey`.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, CertError> {
+    let certs = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if certs.is_empty() {
+        return Err(CertError::NoCertificates(cert_path.to_owned()));
+    }
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| CertError::NoPrivateKey(key_path.to_owned()))?;
+
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| CertError::UnsupportedKey(key_path.to_owned()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, CertError> {
+    let io_error = |source| CertError::Io {
+        path: path.to_owned(),
+        source,
+    };
+
+    let file = File::open(path).map_err(io_error)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(io_error)
+}
+
+/// The reasons certificates can fail to load.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CertError {
+    /// A file could not be read.
+    #[error("unable to read {}: {source}", path.display())]
+    Io {
+        /// The file which could not be read.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: io::Error,
+    },
+    /// A certificate file did not contain any PEM encoded certificates.
+    #[error("no certificates found in {}", .0.display())]
+    NoCertificates(PathBuf),
+    /// A key file did not contain a PEM encoded private key.
+    #[error("no private key found in {}", .0.display())]
+    NoPrivateKey(PathBuf),
+    /// A private key was not of a type supported by rustls.
+    #[error("unsupported private key in {}", .0.display())]
+    UnsupportedKey(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use std::fs;
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let encoded = STANDARD.encode(der);
+        let lines = encoded
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap());
+        let body = lines.collect::<Vec<_>>().join("\n");
+        format!("-----BEGIN {0}-----\n{1}\n-----END {0}-----\n", label, body)
+    }
+
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let dir = std::env::temp_dir().join(format!(
+                "gotham-certs-{}-{}",
+                name,
+                uuid::Uuid::new_v4()
+            ));
+            fs::create_dir(&dir).unwrap();
+
+            let fixture = Fixture { dir };
+            fixture.write(
+                "server.crt",
+                &pem("CERTIFICATE", include_bytes!("tls_cert.der")),
+            );
+            fixture.write(
+                "ca.crt",
+                &pem("CERTIFICATE", include_bytes!("tls_ca_cert.der")),
+            );
+            fixture.write(
+                "server.key",
+                &pem("PRIVATE KEY", include_bytes!("tls_key.der")),
+            );
+            fixture
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(name)
+        }
+
+        fn write(&self, name: &str, contents: &str) {
+            fs::write(self.path(name), contents).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn leaf(key: Option<Arc<CertifiedKey>>) -> Option<Vec<u8>> {
+        key.map(|key| key.cert[0].0.clone())
+    }
+
+    #[test]
+    fn resolves_by_server_name() {
+        let fixture = Fixture::new("resolve");
+        let server = include_bytes!("tls_cert.der").to_vec();
+        let ca = include_bytes!("tls_ca_cert.der").to_vec();
+
+        let resolver = CertResolver::builder()
+            .cert(
+                "Example.org",
+                fixture.path("server.crt"),
+                fixture.path("server.key"),
+            )
+            .cert(
+                "*.example.com",
+                fixture.path("ca.crt"),
+                fixture.path("server.key"),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            leaf(resolver.lookup(Some("example.ORG"))),
+            Some(server.clone())
+        );
+        assert_eq!(
+            leaf(resolver.lookup(Some("api.example.com"))),
+            Some(ca.clone())
+        );
+        assert_eq!(leaf(resolver.lookup(Some("example.com"))), None);
+        assert_eq!(leaf(resolver.lookup(None)), None);
+
+        let resolver = CertResolver::builder()
+            .cert(
+                "*.example.com",
+                fixture.path("ca.crt"),
+                fixture.path("server.key"),
+            )
+            .default_cert(fixture.path("server.crt"), fixture.path("server.key"))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            leaf(resolver.lookup(Some("a.b.example.com"))),
+            Some(server.clone())
+        );
+        assert_eq!(leaf(resolver.lookup(None)), Some(server));
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let fixture = Fixture::new("reload");
+        let resolver = CertResolver::builder()
+            .default_cert(fixture.path("active.crt"), fixture.path("server.key"));
+
+        assert!(matches!(resolver.build(), Err(CertError::Io { .. })));
+
+        fixture.write(
+            "active.crt",
+            &fs::read_to_string(fixture.path("server.crt")).unwrap(),
+        );
+        let resolver = CertResolver::builder()
+            .default_cert(fixture.path("active.crt"), fixture.path("server.key"))
+            .build()
+            .unwrap();
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        // A broken file is rejected, and the previous certificate stays in use.
+        fixture.write("active.crt", "not a certificate");
+        assert!(matches!(
+            resolver.reload_if_changed(),
+            Err(CertError::NoCertificates(_))
+        ));
+        assert_eq!(
+            leaf(resolver.lookup(None)),
+            Some(include_bytes!("tls_cert.der").to_vec())
+        );
+
+        fixture.write(
+            "active.crt",
+            &fs::read_to_string(fixture.path("ca.crt")).unwrap(),
+        );
+        let file = File::options()
+            .write(true)
+            .open(fixture.path("active.crt"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_eq!(
+            leaf(resolver.lookup(None)),
+            Some(include_bytes!("tls_ca_cert.der").to_vec())
+        );
+    }
+}
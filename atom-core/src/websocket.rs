@@ -0,0 +1,366 @@
+//! Defines `WebSocketUpgrade`, which accepts WebSocket handshakes and hands the upgraded
+//! connection to the application as a stream of framed messages.
+//!
+//! ```rust,ignore
+//! use futures_util::{SinkExt, StreamExt};
+//!
+//! async fn echo(state: &mut State) -> Result<Response<Body>, HandlerError> {
+//!     let upgrade = WebSocketUpgrade::from_state(state)?;
+//!
+//!     Ok(upgrade.on_upgrade(state, |mut socket| async move {
+//!         while let Some(Ok(message)) = socket.next().await {
+//!             if message.is_text() || message.is_binary() {
+//!                 if socket.send(message).await.is_err() {
+//!                     break;
+//!                 }
+//!             }
+//!         }
+//!     }))
+//! }
+//!
+//! build_simple_router(|route| {
+//!     route
+//!         .get("/ws")
+//!         .add_route_matcher(WebSocketUpgradeRouteMatcher::new())
+//!         .to_async_borrowing(echo);
+//! })
+//! ```
+
+use std::future::Future;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Method, Response, StatusCode};
+use log::debug;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::body::Body;
+use crate::helpers::http::response::create_empty_response;
+use crate::server::upgrade::Upgrades;
+use crate::state::{request_id, FromState, State};
+
+pub use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+pub use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+
+/// An upgraded WebSocket connection.
+///
+/// The connection is a `Stream` of incoming `Message`s and a `Sink` for outgoing ones. Pings are
+/// answered automatically, and a close frame from the client is echoed back before the stream
+/// ends.
+pub type WebSocket = WebSocketStream<Upgraded>;
+
+/// The only WebSocket protocol version defined by RFC 6455, and the only one supported.
+const WEBSOCKET_VERSION: &str = "13";
+
+/// A validated WebSocket handshake, ready to be accepted.
+///
+/// Created from the request in `State` by `WebSocketUpgrade::from_state`, which checks the
+/// handshake headers and takes hyper's `OnUpgrade` out of `State`. Calling `on_upgrade` produces
+/// the `101 Switching Protocols` response which completes the handshake.
+#[derive(Debug)]
+pub struct WebSocketUpgrade {
+    key: HeaderValue,
+    protocols: Vec<String>,
+    protocol: Option<HeaderValue>,
+    config: Option<WebSocketConfig>,
+    on_upgrade: OnUpgrade,
+}
+
+impl WebSocketUpgrade {
+    /// Validates the WebSocket handshake of the request in `state`.
+    ///
+    /// The request must be a `GET` with `Connection: upgrade`, `Upgrade: websocket`,
+    /// `Sec-WebSocket-Version: 13` and a valid `Sec-WebSocket-Key`. On success, hyper's
+    /// `OnUpgrade` is taken from `state`, so the request can only be upgraded once.
+    pub fn from_state(state: &mut State) -> Result<WebSocketUpgrade, WebSocketUpgradeError> {
+        if Method::borrow_from(state) != Method::GET {
+            return Err(WebSocketUpgradeError::MethodNotGet);
+        }
+
+        let headers = state.borrow::<HeaderMap>();
+
+        if !is_websocket_upgrade(headers) {
+            return Err(WebSocketUpgradeError::NotUpgrade);
+        }
+
+        if headers
+            .get(SEC_WEBSOCKET_VERSION)
+            .map(HeaderValue::as_bytes)
+            != Some(WEBSOCKET_VERSION.as_bytes())
+        {
+            return Err(WebSocketUpgradeError::UnsupportedVersion);
+        }
+
+        let key = match headers.get(SEC_WEBSOCKET_KEY) {
+            Some(key) if is_valid_key(key) => key.clone(),
+            _ => return Err(WebSocketUpgradeError::InvalidKey),
+        };
+
+        let protocols = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|protocol| !protocol.is_empty())
+            .map(String::from)
+            .collect();
+
+        let on_upgrade = OnUpgrade::try_take_from(state)
+            .ok_or(WebSocketUpgradeError::ConnectionNotUpgradable)?;
+
+        Ok(WebSocketUpgrade {
+            key,
+            protocols,
+            protocol: None,
+            config: None,
+            on_upgrade,
+        })
+    }
+
+    /// The subprotocols offered by the client in `Sec-WebSocket-Protocol`, in order of
+    /// preference.
+    pub fn protocols(&self) -> impl Iterator<Item = &str> {
+        self.protocols.iter().map(String::as_str)
+    }
+
+    /// Selects the first of `supported` which the client offered as the subprotocol of the
+    /// connection. If the client offered none of them, no subprotocol is selected.
+    pub fn select_protocol<I, S>(mut self, supported: I) -> WebSocketUpgrade
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.protocol = supported
+            .into_iter()
+            .find(|protocol| self.protocols.iter().any(|p| p == protocol.as_ref()))
+            .and_then(|protocol| HeaderValue::from_str(protocol.as_ref()).ok());
+        self
+    }
+
+    /// The subprotocol selected by `select_protocol`, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().and_then(|p| p.to_str().ok())
+    }
+
+    /// Sets the message and frame size limits of the connection, see `WebSocketConfig`.
+    pub fn config(mut self, config: WebSocketConfig) -> WebSocketUpgrade {
+        self.config = Some(config);
+        self
+    }
+
+    /// Accepts the handshake, returning the `101 Switching Protocols` response which the handler
+    /// must send.
+    ///
+    /// Once the response has been written, the connection is upgraded and `callback` is called
+    /// with the resulting `WebSocket`. When served by `bind_server`, `callback` runs within the
+    /// task of the connection it was upgraded from, so it counts towards `max_connections`, is
+    /// drained on shutdown and is dropped once the connection has been idle for the idle timeout.
+    /// Otherwise it is spawned onto the runtime. If the upgrade fails, e.g. because the client
+    /// disconnected, the failure is logged and `callback` is never called.
+    pub fn on_upgrade<F, Fut>(self, state: &State, callback: F) -> Response<Body>
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut res = create_empty_response(state, StatusCode::SWITCHING_PROTOCOLS);
+        let headers = res.headers_mut();
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            SEC_WEBSOCKET_ACCEPT,
+            HeaderValue::from_str(&derive_accept_key(self.key.as_bytes()))
+                .expect("base64 is a valid header value"),
+        );
+        if let Some(protocol) = self.protocol {
+            headers.insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+
+        let request_id = request_id(state).to_owned();
+        let config = self.config;
+        let on_upgrade = self.on_upgrade;
+
+        let task = async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let socket =
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, config).await;
+                    callback(socket).await;
+                }
+                Err(err) => debug!("[{}] WebSocket upgrade failed: {}", request_id, err),
+            }
+        };
+
+        match state.try_borrow::<Upgrades>() {
+            Some(upgrades) => upgrades.spawn(task),
+            None => {
+                tokio::spawn(task);
+            }
+        }
+
+        res
+    }
+}
+
+/// Returns `true` if the headers request an upgrade to the WebSocket protocol, i.e. include
+/// `Connection: upgrade` and `Upgrade: websocket`.
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    has_token(headers, CONNECTION, "upgrade") && has_token(headers, UPGRADE, "websocket")
+}
+
+fn has_token(headers: &HeaderMap, name: hyper::header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// A `Sec-WebSocket-Key` must be the base64 encoding of 16 bytes.
+fn is_valid_key(key: &HeaderValue) -> bool {
+    STANDARD
+        .decode(key.as_bytes())
+        .map(|decoded| decoded.len() == 16)
+        .unwrap_or(false)
+}
+
+/// The reasons `WebSocketUpgrade::from_state` can reject a handshake.
+///
+/// Returning a `WebSocketUpgradeError` from a handler with `?` produces a `HandlerError` with the
+/// status given by `WebSocketUpgradeError::status`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum WebSocketUpgradeError {
+    /// The handshake was not sent as a `GET` request.
+    #[error("WebSocket handshakes must use the GET method")]
+    MethodNotGet,
+    /// The request did not ask to upgrade to the WebSocket protocol.
+    #[error("expected Connection: upgrade and Upgrade: websocket headers")]
+    NotUpgrade,
+    /// The `Sec-WebSocket-Version` header was missing or not `13`.
+    #[error("expected Sec-WebSocket-Version: 13")]
+    UnsupportedVersion,
+    /// The `Sec-WebSocket-Key` header was missing or invalid.
+    #[error("missing or invalid Sec-WebSocket-Key header")]
+    InvalidKey,
+    /// The connection cannot be upgraded, e.g. because the request was sent over HTTP/2 or has
+    /// already been upgraded.
+    #[error("the connection does not support upgrades")]
+    ConnectionNotUpgradable,
+}
+
+impl WebSocketUpgradeError {
+    /// The status code which should be sent to the client.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WebSocketUpgradeError::MethodNotGet => StatusCode::METHOD_NOT_ALLOWED,
+            WebSocketUpgradeError::NotUpgrade
+            | WebSocketUpgradeError::UnsupportedVersion
+            | WebSocketUpgradeError::InvalidKey => StatusCode::BAD_REQUEST,
+            WebSocketUpgradeError::ConnectionNotUpgradable => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn from_request(
+        method: Method,
+        headers: &[(&str, &str)],
+    ) -> Result<WebSocketUpgrade, StatusCode> {
+        let mut req = Request::builder().method(method).uri("http://localhost/ws");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+
+        let mut req = req.body(Body::empty()).unwrap();
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        let mut state = State::from_request(
+            req,
+            "127.0.0.1:10000".parse::<std::net::SocketAddr>().unwrap(),
+        );
+        state.put(on_upgrade);
+
+        WebSocketUpgrade::from_state(&mut state).map_err(|err| err.status())
+    }
+
+    fn handshake<'a>(overrides: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+        let mut headers = vec![
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", KEY),
+        ];
+        for (name, value) in overrides {
+            headers.retain(|(n, _)| n != name);
+            if !value.is_empty() {
+                headers.push((name, value));
+            }
+        }
+        headers
+    }
+
+    #[test]
+    fn accepts_valid_handshakes() {
+        let headers = handshake(&[("sec-websocket-protocol", "chat, superchat")]);
+        let upgrade = from_request(Method::GET, &headers)
+            .unwrap()
+            .select_protocol(["superchat", "chat"]);
+
+        assert_eq!(
+            upgrade.protocols().collect::<Vec<_>>(),
+            ["chat", "superchat"]
+        );
+        assert_eq!(upgrade.protocol(), Some("superchat"));
+        assert_eq!(
+            derive_accept_key(upgrade.key.as_bytes()),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let upgrade = from_request(Method::GET, &handshake(&[]))
+            .unwrap()
+            .select_protocol(["graphql-ws"]);
+        assert_eq!(upgrade.protocol(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let status = |method, overrides| from_request(method, &handshake(overrides)).unwrap_err();
+
+        assert_eq!(status(Method::POST, &[]), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            status(Method::GET, &[("upgrade", "h2c")]),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Method::GET, &[("connection", "")]),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Method::GET, &[("sec-websocket-version", "8")]),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Method::GET, &[("sec-websocket-key", "c2hvcnQ=")]),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Method::GET, &[("sec-websocket-key", "")]),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
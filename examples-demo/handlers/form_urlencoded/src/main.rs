@@ -1,43 +1,36 @@
 //! An example of decoding requests from an HTML form element
 
-use futures_util::future::{self, FutureExt};
-use std::pin::Pin;
-use url::form_urlencoded;
-use atom_core::body::{Body};
-
-use atom_core::handler::HandlerFuture;
-use atom_core::helpers::http::response::create_response;
-use atom_core::hyper::{body, StatusCode};
-use atom_core::mime::TEXT_PLAIN;
+use atom_core::atom_derive::StaticResponseExtender;
 use atom_core::router::builder::{build_simple_router, DefineSingleRoute, DrawRoutes};
 use atom_core::router::Router;
 use atom_core::state::{FromState, State};
+use serde::Deserialize;
 
-/// Extracts the elements of the POST request and responds with the form keys and values
-fn form_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
-    let f = Body::try_take_from(&mut state).expect("try take error").to_bytes().then(|full_body| match full_body {
-        Ok(body_content) => {
-            // Perform decoding on request body
-            let form_data = form_urlencoded::parse(&body_content).into_owned();
-            // Add form keys and values to response body
-            let mut res_body = String::new();
-            for (key, value) in form_data {
-                let res_body_line = format!("{}: {}\n", key, value);
-                res_body.push_str(&res_body_line);
-            }
-            let res = create_response(&state, StatusCode::OK, TEXT_PLAIN, res_body);
-            future::ok((state, res))
-        }
-        Err(e) => future::err((state, e.into())),
-    });
+/// The fields of the HTML form, deserialized from the request body before the handler runs
+#[derive(Deserialize, StaticResponseExtender)]
+struct ContactForm {
+    name: String,
+    address: String,
+    message: String,
+}
 
-    f.boxed()
+/// Extracts the elements of the POST request and responds with the form keys and values
+fn form_handler(mut state: State) -> (State, String) {
+    let form = ContactForm::take_from(&mut state);
+    let res_body = format!(
+        "name: {}\naddress: {}\nmessage: {}\n",
+        form.name, form.address, form.message
+    );
+    (state, res_body)
 }
 
 /// Create a `Router`
 fn router() -> Router {
     build_simple_router(|route| {
-        route.post("/").to(form_handler);
+        route
+            .post("/")
+            .with_form_extractor::<ContactForm>()
+            .to(form_handler);
     })
 }
 
@@ -48,30 +41,32 @@ pub fn main() {
     atom_core::start(addr, router()).unwrap();
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use gotham::mime::APPLICATION_WWW_FORM_URLENCODED;
-//     use gotham::test::TestServer;
-//
-//     #[test]
-//     fn form_request() {
-//         let test_server = TestServer::new(router()).unwrap();
-//         let response = test_server
-//             .client()
-//             .post(
-//                 "http://localhost",
-//                 "name=Bob&address=123+Jersey Ave.&message=Hello world%21",
-//                 APPLICATION_WWW_FORM_URLENCODED,
-//             )
-//             .perform()
-//             .unwrap();
-//
-//         assert_eq!(response.status(), StatusCode::OK);
-//         let body = response.read_body().unwrap();
-//         assert_eq!(
-//             body,
-//             "name: Bob\naddress: 123 Jersey Ave.\nmessage: Hello world!\n".as_bytes()
-//         );
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atom_core::body::Body;
+    use atom_core::handler::Handler;
+    use atom_core::hyper::header::CONTENT_TYPE;
+    use atom_core::hyper::{Request, StatusCode};
+    use atom_core::mime::APPLICATION_WWW_FORM_URLENCODED;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn form_request() {
+        let request = Request::post("http://localhost")
+            .header(CONTENT_TYPE, APPLICATION_WWW_FORM_URLENCODED.as_ref())
+            .body(Body::from(
+                "name=Bob&address=123+Jersey Ave.&message=Hello world%21",
+            ))
+            .unwrap();
+        let state = State::from_request(request, "127.0.0.1:10000".parse::<SocketAddr>().unwrap());
+
+        let (_, response) = router().handle(state).await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().to_bytes().await.unwrap();
+        assert_eq!(
+            body,
+            "name: Bob\naddress: 123 Jersey Ave.\nmessage: Hello world!\n".as_bytes()
+        );
+    }
+}
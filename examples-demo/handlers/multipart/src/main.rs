@@ -1,72 +1,25 @@
 //! An example of decoding multipart form requests
-use futures_util::future::{self, FutureExt};
-use atom_core::handler::HandlerFuture;
-use atom_core::helpers::http::response::create_response;
-use atom_core::hyper::header::CONTENT_TYPE;
-use atom_core::hyper::{ HeaderMap, StatusCode};
-use atom_core::mime::TEXT_PLAIN;
+use atom_core::extractor::{Multipart, MultipartLimits};
+use atom_core::handler::HandlerError;
 use atom_core::router::builder::{build_simple_router, DefineSingleRoute, DrawRoutes};
 use atom_core::router::Router;
-use atom_core::state::{FromState, State};
-use multipart::server::Multipart;
-use std::io::{Cursor, Read};
-use std::pin::Pin;
-use atom_core::body::Body;
+use atom_core::state::State;
 
-/// Extracts the elements of the POST request and responds with the form keys and values
-fn form_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
-    const BOUNDARY: &str = "boundary=";
-    let header_map =HeaderMap::borrow_mut_from(&mut state);
-    let boundary =header_map
-        .get(CONTENT_TYPE)
-        .and_then(|ct| {
-            let ct = ct.to_str().ok()?;
-            let idx = ct.find(BOUNDARY)?;
-            Some(ct[idx + BOUNDARY.len()..].to_string())
-        })
-        .unwrap();
+/// Extracts the elements of the POST request and responds with the value of the first field
+async fn form_handler(state: &mut State) -> Result<String, HandlerError> {
+    let limits = MultipartLimits::default().field_size(1024 * 1024);
+    let mut multipart = Multipart::extract(state)?.with_limits(limits);
 
-    Body::take_from(&mut state).to_bytes()
-        .then(|full_body| match full_body {
-            Ok(valid_body) => {
-                let mut m = Multipart::with_body(Cursor::new(valid_body), boundary);
-                match m.read_entry() {
-                    Ok(Some(mut field)) => {
-                        let mut data = Vec::new();
-                        field.data.read_to_end(&mut data).expect("can't read");
-                        let res_result = String::from_utf8(data);
-                        let res_body = match res_result {
-                            Ok(r) => r,
-                            Err(e) => format!("{:?}", e),
-                        };
-                        let res = create_response(&state, StatusCode::OK, TEXT_PLAIN, res_body);
-                        future::ok((state, res))
-                    }
-                    Ok(None) => {
-                        let res = create_response(
-                            &state,
-                            StatusCode::OK,
-                            TEXT_PLAIN,
-                            "can't read".to_string(),
-                        );
-                        future::ok((state, res))
-                    }
-                    Err(e) => {
-                        let res =
-                            create_response(&state, StatusCode::OK, TEXT_PLAIN, format!("{:?}", e));
-                        future::ok((state, res))
-                    }
-                }
-            }
-            Err(e) => future::err((state, e.into())),
-        })
-        .boxed()
+    match multipart.next_field().await? {
+        Some(field) => Ok(field.text().await?),
+        None => Ok("can't read".to_string()),
+    }
 }
 
 /// Create a `Router`
 fn router() -> Router {
     build_simple_router(|route| {
-        route.post("/").to(form_handler);
+        route.post("/").to_async_borrowing(form_handler);
     })
 }
 
@@ -77,37 +30,37 @@ pub fn main() {
     atom_core::start(addr, router()).unwrap();
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use gotham::hyper::header::HeaderValue;
-//     use gotham::mime::MULTIPART_FORM_DATA;
-//     use gotham::test::TestServer;
-//
-//     #[test]
-//     fn form_request() {
-//         let boundary = "--abcdef1234--";
-//         let body = format!(
-//             "--{0}\r\n\
-//              content-disposition: form-data; name=\"foo\"\r\n\r\n\
-//              bar\r\n\
-//              --{0}--\r\n",
-//             boundary
-//         );
-//
-//         let test_server = TestServer::new(router()).unwrap();
-//         let client = test_server.client();
-//         let mut request = client.post("http://localhost", body, MULTIPART_FORM_DATA);
-//
-//         let content_type_string = format!("multipart/form-data; boundary={}", boundary);
-//         request.headers_mut().insert(
-//             CONTENT_TYPE,
-//             HeaderValue::from_str(content_type_string.as_str()).unwrap(),
-//         );
-//         let response = request.perform().unwrap();
-//         assert_eq!(response.status(), StatusCode::OK);
-//         let body = response.read_body().unwrap();
-//         let r = String::from_utf8(body).unwrap();
-//         assert_eq!(r, "bar");
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atom_core::body::Body;
+    use atom_core::handler::Handler;
+    use atom_core::hyper::header::CONTENT_TYPE;
+    use atom_core::hyper::{Request, StatusCode};
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn form_request() {
+        let boundary = "--abcdef1234--";
+        let body = format!(
+            "--{0}\r\n\
+             content-disposition: form-data; name=\"foo\"\r\n\r\n\
+             bar\r\n\
+             --{0}--\r\n",
+            boundary
+        );
+
+        let content_type_string = format!("multipart/form-data; boundary={}", boundary);
+        let request = Request::post("http://localhost")
+            .header(CONTENT_TYPE, content_type_string)
+            .body(Body::from(body))
+            .unwrap();
+        let state = State::from_request(request, "127.0.0.1:10000".parse::<SocketAddr>().unwrap());
+
+        let (_, response) = router().handle(state).await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().to_bytes().await.unwrap();
+        let r = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(r, "bar");
+    }
+}